@@ -0,0 +1,336 @@
+// Polyglot opening books, so the bot plays known openings instead of searching from move one. A book
+// is a file of 16 byte entries sorted by position key, each with a move and a weight, so the moves for
+// a position are found with a binary search. RUST_BOT_BOOK names the book, RUST_BOT_BOOK_SELECTION
+// picks how a move is chosen when there are several and RUST_BOT_BOOK_DEPTH is the last ply of the
+// game the book is used for. What the bot has learned from its own games (see `learning.rs`) changes
+// the weights of the book moves, and is a book of its own where the Polyglot book has no moves.
+use crate::polyglot_keys::RANDOM64;
+use crate::learning::{learned_moves, MoveRecord};
+
+use memmap2::Mmap;
+use pleco::core::CastleType;
+use pleco::{BitMove, Board, PieceType, Player, SQ};
+use pleco::helper::prelude::pawn_attacks_from;
+use std::env;
+use std::fs::File;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const ENTRY_SIZE: usize = 16;
+const DEFAULT_MAX_DEPTH: u16 = 30;
+// where the Polyglot book has no moves, a move the bot has played needs this many games before it is
+// played as a book move, so one lucky win isn't enough
+const MIN_LEARNED_GAMES: u32 = 3;
+
+// offsets into RANDOM64
+const CASTLING_KEYS: usize = 768;
+const EN_PASSANT_KEYS: usize = 772;
+const TURN_KEY: usize = 780;
+
+static BOOK: OnceLock<Option<OpeningBook>> = OnceLock::new();
+static SETTINGS: OnceLock<BookSettings> = OnceLock::new();
+
+/// How a move is picked from the book moves for a position
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BookSelection {
+    // at random, in proportion to the weights, so the bot doesn't play the same game every time
+    Weighted,
+    // always the move with the highest weight
+    Best,
+}
+
+impl BookSelection {
+    pub fn parse(name: &str) -> Result<BookSelection, String> {
+        match name {
+            "weighted" => Ok(BookSelection::Weighted),
+            "best" => Ok(BookSelection::Best),
+            _ => Err(format!("Unknown book selection \"{}\", expected weighted or best", name)),
+        }
+    }
+}
+
+/// One entry of a Polyglot book
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BookEntry {
+    pub key: u64,
+    pub mv: u16,
+    pub weight: u16,
+    pub learn: u32,
+}
+
+impl BookEntry {
+    // entries are stored big endian
+    pub fn from_bytes(bytes: &[u8]) -> BookEntry {
+        BookEntry {
+            key: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            mv: u16::from_be_bytes(bytes[8..10].try_into().unwrap()),
+            weight: u16::from_be_bytes(bytes[10..12].try_into().unwrap()),
+            learn: u32::from_be_bytes(bytes[12..16].try_into().unwrap()),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; ENTRY_SIZE] {
+        let mut bytes = [0; ENTRY_SIZE];
+        bytes[0..8].copy_from_slice(&self.key.to_be_bytes());
+        bytes[8..10].copy_from_slice(&self.mv.to_be_bytes());
+        bytes[10..12].copy_from_slice(&self.weight.to_be_bytes());
+        bytes[12..16].copy_from_slice(&self.learn.to_be_bytes());
+        bytes
+    }
+}
+
+/// How book moves are used, which is the same for the Polyglot book and the learned moves
+pub struct BookSettings {
+    pub selection: BookSelection,
+    // books aren't used after this many plies from the start of the game
+    pub max_depth: u16,
+}
+
+impl Default for BookSettings {
+    fn default() -> Self {
+        BookSettings { selection: BookSelection::Weighted, max_depth: DEFAULT_MAX_DEPTH }
+    }
+}
+
+pub struct OpeningBook {
+    entries: Mmap,
+}
+
+impl OpeningBook {
+    pub fn open(path: &str) -> Result<OpeningBook, String> {
+        let file = File::open(path).map_err(|e| format!("Could not open opening book {}: {}", path, e))?;
+        // the book is only read, and isn't expected to change while the bot is running
+        let entries = unsafe { Mmap::map(&file) }.map_err(|e| format!("Could not map opening book {}: {}", path, e))?;
+        if entries.len() % ENTRY_SIZE != 0 {
+            return Err(format!("Opening book {} is {} bytes, which isn't a whole number of entries", path, entries.len()));
+        }
+        Ok(OpeningBook { entries })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+
+    fn entry(&self, index: usize) -> BookEntry {
+        BookEntry::from_bytes(&self.entries[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE])
+    }
+
+    /// returns the entries for a position key, in the order they are in the book
+    pub fn entries(&self, key: u64) -> Vec<BookEntry> {
+        // find the first entry with the key
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.entry(middle).key < key {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        (low..self.len()).map(|index| self.entry(index)).take_while(|entry| entry.key == key).collect()
+    }
+
+    /// returns the legal book moves for a position with their weights. Moves the book has that aren't
+    /// legal, e.g. because of a key collision, are left out
+    pub fn moves(&self, board: &Board) -> Vec<(BitMove, u16)> {
+        let legal_moves = board.generate_moves();
+        self.entries(polyglot_key(board))
+            .iter()
+            .filter_map(|entry| {
+                legal_moves.iter()
+                    .find(|&&mv| encode_move(mv) == entry.mv & 0x7fff)
+                    .map(|&mv| (mv, entry.weight))
+            })
+            .collect()
+    }
+}
+
+/// returns the moves to pick from in a position with their weights: the Polyglot book moves with
+/// their weights scaled by how the bot has done with them, or if the book has none, the moves the bot
+/// has played in enough games and scored at least half the points with. Nothing is returned past the
+/// book depth
+pub fn book_moves(board: &Board) -> Vec<(BitMove, f64)> {
+    if board.moves_played() >= settings().max_depth {
+        return Vec::new();
+    }
+
+    let book_moves: Vec<(BitMove, u16)> = book().map(|book| book.moves(board)).unwrap_or_default();
+    weigh_moves(&book_moves, &learned_moves(board))
+}
+
+// combines a position's Polyglot book moves with what the bot has learned about its moves there
+fn weigh_moves(book_moves: &[(BitMove, u16)], learned: &[(BitMove, MoveRecord)]) -> Vec<(BitMove, f64)> {
+    if book_moves.is_empty() {
+        return learned.iter()
+            .filter(|(_, record)| record.games() >= MIN_LEARNED_GAMES && record.score() >= 0.5 && record.wins + record.draws > 0)
+            .map(|&(mv, record)| (mv, (2 * record.wins + record.draws) as f64))
+            .collect();
+    }
+
+    book_moves.iter().map(|&(mv, weight)| {
+        // a score of 0.5 leaves the weight as it is, and the weight goes towards double for a move
+        // that always wins and towards nothing for one that always loses
+        let learned_score = learned.iter().find(|&&(learned_move, _)| learned_move == mv).map_or(0.5, |(_, record)| record.score());
+        (mv, weight as f64 * 2.0 * learned_score)
+    }).collect()
+}
+
+/// returns whether a move is in the Polyglot book for the position. Moves the bot only knows from its
+/// own games don't count, since a few games don't show a move is sound
+pub fn is_polyglot_book_move(board: &Board, mv: BitMove) -> bool {
+    board.moves_played() < settings().max_depth && book().is_some_and(|book| book.moves(board).iter().any(|&(book_move, _)| book_move == mv))
+}
+
+/// returns the move to play from the books, if they have one for the position
+pub fn pick_book_move(board: &Board) -> Option<BitMove> {
+    let moves: Vec<(BitMove, f64)> = book_moves(board).into_iter().filter(|&(_, weight)| weight > 0.0).collect();
+    match settings().selection {
+        BookSelection::Best => moves.iter().max_by(|a, b| a.1.total_cmp(&b.1)).map(|&(mv, _)| mv),
+        BookSelection::Weighted => {
+            let total: f64 = moves.iter().map(|&(_, weight)| weight).sum();
+            let mut target = total * (random() % 1_000_000) as f64 / 1_000_000.0;
+            for &(mv, weight) in moves.iter() {
+                if target < weight {
+                    return Some(mv);
+                }
+                target -= weight;
+            }
+            moves.last().map(|&(mv, _)| mv)
+        }
+    }
+}
+
+// a random number for picking between book moves, which doesn't need to be good, just different
+// each game
+fn random() -> u64 {
+    let mut state = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos() as u64).unwrap_or(1).max(1);
+    // xorshift64
+    state ^= state << 13;
+    state ^= state >> 7;
+    state ^= state << 17;
+    state
+}
+
+/// returns the Polyglot key of a position, which is how books look positions up
+pub fn polyglot_key(board: &Board) -> u64 {
+    let mut key = 0;
+
+    for sq in board.occupied() {
+        let piece = board.piece_at_sq(sq);
+        // pieces are ordered black pawn, white pawn, black knight, white knight...
+        let kind = 2 * (piece.type_of() as usize - 1) + if piece.player_lossy() == Player::White { 1 } else { 0 };
+        key ^= RANDOM64[64 * kind + sq.0 as usize];
+    }
+
+    for (index, (player, castle_type)) in [
+        (Player::White, CastleType::KingSide),
+        (Player::White, CastleType::QueenSide),
+        (Player::Black, CastleType::KingSide),
+        (Player::Black, CastleType::QueenSide),
+    ].into_iter().enumerate() {
+        if board.can_castle(player, castle_type) {
+            key ^= RANDOM64[CASTLING_KEYS + index];
+        }
+    }
+
+    // the en passant file only counts if a pawn can actually take en passant, whether or not the
+    // capture would be legal
+    let ep_square = board.ep_square();
+    if ep_square != SQ::NONE {
+        let them = !board.turn();
+        let our_pawns = board.piece_bb(board.turn(), PieceType::P);
+        if (pawn_attacks_from(ep_square, them) & our_pawns).is_not_empty() {
+            key ^= RANDOM64[EN_PASSANT_KEYS + ep_square.file_idx_of_sq() as usize];
+        }
+    }
+
+    if board.turn() == Player::White {
+        key ^= RANDOM64[TURN_KEY];
+    }
+
+    key
+}
+
+/// returns a move in the Polyglot encoding: the destination file and rank, the source file and rank
+/// and the promotion piece, three bits each. Castling is written as the king taking its own rook,
+/// which is also how pleco stores it
+pub fn encode_move(mv: BitMove) -> u16 {
+    let (from, to) = (mv.get_src(), mv.get_dest());
+    let promotion = if mv.is_promo() {
+        match mv.promo_piece() {
+            PieceType::N => 1,
+            PieceType::B => 2,
+            PieceType::R => 3,
+            _ => 4,
+        }
+    } else {
+        0
+    };
+
+    (to.file_idx_of_sq() as u16)
+        | (to.rank_idx_of_sq() as u16) << 3
+        | (from.file_idx_of_sq() as u16) << 6
+        | (from.rank_idx_of_sq() as u16) << 9
+        | promotion << 12
+}
+
+/// opens the book named by RUST_BOT_BOOK, if it is set, and reads how book moves are picked. Should
+/// be called once at startup
+pub fn init_book_from_env() -> Result<(), String> {
+    let selection = match env::var("RUST_BOT_BOOK_SELECTION") {
+        Ok(name) if !name.is_empty() => BookSelection::parse(&name)?,
+        _ => BookSelection::Weighted,
+    };
+    let max_depth = match env::var("RUST_BOT_BOOK_DEPTH") {
+        Ok(depth) if !depth.is_empty() => depth.parse().map_err(|e| format!("Invalid RUST_BOT_BOOK_DEPTH {}: {}", depth, e))?,
+        _ => DEFAULT_MAX_DEPTH,
+    };
+    SETTINGS.set(BookSettings { selection, max_depth }).map_err(|_| "Book settings have already been initialised".to_string())?;
+
+    let book = match env::var("RUST_BOT_BOOK") {
+        Ok(path) if !path.is_empty() => Some(OpeningBook::open(&path)?),
+        _ => None,
+    };
+
+    BOOK.set(book).map_err(|_| "Opening book has already been initialised".to_string())
+}
+
+/// returns the opening book, if there is one
+pub fn book() -> Option<&'static OpeningBook> {
+    BOOK.get().and_then(Option::as_ref)
+}
+
+pub fn settings() -> &'static BookSettings {
+    SETTINGS.get_or_init(BookSettings::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start_move(uci_move: &str) -> BitMove {
+        Board::start_pos().generate_moves().iter().copied().find(|mv| mv.to_string() == uci_move).unwrap()
+    }
+
+    fn record(wins: u32, draws: u32, losses: u32) -> MoveRecord {
+        MoveRecord { wins, draws, losses }
+    }
+
+    #[test]
+    fn learned_results_scale_book_weights() {
+        let (e4, d4, c4) = (start_move("e2e4"), start_move("d2d4"), start_move("c2c4"));
+        let moves = weigh_moves(&[(e4, 90), (d4, 90), (c4, 90)], &[(e4, record(1, 0, 0)), (c4, record(0, 0, 4))]);
+
+        // one win scores 2/3 and four losses 1/6, with nothing learned the weight stays the same
+        assert_eq!(moves, vec![(e4, 120.0), (d4, 90.0), (c4, 30.0)]);
+    }
+
+    #[test]
+    fn learned_moves_need_a_few_games_without_a_book() {
+        let (e4, d4, c4) = (start_move("e2e4"), start_move("d2d4"), start_move("c2c4"));
+        let learned = [(e4, record(1, 0, 0)), (d4, record(2, 1, 0)), (c4, record(0, 1, 2))];
+
+        assert_eq!(weigh_moves(&[], &learned), vec![(d4, 5.0)]);
+    }
+}
@@ -0,0 +1,319 @@
+// Builds a Polyglot opening book from a directory of PGN files, so the bot's repertoire can be
+// curated from games we pick. This is an offline tool, it is not used in the lambda function.
+//
+// Games can be filtered by the ratings of both players and by result. Every move played in the first
+// plies of the games that are kept is counted as a win, draw or loss for the side that played it, and
+// its weight is 2 points for each win and 1 for each draw, the same as Polyglot's own book maker.
+use crate::book::{encode_move, polyglot_key, BookEntry};
+use crate::pgn::{parse_games, GameResult, PgnGame};
+
+use pleco::Player;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+const DEFAULT_DEPTH: usize = 30;
+
+/// Which games and moves go into the book
+pub struct BookFilter {
+    // both players must be rated at least this, if it is set. Games without ratings are left out
+    pub min_rating: Option<u32>,
+    // results of the games to use
+    pub results: Vec<GameResult>,
+    // plies of each game that are added
+    pub depth: usize,
+    // moves played in fewer games than this are left out
+    pub min_games: u32,
+}
+
+impl Default for BookFilter {
+    fn default() -> Self {
+        BookFilter {
+            min_rating: None,
+            results: vec![GameResult::WhiteWin, GameResult::BlackWin, GameResult::Draw],
+            depth: DEFAULT_DEPTH,
+            min_games: 1,
+        }
+    }
+}
+
+impl BookFilter {
+    /// reads the filter from command line options, like `--min-rating 2200 --results 1-0,0-1`
+    pub fn from_args(args: &[String]) -> Result<BookFilter, String> {
+        let mut filter = BookFilter::default();
+        let mut args = args.iter();
+
+        while let Some(option) = args.next() {
+            let value = args.next().ok_or_else(|| format!("Missing value for {}", option))?;
+            let number = || value.parse().map_err(|e| format!("Invalid value for {}: {}", option, e));
+            match option.as_str() {
+                "--min-rating" => filter.min_rating = Some(number()?),
+                "--depth" => filter.depth = number()? as usize,
+                "--min-games" => filter.min_games = number()?,
+                "--results" => {
+                    filter.results = value.split(',').map(|result| match GameResult::parse(result) {
+                        GameResult::Unfinished => Err(format!("Unknown result {}, expected 1-0, 0-1 or 1/2-1/2", result)),
+                        result => Ok(result),
+                    }).collect::<Result<_, _>>()?;
+                }
+                _ => return Err(format!("Unknown option {}", option)),
+            }
+        }
+
+        Ok(filter)
+    }
+
+    // returns why a game is left out, or None if it is used
+    fn rejects(&self, game: &PgnGame) -> Option<&'static str> {
+        if !self.results.contains(&game.result()) {
+            return Some("result");
+        }
+        if let Some(min_rating) = self.min_rating {
+            let rated = |tag| game.rating(tag).is_some_and(|rating| rating >= min_rating);
+            if !rated("WhiteElo") || !rated("BlackElo") {
+                return Some("rating");
+            }
+        }
+        if game.tag("Variant").is_some_and(|variant| !variant.eq_ignore_ascii_case("standard")) {
+            return Some("variant");
+        }
+        None
+    }
+}
+
+// how a move has done in the games it was played in, for the side that played it
+#[derive(Default)]
+struct MoveStats {
+    wins: u32,
+    draws: u32,
+    losses: u32,
+}
+
+impl MoveStats {
+    fn games(&self) -> u32 {
+        self.wins + self.draws + self.losses
+    }
+
+    fn score(&self) -> u64 {
+        2 * self.wins as u64 + self.draws as u64
+    }
+}
+
+/// reads every .pgn file in `pgn_dir`, and writes a book of the games that pass the filter to
+/// `output_path`
+pub fn build_book(pgn_dir: &str, output_path: &str, filter: &BookFilter) -> Result<(), String> {
+    let mut paths: Vec<_> = fs::read_dir(pgn_dir)
+        .map_err(|e| format!("Could not read PGN directory {}: {}", pgn_dir, e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("pgn")))
+        .collect();
+    paths.sort();
+    if paths.is_empty() {
+        return Err(format!("No .pgn files found in {}", pgn_dir));
+    }
+
+    // stats for each (position key, move) pair
+    let mut stats: HashMap<(u64, u16), MoveStats> = HashMap::new();
+    let mut rejected: HashMap<&str, usize> = HashMap::new();
+    let (mut games_read, mut games_used) = (0, 0);
+
+    for path in paths.iter() {
+        let contents = read_pgn(path)?;
+        for (index, game) in parse_games(&contents).into_iter().enumerate() {
+            games_read += 1;
+            if let Some(reason) = filter.rejects(&game) {
+                *rejected.entry(reason).or_default() += 1;
+                continue;
+            }
+            match add_game(&game, filter.depth, &mut stats) {
+                Ok(()) => games_used += 1,
+                Err(e) => {
+                    println!("skipping game {} in {}: {}", index + 1, path.display(), e);
+                    *rejected.entry("illegal move").or_default() += 1;
+                }
+            }
+        }
+    }
+
+    let entries = to_entries(&stats, filter.min_games);
+    let bytes: Vec<u8> = entries.iter().flat_map(|entry| entry.to_bytes()).collect();
+    fs::write(output_path, bytes).map_err(|e| format!("Could not write opening book {}: {}", output_path, e))?;
+
+    println!("read {} games from {} files, used {}", games_read, paths.len(), games_used);
+    let mut reasons: Vec<_> = rejected.into_iter().collect();
+    reasons.sort();
+    for (reason, count) in reasons {
+        println!("  {} left out because of their {}", count, reason);
+    }
+    println!("wrote {} moves in {} positions to {}", entries.len(), entries.iter().map(|entry| entry.key).collect::<HashSet<_>>().len(), output_path);
+    Ok(())
+}
+
+// PGN files are meant to be ASCII or Latin-1, but player names in the wild are in anything
+fn read_pgn(path: &Path) -> Result<String, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// counts the first `depth` moves of a game. Nothing is counted if a move can't be played, so a
+// broken game doesn't add half a line
+fn add_game(game: &PgnGame, depth: usize, stats: &mut HashMap<(u64, u16), MoveStats>) -> Result<(), String> {
+    let moves = game.play()?;
+    let mut board = game.start_position()?;
+    let result = game.result();
+
+    for &mv in moves.iter().take(depth) {
+        let white = board.turn() == Player::White;
+        let move_stats = stats.entry((polyglot_key(&board), encode_move(mv))).or_default();
+        match (result, white) {
+            (GameResult::Draw, _) => move_stats.draws += 1,
+            (GameResult::WhiteWin, true) | (GameResult::BlackWin, false) => move_stats.wins += 1,
+            _ => move_stats.losses += 1,
+        }
+        board.apply_move(mv);
+    }
+    Ok(())
+}
+
+// turns the stats into book entries sorted by key, with the best move for each position first.
+// Moves that never scored are left out, and weights are scaled down per position if they don't fit
+// in 16 bits
+fn to_entries(stats: &HashMap<(u64, u16), MoveStats>, min_games: u32) -> Vec<BookEntry> {
+    let mut positions: HashMap<u64, Vec<(u16, u64)>> = HashMap::new();
+    for (&(key, mv), move_stats) in stats.iter() {
+        if move_stats.games() >= min_games && move_stats.score() > 0 {
+            positions.entry(key).or_default().push((mv, move_stats.score()));
+        }
+    }
+
+    let mut entries = Vec::new();
+    for (key, mut moves) in positions {
+        moves.sort_by_key(|&(mv, score)| (std::cmp::Reverse(score), mv));
+        let max_score = moves[0].1;
+        for (mv, score) in moves {
+            let weight = if max_score > u16::MAX as u64 { (score * u16::MAX as u64 / max_score).max(1) } else { score };
+            entries.push(BookEntry { key, mv, weight: weight as u16, learn: 0 });
+        }
+    }
+
+    // sorting is stable, so each position keeps its best move first
+    entries.sort_by_key(|entry| entry.key);
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pleco::Board;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn game(tags: &str, movetext: &str) -> PgnGame {
+        parse_games(&format!("{}\n\n{}\n", tags, movetext)).remove(0)
+    }
+
+    fn stats_of(games: &[PgnGame], depth: usize) -> HashMap<(u64, u16), MoveStats> {
+        let mut stats = HashMap::new();
+        for game in games {
+            add_game(game, depth, &mut stats).unwrap();
+        }
+        stats
+    }
+
+    fn start_entries(entries: &[BookEntry]) -> Vec<(u16, u16)> {
+        let key = polyglot_key(&Board::start_pos());
+        entries.iter().filter(|entry| entry.key == key).map(|entry| (entry.mv, entry.weight)).collect()
+    }
+
+    fn first_move(movetext: &str) -> u16 {
+        let game = game("[Result \"*\"]", movetext);
+        encode_move(game.play().unwrap()[0])
+    }
+
+    #[test]
+    fn reads_the_filter_from_options() {
+        let filter = BookFilter::from_args(&[]).unwrap();
+        assert_eq!((filter.min_rating, filter.depth, filter.min_games), (None, DEFAULT_DEPTH, 1));
+        assert_eq!(filter.results.len(), 3);
+
+        let filter = BookFilter::from_args(&args("--min-rating 2200 --depth 12 --min-games 3 --results 1-0,1/2-1/2")).unwrap();
+        assert_eq!((filter.min_rating, filter.depth, filter.min_games), (Some(2200), 12, 3));
+        assert_eq!(filter.results, vec![GameResult::WhiteWin, GameResult::Draw]);
+
+        assert!(BookFilter::from_args(&args("--depth")).err().unwrap().contains("Missing value"));
+        assert!(BookFilter::from_args(&args("--depth ten")).err().unwrap().contains("Invalid value"));
+        assert!(BookFilter::from_args(&args("--results 1-0,2-0")).err().unwrap().contains("Unknown result"));
+        assert!(BookFilter::from_args(&args("--max-rating 2000")).err().unwrap().contains("Unknown option"));
+    }
+
+    #[test]
+    fn leaves_out_games_the_filter_rejects() {
+        let filter = BookFilter::from_args(&args("--min-rating 2000 --results 1-0,0-1")).unwrap();
+        let rated = |white: u32, black: u32, result: &str| game(
+            &format!("[WhiteElo \"{}\"]\n[BlackElo \"{}\"]\n[Result \"{}\"]", white, black, result), &format!("1. e4 e5 {}", result));
+
+        assert_eq!(filter.rejects(&rated(2100, 2050, "1-0")), None);
+        assert_eq!(filter.rejects(&rated(2100, 2050, "1/2-1/2")), Some("result"));
+        assert_eq!(filter.rejects(&rated(2100, 1950, "0-1")), Some("rating"));
+        assert_eq!(filter.rejects(&game("[Result \"1-0\"]", "1. e4 e5 1-0")), Some("rating"));
+        let chess960 = game("[WhiteElo \"2100\"]\n[BlackElo \"2100\"]\n[Variant \"Chess960\"]\n[Result \"1-0\"]", "1. e4 e5 1-0");
+        assert_eq!(filter.rejects(&chess960), Some("variant"));
+
+        // without a minimum rating unrated games are fine
+        assert_eq!(BookFilter::default().rejects(&game("[Result \"1-0\"]", "1. e4 e5 1-0")), None);
+    }
+
+    #[test]
+    fn weights_count_wins_twice_and_draws_once_for_the_side_that_moved() {
+        let games = [
+            game("[Result \"1-0\"]", "1. e4 e5 1-0"),
+            game("[Result \"1/2-1/2\"]", "1. e4 c5 1/2-1/2"),
+            game("[Result \"0-1\"]", "1. e4 e5 0-1"),
+            game("[Result \"1-0\"]", "1. d4 d5 1-0"),
+            game("[Result \"0-1\"]", "1. c4 e5 0-1"),
+        ];
+        let entries = to_entries(&stats_of(&games, 2), 1);
+
+        // e4 won once, drew once and lost once, d4 won once and c4, which only lost, is left out
+        assert_eq!(start_entries(&entries), vec![(first_move("1. e4"), 3), (first_move("1. d4"), 2)]);
+        // after e4 it is black who scores: e5 won once and lost once, and c5 drew
+        let mut board = Board::start_pos();
+        board.apply_move(game("[Result \"*\"]", "1. e4").play().unwrap()[0]);
+        let after_e4 = polyglot_key(&board);
+        let replies: Vec<u16> = entries.iter().filter(|entry| entry.key == after_e4).map(|entry| entry.weight).collect();
+        assert_eq!(replies, vec![2, 1]);
+
+        assert!(entries.windows(2).all(|pair| pair[0].key <= pair[1].key));
+    }
+
+    #[test]
+    fn moves_played_in_too_few_games_are_left_out() {
+        let games = [
+            game("[Result \"1-0\"]", "1. e4 1-0"),
+            game("[Result \"1-0\"]", "1. e4 1-0"),
+            game("[Result \"1-0\"]", "1. d4 1-0"),
+        ];
+        let entries = to_entries(&stats_of(&games, 1), 2);
+        assert_eq!(start_entries(&entries), vec![(first_move("1. e4"), 4)]);
+    }
+
+    #[test]
+    fn depth_limits_the_moves_counted() {
+        let games = [game("[Result \"1/2-1/2\"]", "1. e4 e5 2. Nf3 Nc6 1/2-1/2")];
+        assert_eq!(stats_of(&games, 3).len(), 3);
+        assert_eq!(stats_of(&games, 30).len(), 4);
+    }
+
+    #[test]
+    fn large_weights_are_scaled_to_fit() {
+        let key = polyglot_key(&Board::start_pos());
+        let (e4, d4) = (first_move("1. e4"), first_move("1. d4"));
+        let mut stats = HashMap::new();
+        stats.insert((key, e4), MoveStats { wins: 100_000, draws: 0, losses: 0 });
+        stats.insert((key, d4), MoveStats { wins: 25_000, draws: 0, losses: 0 });
+
+        assert_eq!(start_entries(&to_entries(&stats, 1)), vec![(e4, u16::MAX), (d4, u16::MAX / 4)]);
+    }
+}
@@ -0,0 +1,271 @@
+// Which challenges the bot accepts. Each rule that a challenge breaks comes with the reason Lichess
+// shows the challenger, so they know what to change: a faster or slower time control, another
+// variant, rated or casual, and so on. The policy is read at startup from the JSON file named by
+// RUST_BOT_CHALLENGE_POLICY, and without one the bot takes standard games and games from a position
+// from anyone, at any time control it can play.
+use crate::lichess_api::{Challenge, DeclineReason, TimeControl};
+
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::sync::OnceLock;
+
+static POLICY: OnceLock<ChallengePolicy> = OnceLock::new();
+
+/// The rules a challenge has to meet to be accepted. Times are in seconds
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChallengePolicy {
+    // Lichess variant keys, e.g. "standard", "fromPosition" or "chess960"
+    pub variants: Vec<String>,
+    pub correspondence: bool,
+    // games with a clock, which only the daemon plays
+    pub clock: bool,
+    pub unlimited: bool,
+    // limits on the starting time and increment of games with a clock
+    pub min_initial: Option<u64>,
+    pub max_initial: Option<u64>,
+    pub min_increment: Option<u64>,
+    pub max_increment: Option<u64>,
+    // limits on the days per move of correspondence games
+    pub min_days: Option<u32>,
+    pub max_days: Option<u32>,
+    pub rated: bool,
+    pub casual: bool,
+    pub bots: bool,
+    pub humans: bool,
+    // limits on the challenger's rating in the challenge's time control
+    pub min_rating: Option<u32>,
+    pub max_rating: Option<u32>,
+    // Lichess usernames, in any case. With an allow list only those players are accepted
+    pub allow_list: Vec<String>,
+    pub block_list: Vec<String>,
+    // how many games the bot plays at once
+    pub max_games: Option<usize>,
+}
+
+impl Default for ChallengePolicy {
+    fn default() -> Self {
+        ChallengePolicy {
+            // pleco can't castle in Chess960, and the other variants have different rules
+            variants: vec!["standard".to_string(), "fromPosition".to_string()],
+            correspondence: true,
+            clock: true,
+            unlimited: false,
+            min_initial: None,
+            max_initial: None,
+            min_increment: None,
+            max_increment: None,
+            min_days: None,
+            max_days: None,
+            rated: true,
+            casual: true,
+            bots: true,
+            humans: true,
+            min_rating: None,
+            max_rating: None,
+            allow_list: Vec::new(),
+            block_list: Vec::new(),
+            max_games: None,
+        }
+    }
+}
+
+impl ChallengePolicy {
+    pub fn load(path: &str) -> Result<ChallengePolicy, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Could not read challenge policy {}: {}", path, e))?;
+        serde_json::from_str(&contents).map_err(|e| format!("Invalid challenge policy {}: {}", path, e))
+    }
+
+    /// returns why a challenge should be declined, or None if it should be accepted. The Lambda
+    /// function only runs every 30 minutes, so `real_time` is false there and games with a clock are
+    /// too fast for it whatever the policy says. `games_in_progress` is how many games the bot is
+    /// playing now
+    pub fn decline_reason(&self, challenge: &Challenge, real_time: bool, games_in_progress: usize) -> Option<DeclineReason> {
+        let challenger = challenge.challenger.as_ref();
+        let on_list = |list: &[String]| challenger.is_some_and(|challenger| list.iter().any(|name| name.eq_ignore_ascii_case(&challenger.id) || name.eq_ignore_ascii_case(&challenger.name)));
+
+        if on_list(&self.block_list) || (!self.allow_list.is_empty() && !on_list(&self.allow_list)) {
+            return Some(DeclineReason::Generic);
+        }
+        if !self.variants.iter().any(|variant| variant == &challenge.variant.key) {
+            return Some(DeclineReason::Variant);
+        }
+        if let Some(reason) = self.time_control_reason(&challenge.time_control, real_time) {
+            return Some(reason);
+        }
+        if challenge.rated && !self.rated {
+            return Some(DeclineReason::Casual);
+        }
+        if !challenge.rated && !self.casual {
+            return Some(DeclineReason::Rated);
+        }
+
+        let is_bot = challenger.is_some_and(|challenger| challenger.title.as_deref() == Some("BOT"));
+        if is_bot && !self.bots {
+            return Some(DeclineReason::NoBot);
+        }
+        if !is_bot && !self.humans {
+            return Some(DeclineReason::OnlyBot);
+        }
+
+        // players without a rating, like new accounts, aren't turned away by a rating range
+        if let Some(rating) = challenger.and_then(|challenger| challenger.rating) {
+            if self.min_rating.is_some_and(|min| rating < min) || self.max_rating.is_some_and(|max| rating > max) {
+                return Some(DeclineReason::Generic);
+            }
+        }
+        if self.max_games.is_some_and(|max| games_in_progress >= max) {
+            return Some(DeclineReason::Later);
+        }
+        None
+    }
+
+    fn time_control_reason(&self, time_control: &TimeControl, real_time: bool) -> Option<DeclineReason> {
+        match *time_control {
+            TimeControl::Clock { .. } if !real_time => Some(DeclineReason::TooFast),
+            TimeControl::Clock { .. } if !self.clock => Some(DeclineReason::TimeControl),
+            TimeControl::Clock { limit, increment } => {
+                if self.min_initial.is_some_and(|min| limit < min) || self.min_increment.is_some_and(|min| increment < min) {
+                    Some(DeclineReason::TooFast)
+                } else if self.max_initial.is_some_and(|max| limit > max) || self.max_increment.is_some_and(|max| increment > max) {
+                    Some(DeclineReason::TooSlow)
+                } else {
+                    None
+                }
+            }
+            TimeControl::Correspondence { .. } if !self.correspondence => Some(DeclineReason::TimeControl),
+            TimeControl::Correspondence { days_per_turn } => {
+                if self.min_days.is_some_and(|min| days_per_turn < min) {
+                    Some(DeclineReason::TooFast)
+                } else if self.max_days.is_some_and(|max| days_per_turn > max) {
+                    Some(DeclineReason::TooSlow)
+                } else {
+                    None
+                }
+            }
+            TimeControl::Unlimited if !self.unlimited => Some(DeclineReason::TimeControl),
+            TimeControl::Unlimited => None,
+        }
+    }
+}
+
+/// reads the challenge policy from the JSON file named by RUST_BOT_CHALLENGE_POLICY, if it is set.
+/// Should be called once at startup
+pub fn init_challenge_policy_from_env() -> Result<(), String> {
+    let policy = match env::var("RUST_BOT_CHALLENGE_POLICY") {
+        Ok(path) if !path.is_empty() => ChallengePolicy::load(&path)?,
+        _ => ChallengePolicy::default(),
+    };
+
+    POLICY.set(policy).map_err(|_| "Challenge policy has already been initialised".to_string())
+}
+
+/// returns the challenge policy the bot is using
+pub fn policy() -> &'static ChallengePolicy {
+    POLICY.get_or_init(ChallengePolicy::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a rated 5+3 blitz challenge from a human rated 1800, with some fields changed
+    fn challenge(changes: serde_json::Value) -> Challenge {
+        let mut challenge = serde_json::json!({
+            "id": "abcd1234",
+            "challenger": {"id": "alice", "name": "Alice", "rating": 1800, "title": null},
+            "variant": {"key": "standard"},
+            "rated": true,
+            "speed": "blitz",
+            "timeControl": {"type": "clock", "limit": 300, "increment": 3},
+        });
+        for (key, value) in changes.as_object().unwrap() {
+            challenge[key] = value.clone();
+        }
+        serde_json::from_value(challenge).unwrap()
+    }
+
+    fn correspondence(days: u32) -> serde_json::Value {
+        serde_json::json!({"type": "correspondence", "daysPerTurn": days})
+    }
+
+    #[test]
+    fn default_policy_accepts_what_the_bot_can_play() {
+        let policy = ChallengePolicy::default();
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({})), true, 0), None);
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({"variant": {"key": "fromPosition"}})), true, 0), None);
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({"timeControl": correspondence(3)})), false, 0), None);
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({"variant": {"key": "chess960"}})), true, 0), Some(DeclineReason::Variant));
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({"timeControl": {"type": "unlimited"}})), true, 0), Some(DeclineReason::TimeControl));
+    }
+
+    #[test]
+    fn games_with_a_clock_are_too_fast_for_the_lambda_function() {
+        let policy = ChallengePolicy::default();
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({})), false, 0), Some(DeclineReason::TooFast));
+        let policy = ChallengePolicy { clock: false, ..Default::default() };
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({})), true, 0), Some(DeclineReason::TimeControl));
+    }
+
+    #[test]
+    fn time_limits() {
+        let policy = ChallengePolicy { min_initial: Some(180), max_initial: Some(900), min_increment: Some(2), min_days: Some(2), max_days: Some(7), ..Default::default() };
+        let clock = |limit: u64, increment: u64| challenge(serde_json::json!({"timeControl": {"type": "clock", "limit": limit, "increment": increment}}));
+        assert_eq!(policy.decline_reason(&clock(300, 3), true, 0), None);
+        assert_eq!(policy.decline_reason(&clock(60, 3), true, 0), Some(DeclineReason::TooFast));
+        assert_eq!(policy.decline_reason(&clock(300, 0), true, 0), Some(DeclineReason::TooFast));
+        assert_eq!(policy.decline_reason(&clock(1800, 3), true, 0), Some(DeclineReason::TooSlow));
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({"timeControl": correspondence(1)})), false, 0), Some(DeclineReason::TooFast));
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({"timeControl": correspondence(14)})), false, 0), Some(DeclineReason::TooSlow));
+        let policy = ChallengePolicy { correspondence: false, ..Default::default() };
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({"timeControl": correspondence(3)})), true, 0), Some(DeclineReason::TimeControl));
+    }
+
+    #[test]
+    fn rated_and_casual() {
+        let policy = ChallengePolicy { rated: false, ..Default::default() };
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({})), true, 0), Some(DeclineReason::Casual));
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({"rated": false})), true, 0), None);
+        let policy = ChallengePolicy { casual: false, ..Default::default() };
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({"rated": false})), true, 0), Some(DeclineReason::Rated));
+    }
+
+    #[test]
+    fn bots_and_humans() {
+        let bot = challenge(serde_json::json!({"challenger": {"id": "botty", "name": "Botty", "rating": 2000, "title": "BOT"}}));
+        let human = challenge(serde_json::json!({}));
+        let policy = ChallengePolicy { bots: false, ..Default::default() };
+        assert_eq!(policy.decline_reason(&bot, true, 0), Some(DeclineReason::NoBot));
+        assert_eq!(policy.decline_reason(&human, true, 0), None);
+        let policy = ChallengePolicy { humans: false, ..Default::default() };
+        assert_eq!(policy.decline_reason(&bot, true, 0), None);
+        assert_eq!(policy.decline_reason(&human, true, 0), Some(DeclineReason::OnlyBot));
+    }
+
+    #[test]
+    fn players_and_ratings() {
+        let policy = ChallengePolicy { min_rating: Some(1500), max_rating: Some(1700), ..Default::default() };
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({})), true, 0), Some(DeclineReason::Generic));
+        let policy = ChallengePolicy { block_list: vec!["ALICE".to_string()], ..Default::default() };
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({})), true, 0), Some(DeclineReason::Generic));
+        let policy = ChallengePolicy { allow_list: vec!["bob".to_string()], ..Default::default() };
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({})), true, 0), Some(DeclineReason::Generic));
+        let policy = ChallengePolicy { allow_list: vec!["Alice".to_string()], ..Default::default() };
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({})), true, 0), None);
+    }
+
+    #[test]
+    fn too_many_games_is_later() {
+        let policy = ChallengePolicy { max_games: Some(2), ..Default::default() };
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({})), true, 1), None);
+        assert_eq!(policy.decline_reason(&challenge(serde_json::json!({})), true, 2), Some(DeclineReason::Later));
+    }
+
+    #[test]
+    fn policy_file_fills_in_defaults_and_rejects_unknown_rules() {
+        let policy: ChallengePolicy = serde_json::from_str(r#"{"clock": false, "min_days": 2, "block_list": ["troll"]}"#).unwrap();
+        assert_eq!(policy, ChallengePolicy { clock: false, min_days: Some(2), block_list: vec!["troll".to_string()], ..Default::default() });
+        assert!(serde_json::from_str::<ChallengePolicy>(r#"{"colour": "white"}"#).is_err());
+    }
+}
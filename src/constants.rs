@@ -1,4 +1,4 @@
-use pleco::{Board,Player,PieceType,SQ,BitMove};
+use pleco::{PieceType,SQ};
 
 pub const NUM_TURNS_TO_LOOK_AHEAD: u8 = 5;
 
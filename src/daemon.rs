@@ -0,0 +1,391 @@
+// A long running Lichess bot, as an alternative to the Lambda function. It listens to the bot's
+// event stream and reacts straight away, so it can play games with a clock as well as correspondence
+// games: challenges are answered as they come in, and each game gets its own stream of moves that
+// the bot answers as soon as it is its turn.
+use crate::book::pick_book_move;
+use crate::challenge_policy::policy;
+use crate::lichess::learn_from_finished_games;
+use crate::lichess_api::{clock_minutes, Event, GameEvent, GameState, LichessClient, LichessError};
+use crate::minimax::{blunder_check, calculate_next_move, iterative_deepening, SearchLimits};
+use crate::uci::parse_move;
+
+use lambda_runtime::Error;
+use pleco::{BitMove, Board, Player};
+use std::collections::HashSet;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+// milliseconds kept back from each move for the time it takes the move to get to Lichess
+const MOVE_OVERHEAD: u64 = 300;
+// how long to wait before connecting again after a stream ends or fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// Lichess asks for a minute's wait after a 429 response
+const RATE_LIMIT_DELAY: Duration = Duration::from_secs(60);
+
+// searches for different games take turns, because they all use the whole CPU and stop together
+static SEARCH_LOCK: Mutex<()> = Mutex::new(());
+// the ids of the games being played. Lichess sends gameStart again for each of them when the event
+// stream reconnects, and they are also counted for the challenge policy's maximum
+static ACTIVE_GAMES: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+fn active_games() -> std::sync::MutexGuard<'static, HashSet<String>> {
+    ACTIVE_GAMES.get_or_init(Default::default).lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// a game that is being played, which stops being active when this is dropped
+struct ActiveGame(String);
+
+impl ActiveGame {
+    // None if the game is already being played
+    fn start(game_id: &str) -> Option<ActiveGame> {
+        active_games().insert(game_id.to_string()).then(|| ActiveGame(game_id.to_string()))
+    }
+}
+
+impl Drop for ActiveGame {
+    fn drop(&mut self) {
+        active_games().remove(&self.0);
+    }
+}
+
+/// runs the bot until it is stopped, connecting to the event stream again whenever it drops
+pub async fn run() -> Result<(), Error> {
+    let client = LichessClient::from_env()?;
+    let account = client.account().await?;
+    println!("running as {}", account.username);
+    let bot_id = account.id;
+
+    loop {
+        match listen_for_events(&client, &bot_id).await {
+            Err(LichessError::RateLimited) => {
+                println!("rate limited, waiting a minute");
+                tokio::time::sleep(RATE_LIMIT_DELAY).await;
+            }
+            Err(e) => println!("event stream failed: {}", e),
+            Ok(()) => println!("event stream ended"),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+// handles events until the event stream ends. Lichess sends a gameStart event for each game in
+// progress when the stream is opened, so games that were going on before are picked up again
+async fn listen_for_events(client: &LichessClient, bot_id: &str) -> Result<(), LichessError> {
+    let mut events = client.stream_events().await?;
+    println!("listening for events");
+
+    while let Some(event) = events.next().await? {
+        match event {
+            Event::Challenge { challenge } => {
+                // challenges the bot sent show up too
+                if challenge.challenger.as_ref().is_some_and(|challenger| challenger.id == bot_id) {
+                    continue;
+                }
+
+                println!("challenge {}", challenge);
+                let games_in_progress = active_games().len();
+                let response = match policy().decline_reason(&challenge, true, games_in_progress) {
+                    None => {
+                        println!("accepting challenge {}", challenge.id);
+                        client.accept_challenge(&challenge.id).await
+                    }
+                    Some(reason) => {
+                        println!("declining challenge {}: {}", challenge.id, reason.key());
+                        client.decline_challenge(&challenge.id, reason).await
+                    }
+                };
+                // the challenge may have been cancelled in the meantime, which isn't a reason to reconnect
+                if let Err(e) = response {
+                    println!("could not answer challenge {}: {}", challenge.id, e);
+                }
+            }
+            Event::GameStart { game } => {
+                let Some(active_game) = ActiveGame::start(&game.game_id) else {
+                    println!("game {} is already being played", game.game_id);
+                    continue;
+                };
+                println!("game {} started", game.game_id);
+                let (client, bot_id) = (client.clone(), bot_id.to_string());
+                tokio::spawn(async move {
+                    if let Err(e) = play_game(&client, &game.game_id, &bot_id, RECONNECT_DELAY).await {
+                        println!("game {} failed: {}", game.game_id, e);
+                    }
+                    drop(active_game);
+                });
+            }
+            Event::GameFinish { game } => {
+                println!("game {} finished", game.game_id);
+                if let Err(e) = learn_from_finished_games(client).await {
+                    println!("could not learn from finished games: {}", e);
+                }
+            }
+            Event::Other => {}
+        }
+    }
+
+    Ok(())
+}
+
+// what doesn't change during a game
+struct GameSetup {
+    initial_fen: Option<String>,
+    bot_side: Player,
+    // games without a clock are correspondence games, which are searched like the Lambda function does
+    has_clock: bool,
+}
+
+// how long to wait before trying again after a game stream or a move fails, or None if trying again
+// won't help, e.g. when Lichess doesn't know the game
+fn retry_delay(e: &LichessError, reconnect_delay: Duration) -> Option<Duration> {
+    match e {
+        LichessError::RateLimited => Some(RATE_LIMIT_DELAY),
+        LichessError::Status(status, _) if status.is_client_error() => None,
+        _ => Some(reconnect_delay),
+    }
+}
+
+// how one connection to a game's stream came to an end
+enum StreamEnd {
+    GameOver,
+    // Lichess closed the stream while the game was still going on
+    Closed,
+    // the stream or a move failed
+    Failed(LichessError),
+}
+
+// plays a game until it is over, opening its stream again after `reconnect_delay` whenever it ends
+// or fails before then. Lichess sends the whole game again each time it is opened
+async fn play_game(client: &LichessClient, game_id: &str, bot_id: &str, reconnect_delay: Duration) -> Result<(), Error> {
+    let mut setup = None;
+
+    loop {
+        let delay = match follow_game(client, game_id, bot_id, &mut setup).await? {
+            StreamEnd::GameOver => return Ok(()),
+            StreamEnd::Closed => {
+                println!("game {} stream ended before the game was over", game_id);
+                reconnect_delay
+            }
+            StreamEnd::Failed(e) => {
+                println!("game {} stream failed: {}", game_id, e);
+                retry_delay(&e, reconnect_delay).ok_or(e)?
+            }
+        };
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// plays a game from one connection to its stream. The first event is the whole game, and each event
+// after it has the moves so far and the clocks. Errors from Lichess are returned as how the stream
+// ended, as playing the game on from a new stream can get past them
+async fn follow_game(client: &LichessClient, game_id: &str, bot_id: &str, setup: &mut Option<GameSetup>) -> Result<StreamEnd, Error> {
+    let mut stream = match client.stream_game(game_id).await {
+        Ok(stream) => stream,
+        Err(e) => return Ok(StreamEnd::Failed(e)),
+    };
+
+    loop {
+        let event = match stream.next().await {
+            Ok(Some(event)) => event,
+            Ok(None) => return Ok(StreamEnd::Closed),
+            Err(e) => return Ok(StreamEnd::Failed(e)),
+        };
+        // the clocks are as they were when the event was sent
+        let received = Instant::now();
+        let state: GameState = match event {
+            GameEvent::GameFull { white, black, clock, initial_fen, state } => {
+                let bot_side = if white.id.as_deref() == Some(bot_id) {
+                    Player::White
+                } else if black.id.as_deref() == Some(bot_id) {
+                    Player::Black
+                } else {
+                    return Err(format!("{} isn't playing in game {}", bot_id, game_id).into());
+                };
+                if let Some(clock) = &clock {
+                    println!("playing {} as {} with {}+{}", game_id, bot_side, clock_minutes(clock.initial / 1000), clock.increment / 1000);
+                }
+                *setup = Some(GameSetup {
+                    initial_fen: Some(initial_fen).filter(|fen| fen != "startpos"),
+                    bot_side,
+                    has_clock: clock.is_some(),
+                });
+                state
+            }
+            GameEvent::GameState(state) => state,
+            // chat lines and opponentGone don't change anything
+            GameEvent::Other => continue,
+        };
+        let Some(setup) = setup.as_ref() else { continue };
+
+        if state.status != "started" && state.status != "created" {
+            println!("game {} over: {}", game_id, state.status);
+            return Ok(StreamEnd::GameOver);
+        }
+
+        let plies = state.moves.split_whitespace().count();
+        let black_started = setup.initial_fen.as_deref().is_some_and(|fen| fen.split_whitespace().nth(1) == Some("b"));
+        let white_to_move = plies.is_multiple_of(2) != black_started;
+        if white_to_move != (setup.bot_side == Player::White) {
+            continue;
+        }
+
+        let clock = match (setup.has_clock, setup.bot_side) {
+            (false, _) => None,
+            (true, Player::White) => Some((state.wtime, state.winc)),
+            (true, Player::Black) => Some((state.btime, state.binc)),
+        };
+
+        let initial_fen = setup.initial_fen.clone();
+        let moves = state.moves;
+        let next_move = tokio::task::spawn_blocking(move || choose_move(initial_fen.as_deref(), &moves, clock, received)).await??;
+        if let Some(next_move) = next_move {
+            println!("making move {} in {}", next_move, game_id);
+            // the stream is opened again if the move doesn't get through, and the move is chosen again
+            if let Err(e) = client.make_move(game_id, &next_move).await {
+                return Ok(StreamEnd::Failed(e));
+            }
+        }
+    }
+}
+
+// plays out the game's moves and returns the bot's move in UCI format, or None if the game is over.
+// With a clock the search is given a share of the bot's time, otherwise it is the same fixed depth
+// search and blunder check as the Lambda function. The time since `received` has already gone from
+// the clock, e.g. while another game's search had the CPU
+fn choose_move(initial_fen: Option<&str>, moves: &str, clock: Option<(u64, u64)>, received: Instant) -> Result<Option<String>, String> {
+    let mut board = match initial_fen {
+        Some(fen) => Board::from_fen(fen).map_err(|e| format!("Invalid initial FEN {}: {:?}", fen, e))?,
+        None => Board::start_pos(),
+    };
+    for uci_move in moves.split_whitespace() {
+        let mv = parse_move(&board, uci_move).ok_or_else(|| format!("Illegal move {} in {}", uci_move, board.fen()))?;
+        board.apply_move(mv);
+    }
+    if board.generate_moves().is_empty() {
+        return Ok(None);
+    }
+
+    let _search = SEARCH_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let next_move: BitMove = match clock {
+        Some((time, increment)) => {
+            let waited = received.elapsed().as_millis() as u64;
+            let limits = SearchLimits { quiescence: true, ..SearchLimits::default() }
+                .with_clock(time.saturating_sub(waited + MOVE_OVERHEAD), increment, None);
+            match pick_book_move(&board) {
+                Some(book_move) => book_move,
+                None => iterative_deepening(board, limits, |info| println!("depth {} score {} move {}", info.depth, info.score, info.best_move)).unwrap(),
+            }
+        }
+        None => {
+            let next_move = calculate_next_move(board.clone());
+            blunder_check(board, next_move)
+        }
+    };
+    Ok(Some(next_move.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_lichess::{MockLichess, OK};
+
+    const MATE_IN_ONE: &str = "7k/6pp/8/8/8/8/8/R5K1 w - - 0 30";
+    const GAME_OVER: &str = r#"{"type": "gameState", "moves": "", "wtime": 60000, "btime": 60000, "winc": 1000, "binc": 1000, "status": "resign"}"#;
+
+    // plays a game without waiting long to reconnect, or for a stalled stream
+    async fn play(lichess: &MockLichess) -> Result<(), Error> {
+        let client = lichess.client().with_stream_timeout(Duration::from_millis(200));
+        play_game(&client, "game0001", "bot", Duration::from_millis(10)).await
+    }
+
+    fn game_full(white: &str, black: &str, moves: &str) -> String {
+        serde_json::json!({
+            "type": "gameFull",
+            "id": "game0001",
+            "white": {"id": white, "name": white},
+            "black": {"id": black, "name": black},
+            "clock": {"initial": 60000, "increment": 1000},
+            "initialFen": MATE_IN_ONE,
+            "state": {"type": "gameState", "moves": moves, "wtime": 60000, "btime": 60000, "winc": 1000, "binc": 1000, "status": "started"},
+        }).to_string()
+    }
+
+    #[tokio::test]
+    async fn moves_when_the_game_stream_says_it_is_the_bots_turn() {
+        let lichess = MockLichess::start();
+        lichess.stream("/api/bot/game/stream/game0001", &[
+            &game_full("bot", "alice", ""),
+            "",
+            r#"{"type": "chatLine", "room": "player", "username": "alice", "text": "hi"}"#,
+            r#"{"type": "gameState", "moves": "a1a8", "wtime": 59000, "btime": 60000, "winc": 1000, "binc": 1000, "status": "mate"}"#,
+        ]).respond("POST", "/api/bot/game/game0001/move/a1a8", 200, OK);
+
+        play(&lichess).await.unwrap();
+
+        assert_eq!(lichess.request_lines(), vec![
+            "GET /api/bot/game/stream/game0001",
+            "POST /api/bot/game/game0001/move/a1a8",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_opponent_to_move() {
+        let lichess = MockLichess::start();
+        lichess.stream("/api/bot/game/stream/game0001", &[&game_full("alice", "bot", ""), GAME_OVER]);
+
+        play(&lichess).await.unwrap();
+
+        assert_eq!(lichess.request_lines(), vec!["GET /api/bot/game/stream/game0001"]);
+    }
+
+    #[tokio::test]
+    async fn game_stream_is_opened_again_until_the_game_is_over() {
+        let lichess = MockLichess::start();
+        // the first stream stalls before it is the bot's turn, the second ends early and the third
+        // has the opponent's move and the mate
+        lichess
+            .stalled_stream("/api/bot/game/stream/game0001", &[&game_full("bot", "alice", "a1a2 h8g8 a2a1")])
+            .stream("/api/bot/game/stream/game0001", &[])
+            .stream("/api/bot/game/stream/game0001", &[
+                &game_full("bot", "alice", "a1a2 h8g8 a2a1 g8h8"),
+                r#"{"type": "gameState", "moves": "a1a2 h8g8 a2a1 g8h8 a1a8", "wtime": 59000, "btime": 60000, "winc": 1000, "binc": 1000, "status": "mate"}"#,
+            ])
+            .respond("POST", "/api/bot/game/game0001/move/a1a8", 200, OK);
+
+        play(&lichess).await.unwrap();
+
+        assert_eq!(lichess.request_lines(), vec![
+            "GET /api/bot/game/stream/game0001",
+            "GET /api/bot/game/stream/game0001",
+            "GET /api/bot/game/stream/game0001",
+            "POST /api/bot/game/game0001/move/a1a8",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn game_lichess_does_not_know_is_not_retried() {
+        let lichess = MockLichess::start();
+        lichess.respond("GET", "/api/bot/game/stream/game0001", 404, r#"{"error": "No such game"}"#);
+
+        assert!(play(&lichess).await.is_err());
+        assert_eq!(lichess.request_lines().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn game_the_bot_isnt_playing_is_an_error() {
+        let lichess = MockLichess::start();
+        lichess.stream("/api/bot/game/stream/game0001", &[&game_full("alice", "bob", "")]);
+
+        assert!(play(&lichess).await.is_err());
+    }
+
+    #[test]
+    fn a_game_is_only_played_once_at_a_time() {
+        let game = ActiveGame::start("active01").unwrap();
+        assert!(ActiveGame::start("active01").is_none());
+        assert!(active_games().contains("active01"));
+
+        drop(game);
+        assert!(!active_games().contains("active01"));
+        assert!(ActiveGame::start("active01").is_some());
+    }
+}
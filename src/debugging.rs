@@ -1,6 +1,12 @@
 // This file is used for testing purposes. It is not used in the lambda function.
-use pleco::{Board,Player,PieceType,SQ,BitMove};
+use pleco::{Board,Player};
 use crate::minimax::minimax;
+use crate::evaluation::{is_draw, trace_evaluation};
+use crate::syzygy::tablebases;
+use crate::retrograde;
+use crate::book::{book, book_moves, polyglot_key, settings};
+use crate::learning;
+use crate::symmetry::{check_symmetry, random_positions};
 
 //takes in a board, and prints the board to the console. Used for debugging
 #[allow(dead_code)]
@@ -34,11 +40,11 @@ pub fn draw_board(board_obj : Board) {
     }
 
     //iterate and print board
-    for i in 0..8 {
-        for j in 0..8 {
-            print!("{}, ", board[i][j]);
+    for row in board {
+        for square in row {
+            print!("{}, ", square);
         }
-        println!("");
+        println!();
     }
 }
 
@@ -46,7 +52,7 @@ pub fn draw_board(board_obj : Board) {
 pub fn play_against_itself(mut board: Board, depth: u8) {
     let mut total_time_taken = 0;
 
-    while board.checkmate() == false && board.stalemate() == false {
+    while !board.checkmate() && !is_draw(&board) {
         let start_time = std::time::Instant::now();
         let next_move = minimax(board.clone(), depth);
         let time_taken_to_find_move = start_time.elapsed();
@@ -66,12 +72,108 @@ pub fn play_against_itself(mut board: Board, depth: u8) {
             println!("Black wins!");
         }
     }
-    else if board.stalemate() {
-        println!("Stalemate :(");
+    else if is_draw(&board) {
+        println!("Draw by stalemate or the fifty move rule :(");
     }
     else {
         println!("Game didn't end in checkmate or stalemate (this shouldn't happen)");
     }
 
     println!("Total time taken: {} ms", total_time_taken);
-}
\ No newline at end of file
+}
+
+// prints the breakdown of the evaluation of the given position, either as a table or as JSON
+pub fn print_eval_trace(board: &Board, as_json: bool) {
+    let trace = trace_evaluation(board);
+
+    if as_json {
+        let mut report = trace.to_json();
+        report["fen"] = board.fen().into();
+        println!("{}", report);
+    }
+    else {
+        println!("{}", board.fen());
+        println!("{}", trace);
+    }
+}
+
+// prints what the generated endgame tables and the Syzygy tablebases say about the given position
+pub fn print_tablebase_probe(mut board: Board) {
+    let tables = retrograde::loaded_tables();
+    if !tables.is_empty() {
+        println!("generated tables: {}", tables.join(", "));
+        match retrograde::probe(&board) {
+            Some(result) => println!("table result: {:?}", result),
+            None => println!("table result: position isn't in the generated tables"),
+        }
+    }
+
+    let Some(tablebases) = tablebases() else {
+        println!("No Syzygy tables found, set RUST_BOT_SYZYGY_PATH to the directory they are in");
+        return;
+    };
+
+    println!("{} tables for up to {} pieces", tablebases.num_tables(), tablebases.max_pieces());
+    match tablebases.probe_wdl(&mut board) {
+        Some(wdl) => println!("wdl: {}", wdl.name()),
+        None => println!("wdl: position isn't in the tables"),
+    }
+    match tablebases.probe_dtz(&mut board) {
+        Some(dtz) => println!("dtz: {}", dtz),
+        None => println!("dtz: position isn't in the tables"),
+    }
+    if let Some((best_move, dtz)) = tablebases.best_move(&mut board) {
+        println!("best move: {} (dtz {})", best_move, dtz);
+    }
+}
+
+// prints the position's Polyglot key, the moves the opening book has for it, what the bot has
+// learned about its moves and the weights the moves are picked with
+pub fn print_book_moves(board: &Board) {
+    println!("key: {:016x}", polyglot_key(board));
+
+    match book() {
+        Some(book) => {
+            let moves = book.moves(board);
+            if moves.is_empty() {
+                println!("position isn't in the book");
+            }
+            for (mv, weight) in moves {
+                println!("book {:<8}weight {:>6}", mv.to_string(), weight);
+            }
+        }
+        None => println!("No opening book loaded, set RUST_BOT_BOOK to a Polyglot .bin file"),
+    }
+
+    if learning::is_enabled() {
+        for (mv, record) in learning::learned_moves(board) {
+            println!("learned {:<8}+{} ={} -{} (score {:.2})", mv.to_string(), record.wins, record.draws, record.losses, record.score());
+        }
+    }
+
+    let moves = book_moves(board);
+    let total: f64 = moves.iter().map(|&(_, weight)| weight).sum();
+    for (mv, weight) in moves {
+        println!("picks {:<8}{:.1}%", mv.to_string(), 100.0 * weight / total.max(1.0));
+    }
+    if board.moves_played() >= settings().max_depth {
+        println!("past the book depth of {} plies, so no book moves are played", settings().max_depth);
+    }
+}
+
+// checks that swapping colours negates the evaluation of the given positions, and prints the terms
+// that differ for each position where it doesn't
+pub fn print_symmetry_report(positions: &[Board]) {
+    let failures: Vec<_> = positions.iter().filter_map(check_symmetry).collect();
+
+    for failure in &failures {
+        println!("{}", failure);
+        println!();
+    }
+    println!("{} of {} positions aren't colour symmetric", failures.len(), positions.len());
+}
+
+// checks the positions of random games, see `print_symmetry_report`
+pub fn print_random_symmetry_report(games: usize) {
+    print_symmetry_report(&random_positions(games, 0x5eed));
+}
@@ -0,0 +1,396 @@
+// Knowledge of endgames the normal evaluation gets wrong. `recognise` looks at the material on the
+// board and picks a specialised evaluator, which adjusts the normal score of the position.
+use crate::eval_params::EvalParams;
+use crate::retrograde::{self, TableResult};
+use crate::syzygy::TABLEBASE_WIN_SCORE;
+
+use pleco::{Board,Player,PieceType,SQ,BitBoard,File,Rank};
+use pleco::helper::prelude::king_moves;
+
+// bonus for the strong side in a won endgame, so it prefers positions it knows how to win
+const KNOWN_WIN_BONUS: i32 = 400;
+
+// mop-up weights for driving the lone king to the edge, taking away its squares and bringing the
+// strong king closer. Minor pieces also need the lone king in a corner
+const EDGE_WEIGHT: i32 = 30;
+const KING_AREA_WEIGHT: i32 = 8;
+const KING_PROXIMITY_WEIGHT: i32 = 20;
+const CORNER_WEIGHT: i32 = 60;
+
+// scale factors, out of `SCALE_NORMAL`, for material the side ahead usually can't win with
+const SCALE_NORMAL: i32 = 64;
+const DRAWISH_SCALE: i32 = 8;
+const KNIGHTS_ONLY_SCALE: i32 = 2;
+
+/// The endgames that have their own evaluation
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Endgame {
+    // solved by one of the generated endgame tables
+    Table(TableResult),
+    // the strong side has mating material against a lone king
+    MopUp(Player),
+    // king, bishop and knight against a lone king, which has to be mated in a bishop coloured corner
+    Kbnk(Player),
+    // king and pawn against king
+    Kpk(Player),
+    // bishop and pawns on a rook file whose queening square isn't the bishop's colour
+    WrongRookPawn(Player),
+    // neither side can possibly mate
+    InsufficientMaterial,
+    // bishops on opposite colours and otherwise only pawns
+    OppositeBishops,
+    // material that is usually drawn, so the score is scaled towards zero by the given factor
+    Scaled(i32),
+}
+
+impl Endgame {
+    pub fn name(self) -> &'static str {
+        match self {
+            Endgame::Table(_) => "table",
+            Endgame::MopUp(_) => "mop_up",
+            Endgame::Kbnk(_) => "kbnk",
+            Endgame::Kpk(_) => "kpk",
+            Endgame::WrongRookPawn(_) => "wrong_rook_pawn",
+            Endgame::InsufficientMaterial => "insufficient_material",
+            Endgame::OppositeBishops => "opposite_bishops",
+            Endgame::Scaled(_) => "scaled",
+        }
+    }
+}
+
+// the number of each piece type a side has
+#[derive(Clone, Copy)]
+struct Material {
+    pawns: u8,
+    knights: u8,
+    bishops: u8,
+    rooks: u8,
+    queens: u8,
+}
+
+impl Material {
+    fn of(board: &Board, player: Player) -> Material {
+        Material {
+            pawns: board.count_piece(player, PieceType::P),
+            knights: board.count_piece(player, PieceType::N),
+            bishops: board.count_piece(player, PieceType::B),
+            rooks: board.count_piece(player, PieceType::R),
+            queens: board.count_piece(player, PieceType::Q),
+        }
+    }
+
+    fn pieces(self) -> u8 {
+        self.knights + self.bishops + self.rooks + self.queens
+    }
+
+    fn is_lone_king(self) -> bool {
+        self.pawns == 0 && self.pieces() == 0
+    }
+}
+
+// returns the endgame on the board, if it has a specialised evaluation. `params` are the weights the
+// position is being evaluated with
+pub fn recognise(board: &Board, params: &EvalParams) -> Option<Endgame> {
+    if let Some(result) = retrograde::probe(board) {
+        return Some(Endgame::Table(result));
+    }
+
+    let white = Material::of(board, Player::White);
+    let black = Material::of(board, Player::Black);
+
+    for (strong, weak, player) in [(white, black, Player::White), (black, white, Player::Black)] {
+        if !weak.is_lone_king() {
+            continue;
+        }
+
+        if strong.pieces() == 0 && strong.pawns == 1 {
+            return Some(Endgame::Kpk(player));
+        }
+        if strong.pawns == 0 && strong.pieces() == 2 && strong.bishops == 1 && strong.knights == 1 {
+            return Some(Endgame::Kbnk(player));
+        }
+        if strong.queens > 0 || strong.rooks > 0 || (strong.bishops > 0 && strong.knights > 0) || has_both_bishop_colours(board, player) {
+            return Some(Endgame::MopUp(player));
+        }
+        if strong.pieces() == 1 && strong.bishops == 1 && strong.pawns > 0 && is_wrong_rook_pawn(board, player) {
+            return Some(Endgame::WrongRookPawn(player));
+        }
+    }
+
+    let minor_only = |m: Material| m.pawns == 0 && m.rooks == 0 && m.queens == 0 && m.bishops + m.knights <= 1;
+    if minor_only(white) && minor_only(black) {
+        return Some(Endgame::InsufficientMaterial);
+    }
+
+    let bishop_only = |m: Material| m.bishops == 1 && m.knights == 0 && m.rooks == 0 && m.queens == 0;
+    if bishop_only(white) && bishop_only(black) {
+        let white_bishop = board.piece_bb(Player::White, PieceType::B).to_sq();
+        let black_bishop = board.piece_bb(Player::Black, PieceType::B).to_sq();
+        if white_bishop.opposite_colors(black_bishop) {
+            return Some(Endgame::OppositeBishops);
+        }
+    }
+
+    // without pawns the side ahead needs more than a minor piece extra to win, and knights can't
+    // force mate at all
+    for (strong, player) in [(white, Player::White), (black, Player::Black)] {
+        let advantage = material(board, player, params) - material(board, !player, params);
+        if strong.pawns > 0 || advantage <= 0 {
+            continue;
+        }
+
+        if strong.bishops + strong.rooks + strong.queens == 0 {
+            return Some(Endgame::Scaled(KNIGHTS_ONLY_SCALE));
+        }
+        if advantage <= params.piece_value(PieceType::B) {
+            return Some(Endgame::Scaled(DRAWISH_SCALE));
+        }
+    }
+
+    None
+}
+
+// takes the normal score of a position in a recognised endgame and returns the adjusted score. When
+// mating a lone king the normal score is ignored, as its positional terms only get in the way
+pub fn evaluate_endgame(board: &Board, endgame: Endgame, score: i32, params: &EvalParams) -> i32 {
+    match endgame {
+        // quicker mates score higher, like in the search
+        Endgame::Table(result) => relative(board.turn(), match result {
+            TableResult::Win(plies) => TABLEBASE_WIN_SCORE - plies as i32,
+            TableResult::Loss(plies) => -TABLEBASE_WIN_SCORE + plies as i32,
+            TableResult::Draw => 0,
+        }),
+        Endgame::MopUp(strong) => {
+            let mut bonus = mop_up_bonus(board, strong);
+            // minor pieces can only mate in a corner
+            if board.count_piece(strong, PieceType::Q) == 0 && board.count_piece(strong, PieceType::R) == 0 {
+                bonus += corner_bonus(board, strong, &[SQ::A1, SQ::H1, SQ::A8, SQ::H8]);
+            }
+            relative(strong, material(board, strong, params) + KNOWN_WIN_BONUS + bonus)
+        }
+        Endgame::Kbnk(strong) => relative(strong, material(board, strong, params) + KNOWN_WIN_BONUS + kbnk_bonus(board, strong)),
+        Endgame::Kpk(strong) => evaluate_kpk(board, strong, score),
+        Endgame::WrongRookPawn(_) => score / 16,
+        Endgame::InsufficientMaterial => 0,
+        Endgame::OppositeBishops => {
+            let pawn_difference = board.count_piece(Player::White, PieceType::P) as i32 - board.count_piece(Player::Black, PieceType::P) as i32;
+            if pawn_difference.abs() <= 1 { score / 4 } else { score / 2 }
+        }
+        Endgame::Scaled(factor) => score * factor / SCALE_NORMAL,
+    }
+}
+
+// turns a score for `player` into a score where positive is good for white
+fn relative(player: Player, score: i32) -> i32 {
+    if player == Player::White { score } else { -score }
+}
+
+// returns the value of a side's pieces, not counting its king
+fn material(board: &Board, player: Player, params: &EvalParams) -> i32 {
+    [PieceType::P, PieceType::N, PieceType::B, PieceType::R, PieceType::Q].iter()
+        .map(|&piece| params.piece_value(piece) * board.count_piece(player, piece) as i32)
+        .sum()
+}
+
+// returns how far a square is from the centre, from 0 in the middle four squares to 6 in a corner
+fn centre_distance(sq: SQ) -> i32 {
+    let file = sq.file() as i32;
+    let rank = sq.rank() as i32;
+    (3 - file).max(file - 4) + (3 - rank).max(rank - 4)
+}
+
+// drives the lone king to the edge of the board, takes away the squares it can move to and brings the
+// strong king towards it
+fn mop_up_bonus(board: &Board, strong: Player) -> i32 {
+    let strong_king = board.king_sq(strong);
+    let weak_king = board.king_sq(!strong);
+
+    EDGE_WEIGHT * centre_distance(weak_king)
+        + KING_AREA_WEIGHT * (64 - king_area(board, !strong))
+        + KING_PROXIMITY_WEIGHT * (7 - strong_king.distance(weak_king) as i32)
+}
+
+// returns the number of squares a king could walk to without stepping onto an attacked square, which
+// shrinks as the strong side boxes the lone king in
+fn king_area(board: &Board, player: Player) -> i32 {
+    let king = board.king_sq(player);
+    // take the king off the board so squares behind it on a line count as attacked
+    let occupied = board.occupied() ^ king.to_bb();
+    let enemies = board.get_occupied_player(!player);
+    let is_safe = |sq: SQ| (board.attackers_to(sq, occupied) & enemies).is_empty();
+
+    let mut reached = king.to_bb();
+    let mut frontier = king.to_bb();
+    while frontier.is_not_empty() {
+        let mut next = BitBoard(0);
+        for sq in frontier {
+            for to in king_moves(sq) & !reached {
+                if is_safe(to) {
+                    next |= to.to_bb();
+                }
+            }
+        }
+        reached |= next;
+        frontier = next;
+    }
+
+    reached.count_bits() as i32
+}
+
+// like mop-up, but the lone king can only be mated in a corner the bishop can reach
+fn kbnk_bonus(board: &Board, strong: Player) -> i32 {
+    let bishop = board.piece_bb(strong, PieceType::B).to_sq();
+    // a1 is a dark square. pleco's `on_dark_square` is true for light squares, so it isn't used here
+    let corners = if bishop.opposite_colors(SQ::A1) { [SQ::H1, SQ::A8] } else { [SQ::A1, SQ::H8] };
+
+    mop_up_bonus(board, strong) + corner_bonus(board, strong, &corners)
+}
+
+// rewards the lone king being close to the nearest of the given corners
+fn corner_bonus(board: &Board, strong: Player, corners: &[SQ]) -> i32 {
+    let weak_king = board.king_sq(!strong);
+    let corner_distance = corners.iter().map(|&corner| weak_king.distance(corner) as i32).min().unwrap_or(0);
+    CORNER_WEIGHT * (7 - corner_distance)
+}
+
+fn has_both_bishop_colours(board: &Board, player: Player) -> bool {
+    let bishops = board.piece_bb(player, PieceType::B);
+    bishops.into_iter().any(|sq| sq.on_dark_square()) && bishops.into_iter().any(|sq| sq.on_light_square())
+}
+
+// all the pawns are on one rook file, the bishop can't control the queening square and the lone king
+// has reached it, so the position is a draw however many pawns there are
+fn is_wrong_rook_pawn(board: &Board, strong: Player) -> bool {
+    let pawns = board.piece_bb(strong, PieceType::P);
+    let file = pawns.bit_scan_forward().file();
+    if !(file == File::A || file == File::H) || (pawns & !file.bb()).is_not_empty() {
+        return false;
+    }
+
+    let queening_sq = SQ::make(file, strong.relative_rank(Rank::R8));
+    let bishop = board.piece_bb(strong, PieceType::B).to_sq();
+
+    bishop.opposite_colors(queening_sq) && board.king_sq(!strong).distance(queening_sq) <= 1
+}
+
+// king and pawn against king, using the rule of the square and the key squares in front of the pawn
+fn evaluate_kpk(board: &Board, strong: Player, score: i32) -> i32 {
+    let pawn = board.piece_bb(strong, PieceType::P).to_sq();
+    let strong_king = board.king_sq(strong);
+    let weak_king = board.king_sq(!strong);
+    let pawn_rank = strong.relative_rank_of_sq(pawn) as i32;
+    let queening_sq = SQ::make(pawn.file(), strong.relative_rank(Rank::R8));
+    let is_rook_pawn = pawn.file() == File::A || pawn.file() == File::H;
+
+    let win = score + relative(strong, KNOWN_WIN_BONUS + 20 * pawn_rank);
+    let draw = score / 8;
+
+    // a rook pawn can't be promoted once the lone king reaches the corner
+    if is_rook_pawn && weak_king.distance(queening_sq) <= 1 {
+        return draw;
+    }
+
+    // rule of the square: the lone king can't catch the pawn, and the strong king isn't in its way
+    let moves_to_queen = (7 - pawn_rank).min(5);
+    let weak_to_move = if board.turn() != strong { 1 } else { 0 };
+    let blocked_by_own_king = strong_king.file() == pawn.file() && strong.relative_rank_of_sq(strong_king) as i32 > pawn_rank;
+    if weak_king.distance(queening_sq) as i32 - weak_to_move > moves_to_queen && !blocked_by_own_king {
+        return win;
+    }
+
+    if !is_rook_pawn && key_squares(strong, pawn).into_iter().any(|sq| sq == strong_king) {
+        return win;
+    }
+
+    // the lone king is in front of the pawn and the strong king isn't, which is a draw with best play
+    let weak_in_front = weak_king.file() == pawn.file() && strong.relative_rank_of_sq(weak_king) as i32 > pawn_rank;
+    let strong_in_front = strong.relative_rank_of_sq(strong_king) as i32 > pawn_rank;
+    if weak_in_front && !strong_in_front {
+        return draw;
+    }
+
+    score / 2
+}
+
+// squares that win the game if the strong king reaches them. Two ranks in front of the pawn on its
+// own and adjacent files, or one and two ranks in front once it is past the middle of the board
+fn key_squares(strong: Player, pawn: SQ) -> BitBoard {
+    let pawn_rank = strong.relative_rank_of_sq(pawn) as i32;
+    let file = pawn.file() as i32;
+    let mut squares = BitBoard(0);
+
+    let ranks_ahead: &[i32] = if pawn_rank >= 4 { &[1, 2] } else { &[2] };
+    for &ahead in ranks_ahead {
+        let rank = pawn_rank + ahead;
+        if rank > 7 {
+            continue;
+        }
+        for f in (file - 1).max(0)..=(file + 1).min(7) {
+            let relative_sq = (rank * 8 + f) as u8;
+            squares |= strong.relative_square(SQ(relative_sq)).to_bb();
+        }
+    }
+
+    squares
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recognised(fen: &str) -> (Board, Option<Endgame>) {
+        let board = Board::from_fen(fen).unwrap();
+        let endgame = recognise(&board, &EvalParams::default());
+        (board, endgame)
+    }
+
+    fn evaluated(fen: &str, score: i32) -> i32 {
+        let (board, endgame) = recognised(fen);
+        evaluate_endgame(&board, endgame.unwrap(), score, &EvalParams::default())
+    }
+
+    #[test]
+    fn kpk_wins_when_the_pawn_outruns_the_king() {
+        let fen = "8/8/3P4/8/8/8/8/4K2k w - - 0 1";
+        assert_eq!(recognised(fen).1, Some(Endgame::Kpk(Player::White)));
+        assert!(evaluated(fen, 0) > KNOWN_WIN_BONUS);
+
+        // the same for black
+        assert!(evaluated("4k3/8/8/8/8/8/3p4/7K b - - 0 1", 0) < -KNOWN_WIN_BONUS);
+    }
+
+    #[test]
+    fn kpk_is_drawn_with_the_lone_king_in_front_of_the_pawn() {
+        assert_eq!(evaluated("8/4k3/8/8/8/4P3/4K3/8 w - - 0 1", 800), 100);
+    }
+
+    #[test]
+    fn kbnk_drives_the_king_to_a_corner_the_bishop_covers() {
+        // the bishop is on a light square, so h1 is the corner to mate in
+        let right_corner = "8/8/8/8/2BK4/2N5/8/7k w - - 0 1";
+        let wrong_corner = "8/8/8/8/2BK4/2N5/8/k7 w - - 0 1";
+        assert_eq!(recognised(right_corner).1, Some(Endgame::Kbnk(Player::White)));
+        assert!(evaluated(right_corner, 0) > evaluated(wrong_corner, 0));
+    }
+
+    #[test]
+    fn wrong_rook_pawn_is_drawn() {
+        // the a8 queening square is light and the bishop is on a dark square
+        let fen = "1k6/8/8/P7/3B4/2K5/8/8 w - - 0 1";
+        assert_eq!(recognised(fen).1, Some(Endgame::WrongRookPawn(Player::White)));
+        assert_eq!(evaluated(fen, 800), 50);
+
+        // with the right bishop it is a normal position
+        assert_eq!(recognised("1k6/8/8/P7/2B5/2K5/8/8 w - - 0 1").1, None);
+    }
+
+    #[test]
+    fn recognising_uses_the_given_piece_values() {
+        let board = Board::from_fen("8/8/8/3k4/8/3b4/8/R3K3 w - - 0 1").unwrap();
+        assert_eq!(recognise(&board, &EvalParams::default()), Some(Endgame::Scaled(DRAWISH_SCALE)));
+
+        let mut params = EvalParams::default();
+        params.piece_values[3] = 2000;
+        assert_eq!(recognise(&board, &params), None);
+    }
+}
@@ -0,0 +1,202 @@
+use crate::constants;
+
+use pleco::PieceType;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::sync::OnceLock;
+use constants::{ROOK_SCORE_GRID, PAWN_SCORE_GRID, BISHOP_SCORE_GRID, KNIGHT_SCORE_GRID, QUEEN_SCORE_GRID, KING_SCORE_GRID, EMPTY_SCORE_GRID, get_piece_value};
+
+pub type ScoreGrid = [[i32; 8]; 8];
+
+// piece types that have a value and a score grid, in the order they are stored in `EvalParams`
+pub const PARAM_PIECES: [PieceType; 6] = [PieceType::P, PieceType::N, PieceType::B, PieceType::R, PieceType::Q, PieceType::K];
+const PIECE_NAMES: [&str; 6] = ["pawn", "knight", "bishop", "rook", "queen", "king"];
+
+static PARAMS: OnceLock<EvalParams> = OnceLock::new();
+
+/// The weights used by the evaluation. The defaults are the values in `constants.rs`, but they can
+/// be replaced at startup with a parameter file so weights can be changed without recompiling
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvalParams {
+    pub piece_values: [i32; 6],
+    pub score_grids: [ScoreGrid; 6],
+}
+
+// the format of a parameter file. Any piece that is left out keeps its default value
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParamsFile {
+    #[serde(default)]
+    piece_values: HashMap<String, i32>,
+    #[serde(default)]
+    piece_square_tables: HashMap<String, Vec<Vec<i32>>>,
+}
+
+impl Default for EvalParams {
+    fn default() -> Self {
+        EvalParams {
+            piece_values: PARAM_PIECES.map(get_piece_value),
+            score_grids: [PAWN_SCORE_GRID, KNIGHT_SCORE_GRID, BISHOP_SCORE_GRID, ROOK_SCORE_GRID, QUEEN_SCORE_GRID, KING_SCORE_GRID],
+        }
+    }
+}
+
+impl EvalParams {
+    /// returns numeric value of a piece
+    pub fn piece_value(&self, piece: PieceType) -> i32 {
+        match param_index(piece) {
+            Some(i) => self.piece_values[i],
+            None => 0,
+        }
+    }
+
+    /// returns the score grid of a piece, laid out as in `constants.rs`
+    pub fn score_grid(&self, piece: PieceType) -> &ScoreGrid {
+        match param_index(piece) {
+            Some(i) => &self.score_grids[i],
+            None => &EMPTY_SCORE_GRID,
+        }
+    }
+
+    /// parses a JSON parameter file, starting from the default values
+    pub fn from_json(contents: &str) -> Result<EvalParams, String> {
+        let file: ParamsFile = serde_json::from_str(contents).map_err(|e| format!("Invalid parameter file: {}", e))?;
+        let mut params = EvalParams::default();
+
+        for (name, value) in file.piece_values {
+            let i = piece_name_index(&name)?;
+            if value <= 0 {
+                return Err(format!("Piece value for {} must be positive, got {}", name, value));
+            }
+            params.piece_values[i] = value;
+        }
+
+        for (name, rows) in file.piece_square_tables {
+            let i = piece_name_index(&name)?;
+            params.score_grids[i] = to_score_grid(&name, &rows)?;
+        }
+
+        Ok(params)
+    }
+
+    pub fn load(path: &str) -> Result<EvalParams, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Could not read parameter file {}: {}", path, e))?;
+        EvalParams::from_json(&contents)
+    }
+
+    /// serialises the parameters in the same format `from_json` reads, with each row of a table on
+    /// its own line so the output is easy to edit by hand
+    pub fn to_pretty_json(&self) -> String {
+        let values: Vec<String> = PIECE_NAMES.iter().enumerate()
+            .map(|(i, name)| format!("    \"{}\": {}", name, self.piece_values[i]))
+            .collect();
+
+        let tables: Vec<String> = PIECE_NAMES.iter().enumerate()
+            .map(|(i, name)| {
+                let rows: Vec<String> = self.score_grids[i].iter().map(|row| format!("      {}", json!(row))).collect();
+                format!("    \"{}\": [\n{}\n    ]", name, rows.join(",\n"))
+            })
+            .collect();
+
+        format!("{{\n  \"piece_values\": {{\n{}\n  }},\n  \"piece_square_tables\": {{\n{}\n  }}\n}}", values.join(",\n"), tables.join(",\n"))
+    }
+}
+
+fn param_index(piece: PieceType) -> Option<usize> {
+    PARAM_PIECES.iter().position(|&p| p == piece)
+}
+
+fn piece_name_index(name: &str) -> Result<usize, String> {
+    PIECE_NAMES.iter().position(|&n| n == name).ok_or_else(|| format!("Unknown piece \"{}\", expected one of {:?}", name, PIECE_NAMES))
+}
+
+// checks a table from a parameter file is 8 rows of 8 columns
+fn to_score_grid(name: &str, rows: &[Vec<i32>]) -> Result<ScoreGrid, String> {
+    if rows.len() != 8 {
+        return Err(format!("Piece-square table for {} must have 8 rows, got {}", name, rows.len()));
+    }
+
+    let mut grid = [[0; 8]; 8];
+    for (i, row) in rows.iter().enumerate() {
+        grid[i] = row.as_slice().try_into().map_err(|_| format!("Row {} of the piece-square table for {} must have 8 columns, got {}", i, name, row.len()))?;
+    }
+
+    Ok(grid)
+}
+
+/// loads the parameter file named by RUST_BOT_EVAL_PARAMS, if it is set. Should be called once at
+/// startup, before anything is evaluated
+pub fn init_params_from_env() -> Result<(), String> {
+    let params = match env::var("RUST_BOT_EVAL_PARAMS") {
+        Ok(path) if !path.is_empty() => EvalParams::load(&path)?,
+        _ => EvalParams::default(),
+    };
+
+    PARAMS.set(params).map_err(|_| "Evaluation parameters have already been initialised".to_string())
+}
+
+/// returns the parameters the engine is currently using
+pub fn params() -> &'static EvalParams {
+    PARAMS.get_or_init(EvalParams::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluation::evaluate_with;
+    use pleco::Board;
+
+    #[test]
+    fn parameter_file_is_checked() {
+        let seven_rows = format!(r#"{{"piece_square_tables": {{"rook": {}}}}}"#, json!(vec![vec![0; 8]; 7]));
+        assert!(EvalParams::from_json(&seven_rows).unwrap_err().contains("must have 8 rows"));
+
+        let mut rows = vec![vec![0; 8]; 8];
+        rows[3].pop();
+        let short_row = format!(r#"{{"piece_square_tables": {{"rook": {}}}}}"#, json!(rows));
+        assert!(EvalParams::from_json(&short_row).unwrap_err().contains("Row 3"));
+
+        assert!(EvalParams::from_json(r#"{"piece_values": {"knight": 300}, "mobility": 5}"#).unwrap_err().contains("unknown field"));
+        assert!(EvalParams::from_json(r#"{"piece_values": {"archbishop": 800}}"#).unwrap_err().contains("Unknown piece"));
+        assert!(EvalParams::from_json(r#"{"piece_values": {"knight": 0}}"#).unwrap_err().contains("must be positive"));
+        assert!(EvalParams::from_json(r#"{"piece_values": {"queen": -900}}"#).unwrap_err().contains("must be positive"));
+    }
+
+    #[test]
+    fn pieces_left_out_keep_their_defaults() {
+        let params = EvalParams::from_json(r#"{"piece_values": {"knight": 350}}"#).unwrap();
+        assert_eq!(params.piece_value(PieceType::N), 350);
+        assert_eq!(params.piece_value(PieceType::B), EvalParams::default().piece_value(PieceType::B));
+        assert_eq!(params.score_grids, EvalParams::default().score_grids);
+    }
+
+    #[test]
+    fn written_parameters_read_back_the_same() {
+        let defaults = EvalParams::default();
+        assert_eq!(EvalParams::from_json(&defaults.to_pretty_json()).unwrap(), defaults);
+
+        let mut changed = defaults.clone();
+        changed.piece_values[1] = 333;
+        changed.score_grids[3][6][2] = -17;
+        assert_eq!(EvalParams::from_json(&changed.to_pretty_json()).unwrap(), changed);
+    }
+
+    #[test]
+    fn loaded_file_changes_the_evaluation() {
+        let path = std::env::temp_dir().join(format!("eval-params-{}.json", std::process::id()));
+        fs::write(&path, r#"{"piece_values": {"knight": 600}}"#).unwrap();
+        let params = EvalParams::load(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).ok();
+
+        // white is a knight up, so gains what a knight gained
+        let board = Board::from_fen("r1bqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1").unwrap();
+        let defaults = EvalParams::default();
+        let knight = params.piece_value(PieceType::N) - defaults.piece_value(PieceType::N);
+        assert_eq!(evaluate_with(&board, &params) - evaluate_with(&board, &defaults), knight);
+
+        assert!(EvalParams::load("/nonexistent/params.json").unwrap_err().contains("Could not read"));
+    }
+}
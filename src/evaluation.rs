@@ -0,0 +1,802 @@
+use crate::constants;
+use crate::eval_params;
+use crate::nnue;
+use crate::endgame;
+use crate::personality::personality;
+use crate::scripting;
+
+use pleco::{Board,Player,PieceType,SQ,BitBoard,BitMove,File,Rank,Piece};
+use pleco::helper::prelude::{forward_rank_bb, king_moves, pawn_attacks_from};
+use serde_json::{json, Value};
+use std::fmt;
+use constants::square_to_int;
+use eval_params::{EvalParams, params, PARAM_PIECES};
+use endgame::{Endgame, evaluate_endgame};
+
+pub const CHECKMATE_SCORE: i32 = 1000000;
+
+// penalty for the side to move being in check
+const IN_CHECK_PENALTY: i32 = 400;
+
+// pawn structure weights
+const DOUBLED_PAWN_PENALTY: i32 = 10;
+const ISOLATED_PAWN_PENALTY: i32 = 15;
+// bonus for a passed pawn, indexed by its rank relative to its owner
+const PASSED_PAWN_BONUS: [i32; 8] = [0, 5, 10, 20, 35, 60, 100, 0];
+
+// king safety weights
+const PAWN_SHIELD_BONUS: i32 = 10;
+const KING_ZONE_ATTACK_PENALTY: i32 = 8;
+
+// mobility weights, per square a piece attacks that isn't occupied by its own side
+const KNIGHT_MOBILITY: i32 = 4;
+const BISHOP_MOBILITY: i32 = 4;
+const ROOK_MOBILITY: i32 = 2;
+const QUEEN_MOBILITY: i32 = 1;
+
+// threat weights. A piece attacked by a less valuable piece, or attacked and not defended at all, is
+// likely to be lost, and a pawn push that attacks a piece wins tempo. Indexed by the attacked piece,
+// in the order of `PARAM_PIECES`
+const THREAT_BY_LESSER_PIECE: [i32; 6] = [0, 30, 30, 50, 80, 0];
+const HANGING_PIECE: [i32; 6] = [10, 40, 40, 40, 40, 0];
+const PAWN_PUSH_THREAT: i32 = 20;
+// the side to move can act on its threats before the other side can, so the threats of the side that
+// has just moved count for this percentage of their weight
+const WAITING_THREAT_PERCENT: i32 = 50;
+
+// opening weights, scaled down as pieces come off so they have faded out by the endgame
+const UNDEVELOPED_MINOR_PENALTY: i32 = 15;
+// for moving the queen before at least this many minor pieces are developed
+const EARLY_QUEEN_PENALTY: i32 = 25;
+const EARLY_QUEEN_MIN_DEVELOPED: u32 = 3;
+const CASTLED_BONUS: i32 = 40;
+const LOST_CASTLING_PENALTY: i32 = 40;
+// phase weights of the pieces, which add up to `OPENING_PHASE` in the starting position
+const KNIGHT_PHASE: i32 = 1;
+const BISHOP_PHASE: i32 = 1;
+const ROOK_PHASE: i32 = 2;
+const QUEEN_PHASE: i32 = 4;
+const OPENING_PHASE: i32 = 24;
+
+// material imbalance tables, for how much each piece's value changes with the other material on the
+// board. Rows and columns are the bishop pair (0 or 1), pawns, knights, bishops, rooks and queens.
+// A side gets `count[i] * (ours[i][j] * count[j] + theirs[i][j] * their_count[j])` summed over j <= i,
+// in centipawns. Knights like closed positions with lots of pawns and rooks like open ones, a second
+// rook or a rook next to a queen adds less than its value, minor pieces gain against rooks, and a
+// queen is worth less against rooks, which can double up against it, than against minor pieces
+const IMBALANCE_OURS: [[i32; 6]; 6] = [
+    [40, 0, 0, 0, 0, 0],
+    [0, 0, 0, 0, 0, 0],
+    [0, 2, -4, 0, 0, 0],
+    [0, 0, 0, 0, 0, 0],
+    [0, -3, 0, 0, -6, 0],
+    [0, 0, 0, 0, -10, 0],
+];
+const IMBALANCE_THEIRS: [[i32; 6]; 6] = [
+    [0, 0, 0, 0, 0, 0],
+    [0, 0, 0, 0, 0, 0],
+    [0, 0, 0, 0, 0, 0],
+    [0, 0, 0, 0, 0, 0],
+    [0, 0, -8, -8, 0, 0],
+    [0, 0, 4, 4, -8, 0],
+];
+
+/// The individual terms that make up the score returned by `evaluate`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EvalTerm {
+    Material,
+    PieceSquare,
+    PawnStructure,
+    KingSafety,
+    Mobility,
+    Check,
+    Threats,
+    Development,
+    Imbalance,
+    // the score given by RUST_BOT_EVAL_SCRIPT, counted for white
+    Script,
+}
+
+pub const NUM_EVAL_TERMS: usize = 10;
+
+impl EvalTerm {
+    pub const ALL: [EvalTerm; NUM_EVAL_TERMS] = [
+        EvalTerm::Material,
+        EvalTerm::PieceSquare,
+        EvalTerm::PawnStructure,
+        EvalTerm::KingSafety,
+        EvalTerm::Mobility,
+        EvalTerm::Check,
+        EvalTerm::Threats,
+        EvalTerm::Development,
+        EvalTerm::Imbalance,
+        EvalTerm::Script,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EvalTerm::Material => "material",
+            EvalTerm::PieceSquare => "piece_square",
+            EvalTerm::PawnStructure => "pawn_structure",
+            EvalTerm::KingSafety => "king_safety",
+            EvalTerm::Mobility => "mobility",
+            EvalTerm::Check => "check",
+            EvalTerm::Threats => "threats",
+            EvalTerm::Development => "development",
+            EvalTerm::Imbalance => "imbalance",
+            EvalTerm::Script => "script",
+        }
+    }
+}
+
+/// Per-term, per-side breakdown of an evaluation. Each side's score is from its own point of view,
+/// so a term contributes `white - black` to the final score
+#[derive(Clone, Default, Debug)]
+pub struct EvalTrace {
+    scores: [[i32; 2]; NUM_EVAL_TERMS],
+    // set when the position is checkmate or stalemate, in which case the terms are ignored
+    terminal_score: Option<i32>,
+    // the specialised endgame evaluation used, and how much it changed the score by
+    endgame: Option<Endgame>,
+    endgame_adjustment: i32,
+}
+
+impl EvalTrace {
+    fn add(&mut self, term: EvalTerm, player: Player, score: i32) {
+        self.scores[term as usize][player as usize] += score;
+    }
+
+    /// returns the score of a term for one side, from that side's point of view
+    pub fn side(&self, term: EvalTerm, player: Player) -> i32 {
+        self.scores[term as usize][player as usize]
+    }
+
+    /// returns the contribution of a term to the final score (positive is good for white)
+    pub fn term(&self, term: EvalTerm) -> i32 {
+        self.side(term, Player::White) - self.side(term, Player::Black)
+    }
+
+    /// returns how much the specialised endgame evaluation changed the score by
+    pub fn endgame_adjustment(&self) -> i32 {
+        self.endgame_adjustment
+    }
+
+    /// returns the final score, matching what `evaluate` returns for the same position
+    pub fn total(&self) -> i32 {
+        match self.terminal_score {
+            Some(score) => score,
+            None => EvalTerm::ALL.iter().map(|&term| self.term(term)).sum::<i32>() + self.endgame_adjustment,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let terms: Vec<Value> = EvalTerm::ALL.iter().map(|&term| json!({
+            "term": term.name(),
+            "white": self.side(term, Player::White),
+            "black": self.side(term, Player::Black),
+            "total": self.term(term),
+        })).collect();
+
+        let endgame = self.endgame.map(|endgame| json!({
+            "name": endgame.name(),
+            "adjustment": self.endgame_adjustment,
+        }));
+
+        json!({
+            "terms": terms,
+            "endgame": endgame,
+            "terminal": self.terminal_score.is_some(),
+            "total": self.total(),
+        })
+    }
+}
+
+impl fmt::Display for EvalTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<16}{:>10}{:>10}{:>10}", "term", "white", "black", "total")?;
+        for term in EvalTerm::ALL {
+            writeln!(f, "{:<16}{:>10}{:>10}{:>10}", term.name(), self.side(term, Player::White), self.side(term, Player::Black), self.term(term))?;
+        }
+        if let Some(endgame) = self.endgame {
+            writeln!(f, "{:<16}{:>30}", format!("endgame ({})", endgame.name()), self.endgame_adjustment)?;
+        }
+        if self.terminal_score.is_some() {
+            writeln!(f, "(position is checkmate or stalemate, terms are ignored)")?;
+        }
+        write!(f, "{:<16}{:>30}", "total", self.total())
+    }
+}
+
+/// Something that can score positions during a search. Evaluators are told about every move the
+/// search makes and takes back, so they can keep their own state up to date incrementally
+pub trait Evaluator {
+    // called with the board as it was before `mv` is applied to it
+    fn make_move(&mut self, board: &Board, mv: BitMove);
+    fn unmake_move(&mut self);
+    // returns the score of the board, positive is good for white
+    fn evaluate(&mut self, board: &Board) -> i32;
+}
+
+/// The hand-written evaluation below. The material and score grid terms are kept up to date as
+/// moves are made and taken back, and the pawn structure is cached by the position of the pawns,
+/// which most moves don't change. The other terms are worked out from the whole board at each leaf
+pub struct HandcraftedEvaluator {
+    // material and score grid total (positive is good for white) after each move the search has made
+    material_scores: Vec<i32>,
+    // pawn structure scores of [white, black] by pawn key, in the slot the key picks
+    pawn_cache: Vec<Option<(u64, [i32; 2])>>,
+}
+
+// number of pawn structures the evaluator remembers
+const PAWN_CACHE_SIZE: usize = 4096;
+
+impl HandcraftedEvaluator {
+    pub fn new(board: &Board) -> HandcraftedEvaluator {
+        let mut trace = EvalTrace::default();
+        add_material_terms(board, params(), &mut trace);
+        let material_score = trace.term(EvalTerm::Material) + trace.term(EvalTerm::PieceSquare);
+
+        HandcraftedEvaluator { material_scores: vec![material_score], pawn_cache: vec![None; PAWN_CACHE_SIZE] }
+    }
+
+    fn pawn_scores(&mut self, board: &Board) -> [i32; 2] {
+        let key = board.pawn_key();
+        let slot = &mut self.pawn_cache[key as usize % PAWN_CACHE_SIZE];
+        match *slot {
+            Some((cached_key, scores)) if cached_key == key => scores,
+            _ => {
+                let scores = pawn_structure_scores(board);
+                *slot = Some((key, scores));
+                scores
+            }
+        }
+    }
+}
+
+impl Evaluator for HandcraftedEvaluator {
+    fn make_move(&mut self, board: &Board, mv: BitMove) {
+        let params = params();
+        let mut material_score = *self.material_scores.last().unwrap();
+
+        for change in piece_changes(board, mv) {
+            let Some((player, piece)) = change.piece.player_piece() else {
+                continue;
+            };
+            let mut score = personality().scale_material(params.piece_value(piece)) + get_piece_square_score(params, piece, change.square, player);
+            if player == Player::Black {
+                score = -score;
+            }
+
+            if change.added {
+                material_score += score;
+            }
+            else {
+                material_score -= score;
+            }
+        }
+
+        self.material_scores.push(material_score);
+    }
+
+    fn unmake_move(&mut self) {
+        self.material_scores.pop();
+    }
+
+    fn evaluate(&mut self, board: &Board) -> i32 {
+        if let Some(score) = terminal_score(board) {
+            return score;
+        }
+
+        let pawn_scores = self.pawn_scores(board);
+        let mut trace = EvalTrace::default();
+        add_positional_terms(board, params(), pawn_scores, &mut trace);
+        let score = apply_endgame(board, *self.material_scores.last().unwrap() + trace.total(), params());
+
+        // in debug builds, check the incremental scores against evaluating the board from scratch
+        debug_assert_eq!(score, evaluate(board), "incremental evaluation is out of sync for {}", board.fen());
+        score
+    }
+}
+
+/// Scores positions by material alone. The blunder check uses it, because it looks for moves that
+/// lose material rather than for positional differences, and it is fast enough to search deeper
+pub struct MaterialEvaluator {
+    // material total (positive is good for white) after each move the search has made
+    material_scores: Vec<i32>,
+}
+
+impl MaterialEvaluator {
+    pub fn new(board: &Board) -> MaterialEvaluator {
+        let mut trace = EvalTrace::default();
+        add_material_terms(board, params(), &mut trace);
+        MaterialEvaluator { material_scores: vec![trace.term(EvalTerm::Material)] }
+    }
+}
+
+impl Evaluator for MaterialEvaluator {
+    fn make_move(&mut self, board: &Board, mv: BitMove) {
+        let mut material_score = *self.material_scores.last().unwrap();
+
+        for change in piece_changes(board, mv) {
+            let Some((player, piece)) = change.piece.player_piece() else {
+                continue;
+            };
+            let score = personality().scale_material(params().piece_value(piece));
+            let sign = if player == Player::White { 1 } else { -1 };
+            material_score += if change.added { sign * score } else { -sign * score };
+        }
+
+        self.material_scores.push(material_score);
+    }
+
+    fn unmake_move(&mut self) {
+        self.material_scores.pop();
+    }
+
+    fn evaluate(&mut self, board: &Board) -> i32 {
+        terminal_score(board).unwrap_or(*self.material_scores.last().unwrap())
+    }
+}
+
+// returns the evaluator selected by RUST_BOT_EVALUATOR, set up for the given starting position
+pub fn create_evaluator(board: &Board) -> Box<dyn Evaluator> {
+    match nnue::network() {
+        Some(network) => Box::new(nnue::NnueEvaluator::new(network, board)),
+        None => Box::new(HandcraftedEvaluator::new(board)),
+    }
+}
+
+/// A piece being put on or taken off a square by a move
+#[derive(Clone, Copy, Debug)]
+pub struct PieceChange {
+    pub piece: Piece,
+    pub square: SQ,
+    pub added: bool,
+}
+
+// returns the pieces a move takes off and puts on the board, given the board before the move is
+// applied. Castling is encoded by pleco as the king capturing its own rook
+pub fn piece_changes(board: &Board, mv: BitMove) -> Vec<PieceChange> {
+    let us = board.turn();
+    let from = mv.get_src();
+    let to = mv.get_dest();
+    let piece = board.piece_at_sq(from);
+    let removed = |piece, square| PieceChange { piece, square, added: false };
+    let added = |piece, square| PieceChange { piece, square, added: true };
+
+    if mv.is_castle() {
+        let rook = Piece::make_lossy(us, PieceType::R);
+        let (king_to, rook_to) = if from < to {
+            (us.relative_square(SQ::G1), us.relative_square(SQ::F1))
+        }
+        else {
+            (us.relative_square(SQ::C1), us.relative_square(SQ::D1))
+        };
+        return vec![removed(piece, from), removed(rook, to), added(piece, king_to), added(rook, rook_to)];
+    }
+
+    let mut changes = Vec::with_capacity(3);
+    if mv.is_en_passant() {
+        let captured_sq = SQ((to.0 as i8 - us.pawn_push()) as u8);
+        changes.push(removed(board.piece_at_sq(captured_sq), captured_sq));
+    }
+    else if board.piece_at_sq(to) != Piece::None {
+        changes.push(removed(board.piece_at_sq(to), to));
+    }
+
+    changes.push(removed(piece, from));
+    if mv.is_promo() {
+        changes.push(added(Piece::make_lossy(us, mv.promo_piece()), to));
+    }
+    else {
+        changes.push(added(piece, to));
+    }
+
+    changes
+}
+
+//takes in a board and returns its score
+pub fn evaluate(board: &Board) -> i32 {
+    evaluate_with(board, params())
+}
+
+//takes in a board and returns its score using the given weights instead of the engine's own
+pub fn evaluate_with(board: &Board, params: &EvalParams) -> i32 {
+    if let Some(score) = terminal_score(board) {
+        return score;
+    }
+
+    let mut trace = EvalTrace::default();
+    add_terms(board, params, &mut trace);
+    apply_endgame(board, trace.total(), params)
+}
+
+//takes in a board and returns the breakdown of its score, term by term
+pub fn trace_evaluation(board: &Board) -> EvalTrace {
+    let mut trace = EvalTrace {
+        terminal_score: terminal_score(board),
+        ..Default::default()
+    };
+    add_terms(board, params(), &mut trace);
+
+    if let Some(endgame) = endgame::recognise(board, params()) {
+        let score = trace.total();
+        trace.endgame = Some(endgame);
+        trace.endgame_adjustment = evaluate_endgame(board, endgame, score, params()) - score;
+    }
+    trace
+}
+
+// applies the specialised evaluation of the endgame on the board, if there is one, to its normal score
+fn apply_endgame(board: &Board, score: i32, params: &EvalParams) -> i32 {
+    match endgame::recognise(board, params) {
+        Some(endgame) => evaluate_endgame(board, endgame, score, params),
+        None => score,
+    }
+}
+
+// returns true if the game is drawn by stalemate or the fifty move rule. pleco's own `stalemate`
+// counts 50 plies without a capture or pawn move as a draw, but the rule is 50 moves by each side
+pub fn is_draw(board: &Board) -> bool {
+    !board.in_check() && (board.rule_50() >= 100 || board.generate_moves().is_empty())
+}
+
+// returns the score of a checkmate or stalemate, or None if the game isn't over
+fn terminal_score(board: &Board) -> Option<i32> {
+    if is_draw(board) {
+        Some(0)
+    }
+    else if board.checkmate() {
+        if board.turn() == Player::White {
+            Some(-CHECKMATE_SCORE)
+        }
+        else {
+            Some(CHECKMATE_SCORE)
+        }
+    }
+    else {
+        None
+    }
+}
+
+fn add_terms(board: &Board, params: &EvalParams, trace: &mut EvalTrace) {
+    add_material_terms(board, params, trace);
+    add_positional_terms(board, params, pawn_structure_scores(board), trace);
+}
+
+// adds the value of each piece and its score grid value
+fn add_material_terms(board: &Board, params: &EvalParams, trace: &mut EvalTrace) {
+    for (sq, piece) in board.get_piece_locations() {
+        let player = match piece.player() {
+            Some(x) => x,
+            None => Player::White
+        };
+
+        trace.add(EvalTerm::Material, player, personality().scale_material(params.piece_value(piece.type_of())));
+        trace.add(EvalTerm::PieceSquare, player, get_piece_square_score(params, piece.type_of(), sq, player));
+    }
+}
+
+// adds the terms that depend on how the pieces interact, which can't be updated incrementally. The
+// pawn structure scores of both sides are passed in, as they only change when the pawns do
+fn add_positional_terms(board: &Board, params: &EvalParams, pawn_scores: [i32; 2], trace: &mut EvalTrace) {
+    for player in [Player::White, Player::Black] {
+        trace.add(EvalTerm::PawnStructure, player, pawn_scores[player as usize]);
+        trace.add(EvalTerm::KingSafety, player, king_safety_score(board, player));
+        trace.add(EvalTerm::Mobility, player, mobility_score(board, player));
+        trace.add(EvalTerm::Threats, player, threat_score(board, player, params));
+        trace.add(EvalTerm::Development, player, development_score(board, player));
+        trace.add(EvalTerm::Imbalance, player, imbalance_score(board, player));
+    }
+
+    if board.in_check() {
+        trace.add(EvalTerm::Check, board.turn(), -IN_CHECK_PENALTY);
+    }
+
+    if let Some(score) = scripting::script_score(board) {
+        trace.add(EvalTerm::Script, Player::White, score);
+    }
+}
+
+//gets the score grid value of a piece at a specific square, from the point of view of its owner
+fn get_piece_square_score(params: &EvalParams, piece: PieceType, square: SQ, player: Player) -> i32 {
+    let (row, col) = score_grid_cell(square, player);
+    params.score_grid(piece)[row][col]
+}
+
+//returns the row and column of the score grid used for a piece of the given player on a square.
+//grids are laid out as white sees the board, with the a file on the left, so only the ranks are
+//flipped for white. Flipping the files as well would make the evaluation favour one colour
+pub fn score_grid_cell(square: SQ, player: Player) -> (usize, usize) {
+    let mut index = square_to_int(square);
+
+    if player == Player::White {
+        index ^= 56;
+    }
+
+    (index / 8, index % 8)
+}
+
+// returns the pawn structure score of [white, black], scaled by the personality
+fn pawn_structure_scores(board: &Board) -> [i32; 2] {
+    [Player::White, Player::Black].map(|player| personality().scale_pawn_structure(pawn_structure_score(board, player)))
+}
+
+// penalises doubled and isolated pawns, and rewards passed pawns the further they have advanced
+fn pawn_structure_score(board: &Board, player: Player) -> i32 {
+    let pawns = board.piece_bb(player, PieceType::P);
+    let mut score = 0;
+
+    for sq in pawns {
+        let file = sq.file();
+        let pawns_on_file = pawns & file.bb();
+
+        // only count each doubled file once, on its front-most pawn
+        if pawns_on_file.more_than_one() && pawns_on_file.frontmost_sq(player) == sq {
+            score -= DOUBLED_PAWN_PENALTY * (pawns_on_file.count_bits() as i32 - 1);
+        }
+
+        if (pawns & adjacent_files(file)).is_empty() {
+            score -= ISOLATED_PAWN_PENALTY;
+        }
+
+        if is_passed_pawn(board, player, sq) {
+            score += PASSED_PAWN_BONUS[player.relative_rank_of_sq(sq) as usize];
+        }
+    }
+
+    score
+}
+
+// returns the files either side of the given file. pleco's own `adjacent_file` table is never
+// initialised, so it can't be used
+fn adjacent_files(file: File) -> BitBoard {
+    let file_mask = file.bb().0;
+    let left = (file_mask >> 1) & !File::H.bb().0;
+    let right = (file_mask << 1) & !File::A.bb().0;
+    BitBoard(left | right)
+}
+
+// a pawn is passed if no enemy pawn is in front of it on its own or an adjacent file
+fn is_passed_pawn(board: &Board, player: Player, sq: SQ) -> bool {
+    let files = sq.file().bb() | adjacent_files(sq.file());
+    let in_front = forward_rank_bb(player, sq.rank());
+    (board.piece_bb(!player, PieceType::P) & files & in_front).is_empty()
+}
+
+// rewards pawns sheltering the king and penalises enemy attacks on the squares around it
+fn king_safety_score(board: &Board, player: Player) -> i32 {
+    let king_sq = board.king_sq(player);
+    let king_rank = player.relative_rank_of_sq(king_sq) as i32;
+    let mut score = 0;
+
+    for pawn_sq in board.piece_bb(player, PieceType::P) {
+        let rank_diff = player.relative_rank_of_sq(pawn_sq) as i32 - king_rank;
+
+        if pawn_sq.file().distance(king_sq.file()) <= 1 && (1..=2).contains(&rank_diff) {
+            score += PAWN_SHIELD_BONUS;
+        }
+    }
+
+    let enemy_pieces = board.get_occupied_player(!player);
+    let occupied = board.occupied();
+
+    for sq in king_moves(king_sq) | king_sq.to_bb() {
+        let attackers = board.attackers_to(sq, occupied) & enemy_pieces;
+        score -= personality().scale_king_attack(KING_ZONE_ATTACK_PENALTY * attackers.count_bits() as i32);
+    }
+
+    score
+}
+
+// rewards pieces for the number of squares they attack that aren't occupied by their own side
+fn mobility_score(board: &Board, player: Player) -> i32 {
+    let own_pieces = board.get_occupied_player(player);
+    let mut score = 0;
+
+    for (piece, weight) in [(PieceType::N, KNIGHT_MOBILITY), (PieceType::B, BISHOP_MOBILITY), (PieceType::R, ROOK_MOBILITY), (PieceType::Q, QUEEN_MOBILITY)] {
+        for sq in board.piece_bb(player, piece) {
+            let moves: BitBoard = board.attacks_from(piece, sq, player) & !own_pieces;
+            score += weight * moves.count_bits() as i32;
+        }
+    }
+
+    score
+}
+
+// rewards a side for the enemy pieces it threatens: ones attacked by a less valuable piece, ones that
+// are attacked and undefended, and ones a safe pawn push would attack. The side to move's threats count
+// in full, as it can carry them out, while the other side's can still be answered
+fn threat_score(board: &Board, player: Player, params: &EvalParams) -> i32 {
+    let occupied = board.occupied();
+    let own_pieces = board.get_occupied_player(player);
+    let enemy_pieces = board.get_occupied_player(!player);
+    let enemy_targets = enemy_pieces & !board.piece_bb(!player, PieceType::K);
+    let mut score = 0;
+
+    for sq in enemy_targets {
+        let victim = board.piece_at_sq(sq).type_of();
+        let victim_index = PARAM_PIECES.iter().position(|&p| p == victim).unwrap();
+        let attackers_to = board.attackers_to(sq, occupied);
+        let attackers = attackers_to & own_pieces;
+        if attackers.is_empty() {
+            continue;
+        }
+
+        let least_valuable = attackers.into_iter().map(|attacker| params.piece_value(board.piece_at_sq(attacker).type_of())).min().unwrap();
+        if least_valuable < params.piece_value(victim) {
+            score += THREAT_BY_LESSER_PIECE[victim_index];
+        }
+        if (attackers_to & enemy_pieces).is_empty() {
+            score += HANGING_PIECE[victim_index];
+        }
+    }
+
+    // pushes onto empty squares that enemy pawns don't attack, which would attack a piece
+    let enemy_pawn_attacks = board.piece_bb(!player, PieceType::P).into_iter().fold(BitBoard(0), |attacks, sq| attacks | pawn_attacks_from(sq, !player));
+    let enemy_minors_and_majors = enemy_targets & !board.piece_bb(!player, PieceType::P);
+    for sq in board.piece_bb(player, PieceType::P) {
+        let push = SQ((sq.0 as i8 + player.pawn_push()) as u8);
+        if (occupied & push.to_bb()).is_not_empty() || (enemy_pawn_attacks & push.to_bb()).is_not_empty() || player.relative_rank_of_sq(push) == Rank::R8 {
+            continue;
+        }
+        score += PAWN_PUSH_THREAT * (pawn_attacks_from(push, player) & enemy_minors_and_majors).count_bits() as i32;
+    }
+
+    if board.turn() == player {
+        score
+    } else {
+        score * WAITING_THREAT_PERCENT / 100
+    }
+}
+
+// returns how far the game is from the endgame, from `OPENING_PHASE` with all the pieces on the board
+// down to 0 with only kings and pawns left
+pub fn game_phase(board: &Board) -> i32 {
+    let phase: i32 = [(PieceType::N, KNIGHT_PHASE), (PieceType::B, BISHOP_PHASE), (PieceType::R, ROOK_PHASE), (PieceType::Q, QUEEN_PHASE)]
+        .iter()
+        .map(|&(piece, weight)| weight * (board.count_piece(Player::White, piece) + board.count_piece(Player::Black, piece)) as i32)
+        .sum();
+
+    phase.min(OPENING_PHASE)
+}
+
+// penalises minor pieces left on their starting squares, moving the queen out before the minor pieces
+// and losing the right to castle, and rewards having castled. Fades out as material comes off
+fn development_score(board: &Board, player: Player) -> i32 {
+    let start_squares = [(PieceType::N, SQ::B1), (PieceType::N, SQ::G1), (PieceType::B, SQ::C1), (PieceType::B, SQ::F1)];
+    let undeveloped = start_squares.iter()
+        .filter(|&&(piece, sq)| board.piece_at_sq(player.relative_square(sq)) == Piece::make_lossy(player, piece))
+        .count() as u32;
+    let mut score = -UNDEVELOPED_MINOR_PENALTY * undeveloped as i32;
+
+    let queen_at_home = board.piece_at_sq(player.relative_square(SQ::D1)) == Piece::make_lossy(player, PieceType::Q);
+    if !queen_at_home && board.count_piece(player, PieceType::Q) > 0 && 4 - undeveloped < EARLY_QUEEN_MIN_DEVELOPED {
+        score -= EARLY_QUEEN_PENALTY;
+    }
+
+    // pleco doesn't record whether a side has castled, so a king tucked into a corner of its back rank
+    // counts as castled
+    let king_sq = board.king_sq(player);
+    let castled = player.relative_rank_of_sq(king_sq) == Rank::R1 && [File::A, File::B, File::C, File::G, File::H].contains(&king_sq.file());
+    if castled {
+        score += CASTLED_BONUS;
+    }
+    else if board.player_can_castle(player).bits() == 0 {
+        score -= LOST_CASTLING_PENALTY;
+    }
+
+    score * game_phase(board) / OPENING_PHASE
+}
+
+// adjusts the piece values for the other material on the board, using the imbalance tables
+fn imbalance_score(board: &Board, player: Player) -> i32 {
+    let counts = |player: Player| {
+        let count = |piece| board.count_piece(player, piece) as i32;
+        [(count(PieceType::B) > 1) as i32, count(PieceType::P), count(PieceType::N), count(PieceType::B), count(PieceType::R), count(PieceType::Q)]
+    };
+    let ours = counts(player);
+    let theirs = counts(!player);
+    let mut score = 0;
+
+    for i in 0..6 {
+        if ours[i] == 0 {
+            continue;
+        }
+        let value: i32 = (0..=i).map(|j| IMBALANCE_OURS[i][j] * ours[j] + IMBALANCE_THEIRS[i][j] * theirs[j]).sum();
+        score += ours[i] * value;
+    }
+
+    score
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_adds_up_to_the_evaluation() {
+        let fens = [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "r1bq1rk1/pp2bppp/2n1pn2/3p4/2PP4/2N1PN2/PP3PPP/R2QKB1R w KQ - 2 9",
+            // doubled, isolated and passed pawns
+            "4k3/p1p5/8/3P4/8/1P6/1P3PP1/4K3 b - - 0 30",
+            // in check
+            "rnb1kbnr/pppp1ppp/8/4p3/5PPq/8/PPPPP2P/RNBQKBNR w KQkq - 1 3",
+            // checkmate and stalemate
+            "rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3",
+            "7k/5Q2/6K1/8/8/8/8/8 b - - 0 1",
+            // endgames with their own evaluation
+            "8/8/3P4/8/8/8/8/4K2k w - - 0 1",
+            "8/8/8/8/8/2k5/8/KBN5 w - - 0 1",
+        ];
+
+        for fen in fens {
+            let board = Board::from_fen(fen).unwrap();
+            assert_eq!(trace_evaluation(&board).total(), evaluate(&board), "{}", fen);
+        }
+    }
+
+    #[test]
+    fn threats_of_the_side_to_move_count_for_more() {
+        // the knight attacks the undefended rook, which is worth more
+        let white_to_move = Board::from_fen("4k3/8/8/3r4/8/4N3/8/4K3 w - - 0 1").unwrap();
+        let black_to_move = Board::from_fen("4k3/8/8/3r4/8/4N3/8/4K3 b - - 0 1").unwrap();
+        let params = EvalParams::default();
+
+        let threats = THREAT_BY_LESSER_PIECE[3] + HANGING_PIECE[3];
+        assert_eq!(threat_score(&white_to_move, Player::White, &params), threats);
+        assert_eq!(threat_score(&black_to_move, Player::White, &params), threats * WAITING_THREAT_PERCENT / 100);
+        assert_eq!(threat_score(&white_to_move, Player::Black, &params), 0);
+    }
+
+    #[test]
+    fn threats_use_the_given_piece_values() {
+        let board = Board::from_fen("4k3/8/8/3r4/8/4N3/8/4K3 w - - 0 1").unwrap();
+        let mut params = EvalParams::default();
+        params.piece_values[1] = params.piece_values[3] + 100;
+
+        // with the knight worth more than the rook, the rook is only hanging
+        assert_eq!(threat_score(&board, Player::White, &params), HANGING_PIECE[3]);
+    }
+
+    #[test]
+    fn a_queen_is_worth_less_against_rooks() {
+        let imbalance = |fen: &str, player: Player| imbalance_score(&Board::from_fen(fen).unwrap(), player);
+
+        // the queen's side loses out as the other side gets rooks, and gains against minor pieces
+        let against_nothing = imbalance("3qk3/8/8/8/8/8/8/4K3 w - - 0 1", Player::Black);
+        let against_rooks = imbalance("3qk3/8/8/8/8/8/8/R3K2R w - - 0 1", Player::Black);
+        let against_minors = imbalance("3qk3/8/8/8/8/8/8/1N2KB2 w - - 0 1", Player::Black);
+        assert!(against_rooks < against_nothing);
+        assert!(against_minors > against_nothing);
+    }
+
+    #[test]
+    fn handcrafted_evaluator_matches_evaluating_from_scratch() {
+        let mut board = Board::start_pos();
+        let mut evaluator = HandcraftedEvaluator::new(&board);
+        let mut state: u64 = 0x5eed;
+
+        for ply in 0..150 {
+            let moves = board.generate_moves();
+            if moves.is_empty() {
+                break;
+            }
+            // take moves back now and then, so pawn structures come up again and are read from the cache
+            if ply % 5 == 4 {
+                board.undo_move();
+                evaluator.unmake_move();
+            } else {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let mv = moves[(state % moves.len() as u64) as usize];
+                evaluator.make_move(&board, mv);
+                board.apply_move(mv);
+            }
+            assert_eq!(evaluator.evaluate(&board), evaluate(&board), "{}", board.fen());
+        }
+    }
+}
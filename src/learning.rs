@@ -0,0 +1,241 @@
+// Learns from the bot's own finished games. For each opening position the bot played a move in, the
+// learning file keeps how many games it won, drew and lost after that move, and book selection uses
+// this to play lines that went well more often and lines that lost less often. The file named by
+// RUST_BOT_LEARNING_FILE is read at startup and written after each batch of new games, so it needs
+// to be somewhere that lasts between invocations.
+use crate::book::{polyglot_key, settings};
+use crate::pgn::{parse_games, GameResult, PgnGame};
+
+use pleco::{BitMove, Board, Player};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::sync::{Mutex, OnceLock};
+
+static LEARNING: OnceLock<Option<Learning>> = OnceLock::new();
+
+struct Learning {
+    path: String,
+    book: Mutex<LearnedBook>,
+}
+
+/// The bot's results after a move
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MoveRecord {
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+}
+
+impl MoveRecord {
+    pub fn games(&self) -> u32 {
+        self.wins + self.draws + self.losses
+    }
+
+    /// returns the bot's expected score with the move, from 0 to 1. One extra win and one extra loss
+    /// are counted so a single game doesn't decide it, which makes a move without games score 0.5
+    pub fn score(&self) -> f64 {
+        (self.wins as f64 + self.draws as f64 / 2.0 + 1.0) / (self.games() as f64 + 2.0)
+    }
+}
+
+/// What the bot has learned from its games, in the format the learning file is stored in
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LearnedBook {
+    // ids of the games already learned from, so each game is only counted once
+    games: HashSet<String>,
+    // the bot's records by the Polyglot key of the position, in hex, and the move in UCI format
+    positions: HashMap<String, HashMap<String, MoveRecord>>,
+}
+
+impl LearnedBook {
+    // a missing file is an empty book, so learning can start from nothing
+    pub fn load(path: &str) -> Result<LearnedBook, String> {
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(|e| format!("Invalid learning file {}: {}", path, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(LearnedBook::default()),
+            Err(e) => Err(format!("Could not read learning file {}: {}", path, e)),
+        }
+    }
+
+    // writes to a temporary file first, so the learning file isn't left half written
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let temp_path = format!("{}.tmp", path);
+        fs::write(&temp_path, serde_json::to_string(self).unwrap()).map_err(|e| format!("Could not write learning file {}: {}", temp_path, e))?;
+        fs::rename(&temp_path, path).map_err(|e| format!("Could not replace learning file {}: {}", path, e))
+    }
+
+    /// returns the bot's records for the legal moves it has played in a position
+    pub fn moves(&self, board: &Board) -> Vec<(BitMove, MoveRecord)> {
+        let Some(records) = self.positions.get(&format!("{:016x}", polyglot_key(board))) else {
+            return Vec::new();
+        };
+        board.generate_moves().iter()
+            .filter_map(|&mv| records.get(&mv.to_string()).map(|&record| (mv, record)))
+            .collect()
+    }
+
+    // adds the result of a game to the bot's moves in its first `depth` plies. Returns whether the
+    // game was learned from, which it isn't if it was already or can't be replayed
+    fn learn(&mut self, id: &str, game: &PgnGame, bot: Player, depth: u16) -> Result<bool, String> {
+        if self.games.contains(id) {
+            return Ok(false);
+        }
+
+        let mut board = game.start_position()?;
+        let result = game.result();
+        for mv in game.play()? {
+            if board.moves_played() >= depth {
+                break;
+            }
+            if board.turn() == bot {
+                let records = self.positions.entry(format!("{:016x}", polyglot_key(&board))).or_default();
+                let record = records.entry(mv.to_string()).or_default();
+                match (result, bot) {
+                    (GameResult::Draw, _) => record.draws += 1,
+                    (GameResult::WhiteWin, Player::White) | (GameResult::BlackWin, Player::Black) => record.wins += 1,
+                    _ => record.losses += 1,
+                }
+            }
+            board.apply_move(mv);
+        }
+
+        self.games.insert(id.to_string());
+        Ok(true)
+    }
+}
+
+/// reads the learning file named by RUST_BOT_LEARNING_FILE, if it is set. Should be called once at
+/// startup
+pub fn init_learning_from_env() -> Result<(), String> {
+    let learning = match env::var("RUST_BOT_LEARNING_FILE") {
+        Ok(path) if !path.is_empty() => Some(Learning { book: Mutex::new(LearnedBook::load(&path)?), path }),
+        _ => None,
+    };
+
+    LEARNING.set(learning).map_err(|_| "Learning has already been initialised".to_string())
+}
+
+pub fn is_enabled() -> bool {
+    LEARNING.get().is_some_and(Option::is_some)
+}
+
+/// returns the bot's records for the moves it has played in a position, if learning is on
+pub fn learned_moves(board: &Board) -> Vec<(BitMove, MoveRecord)> {
+    match LEARNING.get().and_then(Option::as_ref) {
+        Some(learning) => learning.book.lock().unwrap().moves(board),
+        None => Vec::new(),
+    }
+}
+
+/// learns from the finished games in a PGN export that the bot named `bot_name` played and hasn't
+/// learned from yet, and saves the learning file if there were any. Games are identified by their
+/// Site tag, which is the game's URL on Lichess. Returns how many games were learned from
+pub fn learn_from_pgn(pgn: &str, bot_name: &str) -> Result<usize, String> {
+    let Some(learning) = LEARNING.get().and_then(Option::as_ref) else {
+        return Err("Learning is off, set RUST_BOT_LEARNING_FILE to the file to keep what is learned in".to_string());
+    };
+    let mut book = learning.book.lock().unwrap();
+    let depth = settings().max_depth;
+    let (mut learned, mut skipped) = (0, 0);
+
+    for game in parse_games(pgn) {
+        // games in progress and aborted games have no result to learn from
+        if game.result() == GameResult::Unfinished || game.tag("Variant").is_some_and(|variant| !variant.eq_ignore_ascii_case("standard")) {
+            continue;
+        }
+        let Some(id) = game.tag("Site").or(game.tag("GameId")).map(str::to_string) else {
+            continue;
+        };
+        let bot = if game.tag("White").is_some_and(|name| name.eq_ignore_ascii_case(bot_name)) {
+            Player::White
+        } else if game.tag("Black").is_some_and(|name| name.eq_ignore_ascii_case(bot_name)) {
+            Player::Black
+        } else {
+            continue;
+        };
+
+        match book.learn(&id, &game, bot, depth) {
+            Ok(true) => learned += 1,
+            Ok(false) => {}
+            Err(e) => {
+                // remembered anyway, so it isn't tried again every time
+                println!("could not learn from {}: {}", id, e);
+                book.games.insert(id);
+                skipped += 1;
+            }
+        }
+    }
+
+    if learned + skipped > 0 {
+        book.save(&learning.path)?;
+    }
+    Ok(learned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAME: &str = r#"[Site "https://lichess.org/abcd1234"]
+[White "bot"]
+[Black "alice"]
+[Result "1-0"]
+
+1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 1-0
+"#;
+
+    fn play(board: &mut Board, uci_move: &str) {
+        let mv = board.generate_moves().iter().copied().find(|mv| mv.to_string() == uci_move).unwrap();
+        board.apply_move(mv);
+    }
+
+    fn records(book: &LearnedBook, board: &Board) -> Vec<(String, u32, u32, u32)> {
+        book.moves(board).iter().map(|(mv, record)| (mv.to_string(), record.wins, record.draws, record.losses)).collect()
+    }
+
+    #[test]
+    fn score_counts_an_extra_win_and_loss() {
+        assert_eq!(MoveRecord::default().score(), 0.5);
+        assert_eq!(MoveRecord { wins: 1, draws: 0, losses: 0 }.score(), 2.0 / 3.0);
+        assert_eq!(MoveRecord { wins: 1, draws: 0, losses: 1 }.score(), 0.5);
+        assert_eq!(MoveRecord { wins: 0, draws: 2, losses: 0 }.score(), 0.5);
+        assert_eq!(MoveRecord { wins: 0, draws: 0, losses: 3 }.score(), 0.2);
+    }
+
+    #[test]
+    fn learns_the_bots_moves_in_the_opening_once() {
+        let game = &parse_games(GAME)[0];
+        let mut book = LearnedBook::default();
+
+        assert_eq!(book.learn("abcd1234", game, Player::White, 4), Ok(true));
+        assert_eq!(book.learn("abcd1234", game, Player::White, 4), Ok(false));
+
+        let mut board = Board::start_pos();
+        assert_eq!(records(&book, &board), vec![("e2e4".to_string(), 1, 0, 0)]);
+        // the opponent's moves aren't learned
+        play(&mut board, "e2e4");
+        assert!(records(&book, &board).is_empty());
+        play(&mut board, "e7e5");
+        assert_eq!(records(&book, &board), vec![("g1f3".to_string(), 1, 0, 0)]);
+        // Bb5 is the fifth ply, past the depth
+        play(&mut board, "g1f3");
+        play(&mut board, "b8c6");
+        assert!(records(&book, &board).is_empty());
+    }
+
+    #[test]
+    fn a_loss_is_counted_for_the_losing_side() {
+        let game = &parse_games(GAME)[0];
+        let mut book = LearnedBook::default();
+
+        assert_eq!(book.learn("abcd1234", game, Player::Black, 4), Ok(true));
+
+        let mut board = Board::start_pos();
+        play(&mut board, "e2e4");
+        assert_eq!(records(&book, &board), vec![("e7e5".to_string(), 0, 0, 1)]);
+    }
+}
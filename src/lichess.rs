@@ -1,174 +1,401 @@
 use crate::minimax;
+use crate::learning;
+use crate::challenge_policy::policy;
+use crate::lichess_api::{LichessClient, LichessError};
 
-use minimax::calculate_next_move;
+use minimax::{calculate_next_move, blunder_check};
 use lambda_runtime::Error;
 use std::env;
-use std::time::Duration;
 use pleco::Board;
-use serde_json::Value;
 
 // finds all current games of the bot and makes a move in those where it is the bot's turn
-pub async fn make_moves_in_all_ongoing_games() -> Result<(), Error> {
-    let LICHESS_ACCESS_TOKEN = env::var("RUST_BOT_TOKEN").expect("You need to set a Lichess personal access token.");
-
-    let ongoing_games = get_ongoing_games().await?;
-    println!("ongoing_games: {}", ongoing_games);
-    let ongoing_games_object: Value = serde_json::from_str(&ongoing_games).unwrap();
-    let game_array_json = &ongoing_games_object["nowPlaying"];
-
-    if game_array_json == &Value::Null && game_array_json == &Value::Array(vec![]) {
-        println!("No ongoing games");
-        return Ok(());
-    }
+pub async fn make_moves_in_all_ongoing_games(client: &LichessClient) -> Result<(), Error> {
+    let ongoing_games = client.ongoing_games().await?;
+    println!("ongoing games: {}", ongoing_games.len());
 
     // loop through all current games and make a move in each
-    if let Some(games) = game_array_json.as_array() {
-        for game in games {
-            let game_id = &game["gameId"].as_str().unwrap();
-            let fen = &game["fen"].as_str().unwrap();
-            let is_my_turn = &game["isMyTurn"].as_bool().unwrap();
-
-            if *is_my_turn == true {
-                let board = Board::from_fen(fen).unwrap();
-                let next_move = calculate_next_move(board);
-                println!("making move: {}", next_move);
-
-                make_move(&game_id, &next_move).await?;
+    for game in ongoing_games {
+        if !game.is_my_turn {
+            continue;
+        }
+
+        // a game the bot can't read the position of is skipped, so the other games still get moves
+        let board = match Board::from_fen(&game.fen) {
+            Ok(board) => board,
+            Err(e) => {
+                println!("skipping game {} with invalid FEN {}: {:?}", game.game_id, game.fen, e);
+                continue;
             }
+        };
+        if board.generate_moves().is_empty() {
+            continue;
+        }
+
+        let next_move = calculate_next_move(board.clone());
+        // re-check the move deeper before sending it, in case it hangs material
+        let next_move = blunder_check(board, next_move);
+        println!("making move {} in {}", next_move, game.game_id);
+
+        // a move Lichess turns down, e.g. because the game just ended, doesn't stop the bot moving in
+        // the other games. A rate limit does, as every request would be turned down for a minute
+        match client.make_move(&game.game_id, &next_move.to_string()).await {
+            Err(LichessError::RateLimited) => return Err(LichessError::RateLimited.into()),
+            Err(e) => println!("could not make move in {}: {}", game.game_id, e),
+            Ok(()) => {}
         }
     }
 
     Ok(())
 }
 
-// calls API to make a move in the given game. Move should be a string in UCI format, e.g. "e2e4"
-async fn make_move(game_id: &str, move_to_play: &str) -> Result<String, Error> {
-    let LICHESS_ACCESS_TOKEN = env::var("RUST_BOT_TOKEN").expect("You need to set a Lichess personal access token.");
-    let URL = "https://lichess.org/api/bot/game/".to_owned() + game_id + "/move/" + move_to_play;
+// number of the bot's most recent games checked for results to learn from each time
+const GAMES_TO_LEARN_FROM: u32 = 30;
 
-    let client = reqwest::Client::new();
-    let response = client
-        .post(URL)
-        .header("Authorization", "Bearer ".to_owned() + LICHESS_ACCESS_TOKEN.as_str())
-        .timeout(Duration::from_secs(5))
-        .send()
-        .await?
-        .text()
-        .await?;
+// learns from the bot's recently finished games, if learning is on. Games already learned from are
+// skipped, so the same games can be fetched every time
+pub async fn learn_from_finished_games(client: &LichessClient) -> Result<(), Error> {
+    if !learning::is_enabled() {
+        return Ok(());
+    }
 
-    println!("response after making a move: {}", response);
+    let bot_name = env::var("RUST_BOT_NAME").map_err(|_| "You need to set the bot's Lichess username to learn from its games.")?;
+    let games = client.finished_games(&bot_name, GAMES_TO_LEARN_FROM).await?;
+    let learned = learning::learn_from_pgn(&games, &bot_name)?;
+    println!("learned from {} finished games", learned);
 
-    Ok(response)
+    Ok(())
 }
 
-// fetches all current challenges for the bot, and accepts all that are at least 1 day per turn. It declines the rest
-pub async fn respond_to_all_challenges() -> Result<(), Error> {
-    let LICHESS_ACCESS_TOKEN = env::var("RUST_BOT_TOKEN").expect("You need to set a Lichess personal access token.");
-
-    let current_challenges = list_current_challenges().await?;
-    let challenges_object: Value = serde_json::from_str(&current_challenges).unwrap();
-    
-    let challenges_received = &challenges_object["in"];
-    if challenges_received == &Value::Null && challenges_received == &Value::Array(vec![]) {
+// fetches all current challenges for the bot, and accepts those the challenge policy allows. It
+// declines the rest, telling the challenger why
+pub async fn respond_to_all_challenges(client: &LichessClient) -> Result<(), Error> {
+    let challenges = client.challenges().await?;
+    if challenges.received.is_empty() {
         println!("No challenges received");
         return Ok(());
     }
 
-    // loop through challenges_received and accept all that are at least 1 day per turn
-    if let Some(challenges) = challenges_received.as_array() {
-        for challenge in challenges {
-            let challenge_id = &challenge["id"].as_str().unwrap();
-            println!("challenge_id: {}", challenge_id);
+    // the number of games is only needed to keep to a maximum
+    let mut games_in_progress = match policy().max_games {
+        Some(_) => client.ongoing_games().await?.len(),
+        None => 0,
+    };
+
+    for challenge in challenges.received {
+        println!("challenge {}", challenge);
 
-            let days_per_turn = &challenge["timeControl"]["daysPerTurn"];
-            println!("days_per_turn: {}", days_per_turn);
-            
-            if days_per_turn == &Value::Null {
-                // decline challenge as only accepting games with at least 1 day per turn
-                println!("declining challenge");
-                decline_challenge(challenge_id).await?;
+        let response = match policy().decline_reason(&challenge, false, games_in_progress) {
+            None => {
+                println!("accepting challenge");
+                let response = client.accept_challenge(&challenge.id).await;
+                if response.is_ok() {
+                    games_in_progress += 1;
+                }
+                response
             }
-            else {
-                // accept challenge
-                println!("accepting challenge - need to re-enable");
-                accept_challenge(challenge_id).await?;
+            Some(reason) => {
+                println!("declining challenge: {}", reason.key());
+                client.decline_challenge(&challenge.id, reason).await
             }
+        };
+        // the challenge may have been cancelled in the meantime, which doesn't stop the others being
+        // answered. A rate limit does
+        match response {
+            Err(LichessError::RateLimited) => return Err(LichessError::RateLimited.into()),
+            Err(e) => println!("could not answer challenge {}: {}", challenge.id, e),
+            Ok(()) => {}
         }
     }
 
     Ok(())
 }
 
-// fetches all ongoing games for the bot
-async fn get_ongoing_games() -> Result<String, Error> {
-    let LICHESS_ACCESS_TOKEN = env::var("RUST_BOT_TOKEN").expect("You need to set a Lichess personal access token.");
-
-    let client = reqwest::Client::new();
-    let response = client
-        .get("https://lichess.org/api/account/playing")
-        .header("Authorization", "Bearer ".to_owned() + LICHESS_ACCESS_TOKEN.as_str())
-        .timeout(Duration::from_secs(5))
-        .send()
-        .await?
-        .text()
-        .await?;
-
-    Ok(response)
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lichess_api::{DeclineReason, Event, TimeControl};
+    use crate::mock_lichess::{MockLichess, CHALLENGES, NO_CHALLENGES, NO_GAMES, OK, ONGOING_GAMES, RATE_LIMITED, TOKEN};
 
-// accepts a challenge with the given challenge ID
-async fn accept_challenge(challenge_id: &str) -> Result<(), Error> {
-    let LICHESS_ACCESS_TOKEN = env::var("RUST_BOT_TOKEN").expect("You need to set a Lichess personal access token.");
-    let URL = "https://lichess.org/api/challenge/".to_owned() + challenge_id + "/accept";
-    println!("URL: {}", URL);
+    fn lichess_error(e: &Error) -> &LichessError {
+        e.downcast_ref::<LichessError>().unwrap_or_else(|| panic!("not a Lichess error: {}", e))
+    }
 
-    let client = reqwest::Client::new();
-    let response = client
-        .post(URL)
-        .header("Authorization", "Bearer ".to_owned() + LICHESS_ACCESS_TOKEN.as_str())
-        .timeout(Duration::from_secs(5))
-        .send()
-        .await?
-        .text()
-        .await?;
+    #[tokio::test]
+    async fn accepts_correspondence_challenges_and_declines_the_rest() {
+        let lichess = MockLichess::start();
+        lichess.respond("GET", "/api/challenge", 200, CHALLENGES)
+            .respond("POST", "/api/challenge/corresp1/accept", 200, OK)
+            .respond("POST", "/api/challenge/blitz001/decline", 200, OK)
+            .respond("POST", "/api/challenge/unlim001/decline", 200, OK);
 
-    println!("response: {}", response);
+        respond_to_all_challenges(&lichess.client()).await.unwrap();
 
-    Ok(())
-}
+        assert_eq!(lichess.request_lines(), [
+            "GET /api/challenge",
+            "POST /api/challenge/corresp1/accept",
+            "POST /api/challenge/blitz001/decline",
+            "POST /api/challenge/unlim001/decline",
+        ]);
+        let requests = lichess.requests();
+        for request in &requests {
+            assert_eq!(request.headers.get("authorization").map(String::as_str), Some(format!("Bearer {}", TOKEN).as_str()));
+        }
+        assert_eq!(requests[1].body, "");
+        // games with a clock are too fast for the Lambda function, and unlimited games aren't accepted
+        assert_eq!(requests[2].body, "reason=tooFast");
+        assert_eq!(requests[3].body, "reason=timeControl");
+        for request in &requests[2..] {
+            assert_eq!(request.headers.get("content-type").map(String::as_str), Some("application/x-www-form-urlencoded"));
+        }
+    }
 
-// declines a challenge with the given challenge ID
-async fn decline_challenge(challenge_id: &str) -> Result<(), Error> {
-    let LICHESS_ACCESS_TOKEN = env::var("RUST_BOT_TOKEN").expect("You need to set a Lichess personal access token.");
-    let URL = "https://lichess.org/api/challenge/".to_owned() + challenge_id + "/decline";
-    println!("URL: {}", URL);
-
-    let client = reqwest::Client::new();
-    let response = client
-        .post(URL)
-        .header("Authorization", "Bearer ".to_owned() + LICHESS_ACCESS_TOKEN.as_str())
-        .body("declineTooFast")
-        .timeout(Duration::from_secs(5))
-        .send()
-        .await?
-        .text()
-        .await?;
+    #[tokio::test]
+    async fn declines_variants_the_bot_cant_play() {
+        let lichess = MockLichess::start();
+        let challenges = CHALLENGES.replacen(r#""key": "standard""#, r#""key": "chess960""#, 1);
+        lichess.respond("GET", "/api/challenge", 200, &challenges)
+            .respond("POST", "/api/challenge/corresp1/decline", 200, OK)
+            .respond("POST", "/api/challenge/blitz001/decline", 200, OK)
+            .respond("POST", "/api/challenge/unlim001/decline", 200, OK);
 
-    Ok(())
-}
+        respond_to_all_challenges(&lichess.client()).await.unwrap();
+
+        let requests = lichess.requests();
+        assert_eq!(requests[1].path, "/api/challenge/corresp1/decline");
+        assert_eq!(requests[1].body, "reason=variant");
+    }
+
+    #[tokio::test]
+    async fn sends_each_decline_reason_as_a_form_field() {
+        let lichess = MockLichess::start();
+        lichess.respond("POST", "/api/challenge/abcd1234/decline", 200, OK);
+        let client = lichess.client();
+        let reasons = [
+            (DeclineReason::Generic, "generic"),
+            (DeclineReason::Later, "later"),
+            (DeclineReason::TooFast, "tooFast"),
+            (DeclineReason::TooSlow, "tooSlow"),
+            (DeclineReason::TimeControl, "timeControl"),
+            (DeclineReason::Rated, "rated"),
+            (DeclineReason::Casual, "casual"),
+            (DeclineReason::Variant, "variant"),
+            (DeclineReason::NoBot, "noBot"),
+            (DeclineReason::OnlyBot, "onlyBot"),
+        ];
+
+        for (reason, _) in reasons {
+            client.decline_challenge("abcd1234", reason).await.unwrap();
+        }
+
+        let bodies: Vec<String> = lichess.requests().into_iter().map(|request| request.body).collect();
+        let expected: Vec<String> = reasons.iter().map(|(_, key)| format!("reason={}", key)).collect();
+        assert_eq!(bodies, expected);
+    }
+
+    #[tokio::test]
+    async fn does_nothing_without_challenges() {
+        let lichess = MockLichess::start();
+        lichess.respond("GET", "/api/challenge", 200, NO_CHALLENGES);
+
+        respond_to_all_challenges(&lichess.client()).await.unwrap();
+
+        assert_eq!(lichess.request_lines(), ["GET /api/challenge"]);
+    }
+
+    #[tokio::test]
+    async fn stops_answering_challenges_when_rate_limited() {
+        let lichess = MockLichess::start();
+        lichess.respond("GET", "/api/challenge", 200, CHALLENGES)
+            .respond("POST", "/api/challenge/corresp1/accept", 429, RATE_LIMITED);
+
+        let e = respond_to_all_challenges(&lichess.client()).await.unwrap_err();
+
+        assert!(matches!(lichess_error(&e), LichessError::RateLimited));
+        assert_eq!(lichess.request_lines(), ["GET /api/challenge", "POST /api/challenge/corresp1/accept"]);
+    }
+
+    #[tokio::test]
+    async fn skips_challenges_it_cant_read() {
+        let lichess = MockLichess::start();
+        let challenges = CHALLENGES.replacen(r#""in": ["#, r#""in": [{"id": "broken"}, "#, 1);
+        lichess.respond("GET", "/api/challenge", 200, &challenges)
+            .respond("POST", "/api/challenge/corresp1/accept", 200, OK)
+            .respond("POST", "/api/challenge/blitz001/decline", 200, OK)
+            .respond("POST", "/api/challenge/unlim001/decline", 200, OK);
+
+        respond_to_all_challenges(&lichess.client()).await.unwrap();
+
+        assert_eq!(lichess.request_lines(), [
+            "GET /api/challenge",
+            "POST /api/challenge/corresp1/accept",
+            "POST /api/challenge/blitz001/decline",
+            "POST /api/challenge/unlim001/decline",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn unreadable_challenges_response_is_an_error() {
+        let lichess = MockLichess::start();
+        lichess.respond("GET", "/api/challenge", 200, r#"{"in": "not a list"}"#);
 
-async fn list_current_challenges() -> Result<String, Error> {
-    let LICHESS_ACCESS_TOKEN = env::var("RUST_BOT_TOKEN").expect("You need to set a Lichess personal access token.");
-
-    let client = reqwest::Client::new();
-    let response = client
-        .get("https://lichess.org/api/challenge")
-        .header("Authorization", "Bearer ".to_owned() + LICHESS_ACCESS_TOKEN.as_str())
-        .timeout(Duration::from_secs(5))
-        .send()
-        .await?
-        .text()
-        .await?;
-
-    Ok(response)
-}
\ No newline at end of file
+        let e = respond_to_all_challenges(&lichess.client()).await.unwrap_err();
+
+        assert!(matches!(lichess_error(&e), LichessError::Json(_)));
+        assert_eq!(lichess.request_lines(), ["GET /api/challenge"]);
+    }
+
+    #[tokio::test]
+    async fn answers_the_other_challenges_when_one_fails() {
+        let lichess = MockLichess::start();
+        lichess.respond("GET", "/api/challenge", 200, CHALLENGES)
+            .respond("POST", "/api/challenge/corresp1/accept", 404, r#"{"error": "Not found"}"#)
+            .respond("POST", "/api/challenge/blitz001/decline", 200, OK)
+            .respond("POST", "/api/challenge/unlim001/decline", 200, OK);
+
+        respond_to_all_challenges(&lichess.client()).await.unwrap();
+
+        assert_eq!(lichess.request_lines(), [
+            "GET /api/challenge",
+            "POST /api/challenge/corresp1/accept",
+            "POST /api/challenge/blitz001/decline",
+            "POST /api/challenge/unlim001/decline",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn moves_only_in_games_where_it_is_the_bots_turn() {
+        let lichess = MockLichess::start();
+        lichess.respond("GET", "/api/account/playing", 200, ONGOING_GAMES)
+            .respond("POST", "/api/bot/game/mate0001/move/a1a8", 200, OK);
+
+        make_moves_in_all_ongoing_games(&lichess.client()).await.unwrap();
+
+        assert_eq!(lichess.request_lines(), ["GET /api/account/playing", "POST /api/bot/game/mate0001/move/a1a8"]);
+    }
+
+    #[tokio::test]
+    async fn does_nothing_without_games() {
+        let lichess = MockLichess::start();
+        lichess.respond("GET", "/api/account/playing", 200, NO_GAMES);
+
+        make_moves_in_all_ongoing_games(&lichess.client()).await.unwrap();
+
+        assert_eq!(lichess.request_lines(), ["GET /api/account/playing"]);
+    }
+
+    #[tokio::test]
+    async fn skips_games_with_an_invalid_position() {
+        let lichess = MockLichess::start();
+        // the game waiting for the opponent becomes the bot's turn in a position it can't read
+        let games = ONGOING_GAMES.replacen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", "not a fen", 1)
+            .replacen(r#""isMyTurn": false"#, r#""isMyTurn": true"#, 1);
+        lichess.respond("GET", "/api/account/playing", 200, &games)
+            .respond("POST", "/api/bot/game/mate0001/move/a1a8", 200, OK);
+
+        make_moves_in_all_ongoing_games(&lichess.client()).await.unwrap();
+
+        assert_eq!(lichess.request_lines(), ["GET /api/account/playing", "POST /api/bot/game/mate0001/move/a1a8"]);
+    }
+
+    #[tokio::test]
+    async fn rejected_move_doesnt_stop_the_other_games() {
+        let lichess = MockLichess::start();
+        // a second game in the same position, after the one Lichess turns the move down in
+        let games = ONGOING_GAMES.replacen("wait0001", "mate0002", 1)
+            .replacen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", "7k/6pp/8/8/8/8/8/R5K1 w - - 0 30", 1)
+            .replacen(r#""isMyTurn": false"#, r#""isMyTurn": true"#, 1);
+        lichess.respond("GET", "/api/account/playing", 200, &games)
+            .respond("POST", "/api/bot/game/mate0001/move/a1a8", 400, r#"{"error": "Not your turn, or game already over"}"#)
+            .respond("POST", "/api/bot/game/mate0002/move/a1a8", 200, OK);
+
+        make_moves_in_all_ongoing_games(&lichess.client()).await.unwrap();
+
+        assert_eq!(lichess.request_lines(), [
+            "GET /api/account/playing",
+            "POST /api/bot/game/mate0001/move/a1a8",
+            "POST /api/bot/game/mate0002/move/a1a8",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn skips_games_it_cant_read() {
+        let lichess = MockLichess::start();
+        let games = ONGOING_GAMES.replacen(r#""nowPlaying": ["#, r#""nowPlaying": [{"gameId": "broken"}, "#, 1);
+        lichess.respond("GET", "/api/account/playing", 200, &games)
+            .respond("POST", "/api/bot/game/mate0001/move/a1a8", 200, OK);
+
+        make_moves_in_all_ongoing_games(&lichess.client()).await.unwrap();
+
+        assert_eq!(lichess.request_lines(), ["GET /api/account/playing", "POST /api/bot/game/mate0001/move/a1a8"]);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_move_is_made_on_the_next_run() {
+        let lichess = MockLichess::start();
+        lichess.respond("GET", "/api/account/playing", 429, RATE_LIMITED)
+            .respond("GET", "/api/account/playing", 200, ONGOING_GAMES)
+            .respond("POST", "/api/bot/game/mate0001/move/a1a8", 200, OK);
+        let client = lichess.client();
+
+        let e = make_moves_in_all_ongoing_games(&client).await.unwrap_err();
+        assert!(matches!(lichess_error(&e), LichessError::RateLimited));
+        make_moves_in_all_ongoing_games(&client).await.unwrap();
+
+        assert_eq!(lichess.request_lines(), [
+            "GET /api/account/playing",
+            "GET /api/account/playing",
+            "POST /api/bot/game/mate0001/move/a1a8",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn event_stream_skips_keep_alive_lines_and_unknown_events() {
+        let lichess = MockLichess::start();
+        let challenge = serde_json::to_string(&serde_json::json!({
+            "type": "challenge",
+            "challenge": serde_json::from_str::<serde_json::Value>(CHALLENGES).unwrap()["in"][1],
+        })).unwrap();
+        lichess.stream("/api/stream/event", &[
+            "",
+            &challenge,
+            r#"{"type": "challengeDeclined", "challenge": {"id": "blitz001"}}"#,
+            "not json",
+            r#"{"type": "gameStart", "game": {"gameId": "game0001", "id": "game0001", "fullId": "game0001abcd"}}"#,
+        ]);
+
+        let mut events = lichess.client().stream_events().await.unwrap();
+        let mut received = Vec::new();
+        while let Some(event) = events.next().await.unwrap() {
+            received.push(event);
+        }
+
+        assert_eq!(received.len(), 3);
+        assert!(matches!(&received[0], Event::Challenge { challenge } if challenge.id == "blitz001"));
+        assert!(matches!(received[1], Event::Other));
+        assert!(matches!(&received[2], Event::GameStart { game } if game.game_id == "game0001"));
+    }
+
+    #[tokio::test]
+    async fn stalled_stream_is_an_error() {
+        let lichess = MockLichess::start();
+        lichess.stalled_stream("/api/stream/event", &[
+            r#"{"type": "gameStart", "game": {"gameId": "game0001", "id": "game0001", "fullId": "game0001abcd"}}"#,
+        ]);
+
+        let client = lichess.client().with_stream_timeout(std::time::Duration::from_millis(200));
+        let mut events = client.stream_events().await.unwrap();
+
+        assert!(matches!(events.next().await.unwrap(), Some(Event::GameStart { .. })));
+        assert!(matches!(events.next().await, Err(LichessError::StreamTimeout)));
+    }
+
+    #[test]
+    fn time_controls_show_minutes_as_lichess_does() {
+        let clock = |limit, increment| TimeControl::Clock { limit, increment }.to_string();
+
+        assert_eq!(clock(180, 2), "3+2");
+        assert_eq!(clock(30, 0), "½+0");
+        assert_eq!(clock(15, 1), "¼+1");
+        assert_eq!(clock(90, 0), "1.5+0");
+    }
+}
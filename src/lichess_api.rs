@@ -0,0 +1,475 @@
+// A client for the parts of the Lichess API the bot uses, with typed models of what it sends and gets
+// back. Fields the bot doesn't need are left out and fields Lichess doesn't always send are optional,
+// so a response with something unexpected in it is either still understood or turned into an error
+// the caller can log, instead of a panic. Streams are NDJSON, one JSON object per line, with empty
+// lines sent now and then to keep the connection open.
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::env;
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+pub const LICHESS_URL: &str = "https://lichess.org";
+
+// how long a request can take, apart from streams, which stay open as long as there are events
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// exporting games can take longer than other requests
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(30);
+// how long a stream can go without sending anything. Lichess sends an empty line every few seconds
+// to keep streams open, so a stream that has been quiet for longer than this has stalled
+const STREAM_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// What can go wrong talking to Lichess
+#[derive(Debug)]
+pub enum LichessError {
+    MissingToken,
+    // the request couldn't be sent or the response couldn't be read
+    Http(reqwest::Error),
+    // Lichess answered 429, and wants a minute's wait before the next request
+    RateLimited,
+    // any other response that isn't a success, with the error Lichess gave
+    Status(StatusCode, String),
+    // the response wasn't what the models expect
+    Json(serde_json::Error),
+    // a stream stopped sending anything, even the empty lines that keep it open
+    StreamTimeout,
+}
+
+impl fmt::Display for LichessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LichessError::MissingToken => write!(f, "You need to set a Lichess personal access token in RUST_BOT_TOKEN."),
+            LichessError::Http(e) => write!(f, "Request to Lichess failed: {}", e),
+            LichessError::RateLimited => write!(f, "Rate limited by Lichess"),
+            LichessError::Status(status, message) => write!(f, "Lichess answered {}: {}", status, message),
+            LichessError::Json(e) => write!(f, "Unexpected response from Lichess: {}", e),
+            LichessError::StreamTimeout => write!(f, "Lichess stopped sending on the stream for {}s", STREAM_TIMEOUT.as_secs()),
+        }
+    }
+}
+
+impl std::error::Error for LichessError {}
+
+impl From<reqwest::Error> for LichessError {
+    fn from(e: reqwest::Error) -> Self {
+        LichessError::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for LichessError {
+    fn from(e: serde_json::Error) -> Self {
+        LichessError::Json(e)
+    }
+}
+
+/// The bot's account, from `/api/account`
+#[derive(Debug, Deserialize)]
+pub struct Account {
+    pub id: String,
+    pub username: String,
+}
+
+/// A player in a challenge
+#[derive(Debug, Deserialize)]
+pub struct ChallengeUser {
+    pub id: String,
+    pub name: String,
+    pub rating: Option<u32>,
+    // "BOT" for bot accounts
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Variant {
+    // e.g. "standard", "chess960" or "fromPosition"
+    pub key: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum TimeControl {
+    // limit and increment are in seconds
+    Clock { limit: u64, increment: u64 },
+    Correspondence {
+        #[serde(rename = "daysPerTurn")]
+        days_per_turn: u32,
+    },
+    Unlimited,
+}
+
+/// A challenge to or from the bot
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Challenge {
+    pub id: String,
+    pub challenger: Option<ChallengeUser>,
+    pub variant: Variant,
+    pub rated: bool,
+    // e.g. "bullet", "blitz" or "correspondence"
+    pub speed: String,
+    pub time_control: TimeControl,
+}
+
+/// returns a clock's starting time in minutes, the way Lichess shows it: "½" for 30 seconds and
+/// "1.5" for 90
+pub fn clock_minutes(seconds: u64) -> String {
+    match seconds {
+        15 => "¼".to_string(),
+        30 => "½".to_string(),
+        45 => "¾".to_string(),
+        _ => (seconds as f64 / 60.0).to_string(),
+    }
+}
+
+impl fmt::Display for TimeControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeControl::Clock { limit, increment } => write!(f, "{}+{}", clock_minutes(*limit), increment),
+            TimeControl::Correspondence { days_per_turn } => write!(f, "{} days per turn", days_per_turn),
+            TimeControl::Unlimited => write!(f, "unlimited"),
+        }
+    }
+}
+
+// e.g. `abcd1234 from Magnus (BOT, 2850): standard rated blitz 3+2`
+impl fmt::Display for Challenge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id)?;
+        if let Some(challenger) = &self.challenger {
+            write!(f, " from {} ({}, {})", challenger.name, challenger.title.as_deref().unwrap_or("untitled"),
+                challenger.rating.map(|rating| rating.to_string()).unwrap_or_else(|| "unrated".to_string()))?;
+        }
+        write!(f, ": {} {} {} {}", self.variant.key, if self.rated { "rated" } else { "casual" }, self.speed, self.time_control)
+    }
+}
+
+/// Why a challenge is declined, as Lichess names the reasons
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeclineReason {
+    Generic,
+    Later,
+    TooFast,
+    TooSlow,
+    TimeControl,
+    // the challenger is asked for a rated game instead, so this declines casual games
+    Rated,
+    // and this declines rated games
+    Casual,
+    Variant,
+    NoBot,
+    OnlyBot,
+}
+
+impl DeclineReason {
+    /// the key Lichess expects for the reason
+    pub fn key(self) -> &'static str {
+        match self {
+            DeclineReason::Generic => "generic",
+            DeclineReason::Later => "later",
+            DeclineReason::TooFast => "tooFast",
+            DeclineReason::TooSlow => "tooSlow",
+            DeclineReason::TimeControl => "timeControl",
+            DeclineReason::Rated => "rated",
+            DeclineReason::Casual => "casual",
+            DeclineReason::Variant => "variant",
+            DeclineReason::NoBot => "noBot",
+            DeclineReason::OnlyBot => "onlyBot",
+        }
+    }
+}
+
+/// The bot's challenges, from `/api/challenge`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Challenges {
+    #[serde(rename = "in", deserialize_with = "skip_unreadable")]
+    pub received: Vec<Challenge>,
+    #[serde(rename = "out", deserialize_with = "skip_unreadable")]
+    pub sent: Vec<Challenge>,
+}
+
+/// A game the bot is playing, from `/api/account/playing`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OngoingGame {
+    pub game_id: String,
+    pub fen: String,
+    pub is_my_turn: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct OngoingGames {
+    #[serde(deserialize_with = "skip_unreadable")]
+    now_playing: Vec<OngoingGame>,
+}
+
+// reads a list, logging and skipping the items that aren't what the models expect, so one odd
+// challenge or game doesn't stop the bot from answering the others
+fn skip_unreadable<'de, D: Deserializer<'de>, T: DeserializeOwned>(deserializer: D) -> Result<Vec<T>, D::Error> {
+    let items = Vec::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(items.into_iter().filter_map(|item| match serde_json::from_value(item.clone()) {
+        Ok(item) => Some(item),
+        Err(e) => {
+            println!("skipping {}: {}", item, e);
+            None
+        }
+    }).collect())
+}
+
+// the parameters of `/api/games/user/{username}`
+#[derive(Serialize)]
+struct GameExportQuery {
+    max: u32,
+    finished: bool,
+    ongoing: bool,
+}
+
+// the form sent to decline a challenge
+#[derive(Serialize)]
+struct DeclineForm {
+    reason: &'static str,
+}
+
+// the answer to requests that only do something, like making a move
+#[derive(Deserialize)]
+struct OkResponse {
+    #[serde(default)]
+    ok: bool,
+}
+
+// the body of an error response
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// A game in the event stream
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GameEventInfo {
+    pub game_id: String,
+}
+
+/// An event from `/api/stream/event`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Event {
+    Challenge { challenge: Challenge },
+    GameStart { game: GameEventInfo },
+    GameFinish { game: GameEventInfo },
+    // challengeCanceled, challengeDeclined and any events added later
+    #[serde(other)]
+    Other,
+}
+
+/// A player in a game. Lichess's AI has no id
+#[derive(Debug, Deserialize)]
+pub struct GamePlayer {
+    pub id: Option<String>,
+}
+
+/// The clock of a game, in milliseconds
+#[derive(Debug, Deserialize)]
+pub struct GameClock {
+    pub initial: u64,
+    pub increment: u64,
+}
+
+/// The moves and clocks of a game
+#[derive(Debug, Deserialize)]
+pub struct GameState {
+    // the moves so far in UCI format, separated by spaces
+    pub moves: String,
+    // the clocks and increments in milliseconds. Correspondence games have very large clocks
+    #[serde(default)]
+    pub wtime: u64,
+    #[serde(default)]
+    pub btime: u64,
+    #[serde(default)]
+    pub winc: u64,
+    #[serde(default)]
+    pub binc: u64,
+    // e.g. "started", "mate" or "resign"
+    pub status: String,
+}
+
+/// An event from `/api/bot/game/stream/{id}`. The first is the whole game and the rest are its state
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum GameEvent {
+    #[serde(rename_all = "camelCase")]
+    GameFull {
+        white: GamePlayer,
+        black: GamePlayer,
+        // correspondence games have no clock
+        clock: Option<GameClock>,
+        // "startpos" or a FEN
+        initial_fen: String,
+        state: GameState,
+    },
+    GameState(GameState),
+    // chatLine, opponentGone and any events added later
+    #[serde(other)]
+    Other,
+}
+
+/// Reads a stream's events as they arrive
+pub struct NdjsonStream<T> {
+    response: Response,
+    buffer: Vec<u8>,
+    timeout: Duration,
+    event: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> NdjsonStream<T> {
+    /// returns the next event, or None when the stream ends. Lines that aren't an event the models
+    /// understand are logged and skipped, so one odd event doesn't end the stream. A stream that
+    /// stops sending is an error, rather than waiting forever on a connection that has dropped
+    pub async fn next(&mut self) -> Result<Option<T>, LichessError> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                match serde_json::from_slice(&line) {
+                    Ok(event) => return Ok(Some(event)),
+                    Err(e) => println!("skipping event {}: {}", String::from_utf8_lossy(&line).trim(), e),
+                }
+                continue;
+            }
+
+            let chunk = tokio::time::timeout(self.timeout, self.response.chunk()).await.map_err(|_| LichessError::StreamTimeout)?;
+            match chunk? {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+/// Talks to Lichess as the bot. Cloning it is cheap and clones share their connections
+#[derive(Clone)]
+pub struct LichessClient {
+    http: reqwest::Client,
+    token: String,
+    base_url: String,
+    stream_timeout: Duration,
+}
+
+impl LichessClient {
+    pub fn new(token: &str, base_url: &str) -> LichessClient {
+        LichessClient {
+            http: reqwest::Client::new(),
+            token: token.to_string(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            stream_timeout: STREAM_TIMEOUT,
+        }
+    }
+
+    // waits a different time for streams to send something, so tests don't take 20s
+    #[cfg(test)]
+    pub fn with_stream_timeout(self, stream_timeout: Duration) -> LichessClient {
+        LichessClient { stream_timeout, ..self }
+    }
+
+    /// a client with the token in RUST_BOT_TOKEN, for lichess.org or the server in RUST_BOT_LICHESS_URL,
+    /// e.g. a local Lichess for testing
+    pub fn from_env() -> Result<LichessClient, LichessError> {
+        let base_url = env::var("RUST_BOT_LICHESS_URL").ok().filter(|url| !url.trim().is_empty()).unwrap_or_else(|| LICHESS_URL.to_string());
+        match env::var("RUST_BOT_TOKEN") {
+            Ok(token) if !token.trim().is_empty() => Ok(LichessClient::new(token.trim(), base_url.trim())),
+            _ => Err(LichessError::MissingToken),
+        }
+    }
+
+    fn get(&self, path: &str) -> RequestBuilder {
+        self.http.get(format!("{}{}", self.base_url, path)).bearer_auth(&self.token)
+    }
+
+    fn post(&self, path: &str) -> RequestBuilder {
+        self.http.post(format!("{}{}", self.base_url, path)).bearer_auth(&self.token)
+    }
+
+    pub async fn account(&self) -> Result<Account, LichessError> {
+        read_json(self.get("/api/account").timeout(REQUEST_TIMEOUT)).await
+    }
+
+    pub async fn challenges(&self) -> Result<Challenges, LichessError> {
+        read_json(self.get("/api/challenge").timeout(REQUEST_TIMEOUT)).await
+    }
+
+    pub async fn ongoing_games(&self) -> Result<Vec<OngoingGame>, LichessError> {
+        let games: OngoingGames = read_json(self.get("/api/account/playing").timeout(REQUEST_TIMEOUT)).await?;
+        Ok(games.now_playing)
+    }
+
+    pub async fn accept_challenge(&self, challenge_id: &str) -> Result<(), LichessError> {
+        read_ok(self.post(&format!("/api/challenge/{}/accept", challenge_id)).timeout(REQUEST_TIMEOUT)).await
+    }
+
+    /// declines a challenge, giving the challenger the reason
+    pub async fn decline_challenge(&self, challenge_id: &str, reason: DeclineReason) -> Result<(), LichessError> {
+        let request = self.post(&format!("/api/challenge/{}/decline", challenge_id)).form(&DeclineForm { reason: reason.key() });
+        read_ok(request.timeout(REQUEST_TIMEOUT)).await
+    }
+
+    /// makes a move in a game. The move is in UCI format, e.g. "e2e4"
+    pub async fn make_move(&self, game_id: &str, uci_move: &str) -> Result<(), LichessError> {
+        read_ok(self.post(&format!("/api/bot/game/{}/move/{}", game_id, uci_move)).timeout(REQUEST_TIMEOUT)).await
+    }
+
+    /// returns a player's most recent finished games as PGN
+    pub async fn finished_games(&self, username: &str, max: u32) -> Result<String, LichessError> {
+        let request = self.get(&format!("/api/games/user/{}", username))
+            .query(&GameExportQuery { max, finished: true, ongoing: false })
+            .header("Accept", "application/x-chess-pgn")
+            .timeout(EXPORT_TIMEOUT);
+        Ok(check_status(request.send().await?).await?.text().await?)
+    }
+
+    /// opens the stream of the bot's challenges and games starting and finishing
+    pub async fn stream_events(&self) -> Result<NdjsonStream<Event>, LichessError> {
+        open_stream(self.get("/api/stream/event"), self.stream_timeout).await
+    }
+
+    /// opens the stream of a game's moves
+    pub async fn stream_game(&self, game_id: &str) -> Result<NdjsonStream<GameEvent>, LichessError> {
+        open_stream(self.get(&format!("/api/bot/game/stream/{}", game_id)), self.stream_timeout).await
+    }
+}
+
+// turns a response that isn't a success into an error
+async fn check_status(response: Response) -> Result<Response, LichessError> {
+    let status = response.status();
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return Err(LichessError::RateLimited);
+    }
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        let message = serde_json::from_str::<ErrorResponse>(&body).map(|error| error.error).unwrap_or(body);
+        return Err(LichessError::Status(status, message));
+    }
+    Ok(response)
+}
+
+async fn read_json<T: DeserializeOwned>(request: RequestBuilder) -> Result<T, LichessError> {
+    let body = check_status(request.send().await?).await?.text().await?;
+    Ok(serde_json::from_str(&body)?)
+}
+
+async fn read_ok(request: RequestBuilder) -> Result<(), LichessError> {
+    let response: OkResponse = read_json(request).await?;
+    if !response.ok {
+        return Err(LichessError::Status(StatusCode::OK, "Lichess didn't answer ok".to_string()));
+    }
+    Ok(())
+}
+
+async fn open_stream<T>(request: RequestBuilder, timeout: Duration) -> Result<NdjsonStream<T>, LichessError> {
+    let response = check_status(request.send().await?).await?;
+    Ok(NdjsonStream { response, buffer: Vec::new(), timeout, event: PhantomData })
+}
@@ -1,10 +1,34 @@
 mod lichess;
+mod lichess_api;
+mod challenge_policy;
+#[cfg(test)]
+mod mock_lichess;
 mod minimax;
 mod constants;
 mod debugging;
+mod evaluation;
+mod eval_params;
+mod tuning;
+mod nnue;
+mod endgame;
+mod syzygy;
+mod retrograde;
+mod symmetry;
+mod personality;
+mod scripting;
+mod book;
+mod polyglot_keys;
+mod pgn;
+mod book_builder;
+mod learning;
+mod uci;
+mod xboard;
+mod daemon;
 
 use lichess::make_moves_in_all_ongoing_games;
 use lichess::respond_to_all_challenges;
+use lichess::learn_from_finished_games;
+use lichess_api::LichessClient;
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use serde::{Deserialize, Serialize};
 use std::env;
@@ -28,29 +52,142 @@ struct Response {
 /// This is the function that will be called when the lambda is invoked
 async fn function_handler(event: LambdaEvent<Request>) -> Result<Response, Error> {
     dotenv().ok();
-    respond_to_all_challenges().await?;
-    make_moves_in_all_ongoing_games().await?;    
+    let client = LichessClient::from_env()?;
+    respond_to_all_challenges(&client).await?;
+    make_moves_in_all_ongoing_games(&client).await?;
+    learn_from_finished_games(&client).await?;
 
     Ok(Response {
         req_id: event.context.request_id,
-        msg: format!("Success"),
+        msg: "Success".to_string(),
     })
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    // tracing_subscriber::fmt()
-    //     .with_max_level(tracing::Level::INFO)
-    //     // disable printing the name of the module in every log line.
-    //     .with_target(false)
-    //     // disabling time is handy because CloudWatch will add the ingestion time.
-    //     .without_time()
-    //     .init();
-
-    // run(service_fn(function_handler)).await
+    dotenv().ok();
+    personality::init_personality_from_env()?;
+    eval_params::init_params_from_env()?;
+    nnue::init_network_from_env()?;
+    syzygy::init_tablebases_from_env()?;
+    retrograde::init_tables_from_env()?;
+    scripting::init_script_from_env()?;
+    book::init_book_from_env()?;
+    learning::init_learning_from_env()?;
+    challenge_policy::init_challenge_policy_from_env()?;
+    let args: Vec<String> = env::args().collect();
 
-    // test bot by playing against itself
-    let board = Board::start_pos();
-    debugging::play_against_itself(board, 4);
-    Ok(())
-}
\ No newline at end of file
+    match args.get(1).map(String::as_str) {
+        Some("lambda") => run(service_fn(function_handler)).await,
+        // stays connected to Lichess and plays games with a clock as well as correspondence games
+        Some("daemon") => daemon::run().await,
+        // speaks the UCI protocol on stdin and stdout, for chess GUIs and tournament managers
+        Some("uci") => {
+            uci::run();
+            Ok(())
+        }
+        // speaks the XBoard protocol on stdin and stdout, for GUIs and tools that don't support UCI
+        Some("xboard") => {
+            xboard::run();
+            Ok(())
+        }
+        // prints how the evaluation scores a position, e.g. `eval "<fen>" --json`
+        Some("eval") => {
+            let fen = args.get(2).expect("Usage: eval <fen> [--json]");
+            let board = Board::from_fen(fen).map_err(|e| format!("Invalid FEN: {:?}", e))?;
+            debugging::print_eval_trace(&board, args.iter().any(|arg| arg == "--json"));
+            Ok(())
+        }
+        // prints what the Syzygy tablebases say about a position, e.g. `probe "8/8/8/4k3/8/8/8/R3K3 w - - 0 1"`
+        Some("probe") => {
+            let fen = args.get(2).expect("Usage: probe <fen>");
+            let board = Board::from_fen(fen).map_err(|e| format!("Invalid FEN: {:?}", e))?;
+            debugging::print_tablebase_probe(board);
+            Ok(())
+        }
+        // prints the opening book moves for a position, e.g. `book "<fen>"`, or for the start position
+        Some("book") => {
+            let board = match args.get(2) {
+                Some(fen) => Board::from_fen(fen).map_err(|e| format!("Invalid FEN: {:?}", e))?,
+                None => Board::start_pos(),
+            };
+            debugging::print_book_moves(&board);
+            Ok(())
+        }
+        // builds an opening book from the PGN files in a directory, e.g.
+        // `buildbook games book.bin --min-rating 2200 --results 1-0,0-1,1/2-1/2 --depth 30 --min-games 2`
+        Some("buildbook") => {
+            let (Some(pgn_dir), Some(output)) = (args.get(2), args.get(3)) else {
+                return Err("Usage: buildbook <pgn dir> <output file> [--min-rating N] [--results 1-0,0-1,1/2-1/2] [--depth plies] [--min-games N]".into());
+            };
+            let filter = book_builder::BookFilter::from_args(&args[4..])?;
+            book_builder::build_book(pgn_dir, output, &filter)?;
+            Ok(())
+        }
+        // learns from the bot's finished games in a PGN file, e.g. an export of its games from Lichess
+        Some("learn") => {
+            let path = args.get(2).expect("Usage: learn <pgn file>");
+            let bot_name = env::var("RUST_BOT_NAME").map_err(|_| "Set RUST_BOT_NAME to the bot's Lichess username".to_string())?;
+            let pgn = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+            println!("learned from {} new games", learning::learn_from_pgn(&pgn, &bot_name)?);
+            Ok(())
+        }
+        // prints the evaluation parameters in use, in the format RUST_BOT_EVAL_PARAMS files are read in
+        Some("params") => {
+            println!("{}", eval_params::params().to_pretty_json());
+            Ok(())
+        }
+        // prints the personality in use, in the format RUST_BOT_PERSONALITY files are read in
+        Some("personality") => {
+            println!("{}", personality::personality().to_pretty_json());
+            Ok(())
+        }
+        // tunes the evaluation parameters against labelled positions, e.g. `tune positions.epd tuned.json 500`
+        Some("tune") => {
+            let (Some(positions), Some(output)) = (args.get(2), args.get(3)) else {
+                return Err("Usage: tune <positions file> <output file> [epochs]".into());
+            };
+            let epochs = match args.get(4) {
+                Some(epochs) => epochs.parse()?,
+                None => 200,
+            };
+            tuning::tune(positions, output, epochs)?;
+            Ok(())
+        }
+        // checks that swapping colours negates the evaluation, e.g. `symmetry 500` for the positions of
+        // 500 random games or `symmetry "<fen>"` for one position
+        Some("symmetry") => {
+            match args.get(2) {
+                Some(arg) => match arg.parse() {
+                    Ok(games) => debugging::print_random_symmetry_report(games),
+                    Err(_) => {
+                        let board = Board::from_fen(arg).map_err(|e| format!("Invalid FEN: {:?}", e))?;
+                        debugging::print_symmetry_report(&[board]);
+                    }
+                },
+                None => debugging::print_random_symmetry_report(100),
+            }
+            Ok(())
+        }
+        // solves the small endgames and writes their tables to a directory, e.g. `gentables tables`
+        Some("gentables") => {
+            let output = args.get(2).expect("Usage: gentables <output dir>");
+            retrograde::generate_tables(output)?;
+            Ok(())
+        }
+        // plays the bot against itself from the given position, e.g. to check it can mate in an endgame
+        Some("selfplay") => {
+            let fen = args.get(2).expect("Usage: selfplay <fen> [depth]");
+            let board = Board::from_fen(fen).map_err(|e| format!("Invalid FEN: {:?}", e))?;
+            let depth = args.get(3).map(|d| d.parse()).transpose().map_err(|e| format!("Invalid depth: {}", e))?.unwrap_or(4);
+            debugging::play_against_itself(board, depth);
+            Ok(())
+        }
+        _ => {
+            // test bot by playing against itself
+            let board = Board::start_pos();
+            debugging::play_against_itself(board, 4);
+            Ok(())
+        }
+    }
+}
@@ -1,76 +1,350 @@
 use crate::constants;
+use crate::evaluation;
+use crate::syzygy;
+use crate::personality;
+use crate::book;
 
-use pleco::{Board,Player,PieceType,SQ,BitMove};
+use pleco::{Board,Player,BitMove};
 use std::cmp::max;
 use std::cmp::min;
-use constants::{NUM_TURNS_TO_LOOK_AHEAD, ROOK_SCORE_GRID, PAWN_SCORE_GRID, BISHOP_SCORE_GRID, KNIGHT_SCORE_GRID, QUEEN_SCORE_GRID, KING_SCORE_GRID, EMPTY_SCORE_GRID, square_to_int, get_piece_value};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use constants::NUM_TURNS_TO_LOOK_AHEAD;
+use evaluation::{Evaluator, MaterialEvaluator, create_evaluator, is_draw, CHECKMATE_SCORE};
+use syzygy::{tablebases, Wdl, TABLEBASE_WIN_SCORE};
+use personality::personality;
+use book::{is_polyglot_book_move, pick_book_move};
 
-// returns the best move as a string for the current player in the given board state
-pub fn calculate_next_move(mut board: Board) -> String {
-    let next_move = minimax(board.clone(), NUM_TURNS_TO_LOOK_AHEAD);
-    next_move.to_string().parse().unwrap()
+// the blunder check searches a ply deeper than the normal search, with quiescence at the horizon,
+// which extends every capture sequence until the position is quiet. It only counts material, so it
+// is fast enough for the extra ply, and it swaps the move if an alternative keeps more than
+// `BLUNDER_MARGIN` centipawns more material
+const BLUNDER_CHECK_DEPTH: u8 = NUM_TURNS_TO_LOOK_AHEAD + 1;
+const BLUNDER_MARGIN: i32 = 150;
+// quiescence stops following checks after this many plies, so a run of checks can't go on forever
+const MAX_QUIESCENCE_PLIES: u8 = 16;
+
+// how many moves the blunder check has looked at and swapped since the bot started
+static BLUNDER_CHECKS: AtomicUsize = AtomicUsize::new(0);
+static BLUNDERS_SWAPPED: AtomicUsize = AtomicUsize::new(0);
+
+// positions searched since the bot started, for reporting how fast the search is
+static NODES: AtomicU64 = AtomicU64::new(0);
+// the search looks at the clock every this many positions
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+// iterative deepening stops here even without other limits
+const MAX_SEARCH_DEPTH: u8 = 64;
+
+// how a search scores positions it can't search any further, and when it stops
+struct SearchSettings {
+    // score of a drawn position, which includes the bot's contempt
+    draw_score: i32,
+    // resolve captures at the horizon instead of evaluating straight away
+    quiescence: bool,
+    // the search stops when it gets to this time
+    deadline: Option<Instant>,
+    // set to make the search give up on the depth it is searching, by the caller or when the deadline
+    // passes. Each search has its own, so one that runs out of time doesn't stop the next
+    stop: Arc<AtomicBool>,
+}
+
+impl SearchSettings {
+    fn new(board: &Board, quiescence: bool) -> SearchSettings {
+        // contempt makes draws look worse for the bot than an equal position
+        let draw_score = if board.turn() == Player::White { -personality().contempt } else { personality().contempt };
+        SearchSettings { draw_score, quiescence, deadline: None, stop: Arc::new(AtomicBool::new(false)) }
+    }
+}
+
+// returns the best move for the current player in the given board state, from the opening book if
+// it has one
+pub fn calculate_next_move(board: Board) -> BitMove {
+    if let Some(book_move) = pick_book_move(&board) {
+        println!("book move: {}", book_move);
+        return book_move;
+    }
+    minimax(board, NUM_TURNS_TO_LOOK_AHEAD)
 }
 
 // takes in a board and returns the best move to make for the current player
 pub fn minimax(mut board: Board, depth: u8) -> BitMove {
-    let possible_moves = board.generate_moves();
-    let mut alpha = -9999999;
-    let mut beta = 9999999;
-
-    if possible_moves.len() == 0 {
+    if board.generate_moves().is_empty() {
         panic!("No possible moves for this position");
     }
 
-    let mut best_move : BitMove = possible_moves[0];
+    if let Some((tablebase_move, dtz)) = tablebases().and_then(|tablebases| tablebases.best_move(&mut board)) {
+        println!("tablebase move: {}, dtz: {}", tablebase_move, dtz);
+        return tablebase_move;
+    }
+
+    let settings = SearchSettings::new(&board, false);
+    let (best_move, score) = search_root(&mut board, depth, None, &settings).unwrap();
+    println!("best move: {}, score: {}", best_move, score);
+    best_move
+}
+
+// moves left in the game assumed when the clock doesn't say, for splitting up the remaining time
+const DEFAULT_MOVES_TO_GO: u64 = 30;
+
+/// Limits on an iterative deepening search. Without any, it searches until it is stopped
+#[derive(Clone, Default)]
+pub struct SearchLimits {
+    pub depth: Option<u8>,
+    // no new depth is started after this long
+    pub soft_time: Option<Duration>,
+    // the depth being searched is given up on after this long
+    pub hard_time: Option<Duration>,
+    pub quiescence: bool,
+    // setting this makes the search stop as soon as it can, e.g. when a UCI GUI sends `stop`
+    pub stop: Option<Arc<AtomicBool>>,
+}
+
+impl SearchLimits {
+    /// sets the time limits to search for exactly `millis` milliseconds
+    pub fn with_move_time(self, millis: u64) -> SearchLimits {
+        let time = Some(Duration::from_millis(millis.max(1)));
+        SearchLimits { soft_time: time, hard_time: time, ..self }
+    }
+
+    /// sets the time limits from a clock with `millis` milliseconds left, gaining `increment` after
+    /// each move. The time is shared out between the moves left, plus most of the increment. No new
+    /// depth is started after half of that, because the next depth would take longer than all the
+    /// depths before it, but a depth that has started can use up to three times as much, as long as
+    /// it leaves most of the clock
+    pub fn with_clock(self, millis: u64, increment: u64, moves_to_go: Option<u64>) -> SearchLimits {
+        let available = millis.max(1);
+        let target = available / moves_to_go.unwrap_or(DEFAULT_MOVES_TO_GO).max(1) + increment * 3 / 4;
+        SearchLimits {
+            soft_time: Some(Duration::from_millis((target / 2).max(1))),
+            hard_time: Some(Duration::from_millis((target * 3).min(available / 2).max(1))),
+            ..self
+        }
+    }
+}
+
+/// What an iterative deepening search found at a depth
+pub struct SearchInfo {
+    pub depth: u8,
+    pub best_move: BitMove,
+    // positive is good for white, like the evaluation
+    pub score: i32,
+    pub nodes: u64,
+    pub elapsed: Duration,
+}
+
+impl SearchInfo {
+    /// returns the score from the point of view of `player`
+    pub fn score_for(&self, player: Player) -> i32 {
+        if player == Player::White { self.score } else { -self.score }
+    }
+
+    /// returns the number of moves to mate if the search found one, negative if `player` is the one
+    /// getting mated
+    pub fn mate_for(&self, player: Player) -> Option<i32> {
+        let score = self.score_for(player);
+        if score.abs() < CHECKMATE_SCORE {
+            return None;
+        }
+        // mates found with `n` plies of depth left score CHECKMATE_SCORE + n
+        let moves = (self.depth as i32 - (score.abs() - CHECKMATE_SCORE) + 1) / 2;
+        Some(if score > 0 { moves } else { -moves })
+    }
+}
+
+/// searches one ply deeper at a time until a limit is reached or the search is stopped, and calls
+/// `report` after each depth. The best move of the deepest finished depth is returned, and it is
+/// searched first at the next depth. Tablebase moves are returned without searching
+pub fn iterative_deepening(mut board: Board, limits: SearchLimits, mut report: impl FnMut(&SearchInfo)) -> Option<BitMove> {
+    let start = Instant::now();
+    let legal_moves = board.generate_moves();
+    if legal_moves.is_empty() {
+        return None;
+    }
+    if let Some((tablebase_move, _)) = tablebases().and_then(|tablebases| tablebases.best_move(&mut board)) {
+        return Some(tablebase_move);
+    }
+
+    let mut settings = SearchSettings::new(&board, limits.quiescence);
+    settings.deadline = limits.hard_time.map(|time| start + time);
+    if let Some(stop) = limits.stop {
+        settings.stop = stop;
+    }
+    let start_nodes = NODES.load(Ordering::Relaxed);
+    let mut best_move = None;
+
+    for depth in 1..=limits.depth.unwrap_or(MAX_SEARCH_DEPTH) {
+        let Some((depth_best_move, score)) = search_root(&mut board, depth, best_move, &settings) else {
+            break;
+        };
+        best_move = Some(depth_best_move);
+        report(&SearchInfo { depth, best_move: depth_best_move, score, nodes: NODES.load(Ordering::Relaxed) - start_nodes, elapsed: start.elapsed() });
+
+        // a deeper search can't find a quicker mate
+        if score.abs() >= CHECKMATE_SCORE || limits.soft_time.is_some_and(|time| start.elapsed() >= time) {
+            break;
+        }
+    }
+
+    // if not even one ply was searched, any legal move is better than none
+    best_move.or(Some(legal_moves[0]))
+}
+
+// searches every move, starting with `first` if it is given, and returns the best one with its score.
+// Returns None if the search was stopped
+fn search_root(board: &mut Board, depth: u8, first: Option<BitMove>, settings: &SearchSettings) -> Option<(BitMove, i32)> {
+    let mut alpha = -9999999;
+    let mut beta = 9999999;
+    let mut best_move = None;
     let curr_player = board.turn();
+    let mut evaluator = create_evaluator(board);
 
-    for curr_move in possible_moves {
+    let mut moves: Vec<BitMove> = board.generate_moves().to_vec();
+    if let Some(index) = first.and_then(|first| moves.iter().position(|&mv| mv == first)) {
+        moves[..=index].rotate_right(1);
+    }
+
+    for curr_move in moves {
         //make move
+        evaluator.make_move(board, curr_move);
         board.apply_move(curr_move);
         //evaluate
-        let score = minimax_helper(board.clone(), depth - 1, alpha, beta);
+        let score = minimax_helper(board, evaluator.as_mut(), depth - 1, alpha, beta, settings);
         //undo move
         board.undo_move();
+        evaluator.unmake_move();
+
+        if settings.stop.load(Ordering::Relaxed) {
+            return None;
+        }
 
-        if curr_player == Player::White && score > alpha {
+        if curr_player == Player::White && (score > alpha || best_move.is_none()) {
             alpha = score;
-            best_move = curr_move;
+            best_move = Some(curr_move);
         }
-        else if curr_player == Player::Black && score < beta {
+        else if curr_player == Player::Black && (score < beta || best_move.is_none()) {
             beta = score;
-            best_move = curr_move;
+            best_move = Some(curr_move);
         }
     }
-    println!("alpha: {}, beta: {}", alpha, beta);
-    best_move
+
+    let score = if curr_player == Player::White { alpha } else { beta };
+    best_move.map(|best_move| (best_move, score))
 }
 
-fn minimax_helper(mut board: Board, depth: u8, mut alpha: i32, mut beta: i32) -> i32 {
-    if board.stalemate() {
+// searches the chosen move a ply deeper than the normal search, counting only material, and returns
+// an alternative instead if it keeps more than `BLUNDER_MARGIN` more material. Each check is logged
+pub fn blunder_check(mut board: Board, chosen: BitMove) -> BitMove {
+    // tablebase moves are exact, book moves are trusted, and with one legal move there is nothing to
+    // check
+    if board.generate_moves().len() < 2
+        || tablebases().and_then(|tablebases| tablebases.best_move(&mut board)).is_some()
+        || is_polyglot_book_move(&board, chosen) {
+        return chosen;
+    }
+
+    let settings = SearchSettings::new(&board, true);
+    let mut evaluator = MaterialEvaluator::new(&board);
+    let chosen_score = search_move(&mut board, &mut evaluator, chosen, -9999999, 9999999, &settings);
+
+    // an alternative only matters if it beats the chosen move by the margin, so the others are
+    // searched with a window that just tells whether they do. One that does is searched again for
+    // its score, which the rest then have to beat
+    let mut best: Option<(BitMove, i32)> = None;
+    for mv in board.generate_moves() {
+        let target = best.map_or(chosen_score + BLUNDER_MARGIN, |(_, score)| score);
+        if mv == chosen || search_move(&mut board, &mut evaluator, mv, target, target + 1, &settings) <= target {
+            continue;
+        }
+        let score = search_move(&mut board, &mut evaluator, mv, target, 9999999, &settings);
+        best = Some((mv, score));
+    }
+
+    let checks = BLUNDER_CHECKS.fetch_add(1, Ordering::Relaxed) + 1;
+    let swapped = if best.is_some() { BLUNDERS_SWAPPED.fetch_add(1, Ordering::Relaxed) + 1 } else { BLUNDERS_SWAPPED.load(Ordering::Relaxed) };
+    match best {
+        Some((alternative, score)) => {
+            println!("blunder check: {} keeps {} material, {} keeps {}, swapping ({} of {} moves swapped)", chosen, chosen_score, alternative, score, swapped, checks);
+            alternative
+        }
+        None => {
+            println!("blunder check: {} keeps {} material, keeping it ({} of {} moves swapped)", chosen, chosen_score, swapped, checks);
+            chosen
+        }
+    }
+}
+
+// returns the score of a move for the side making it, searched to the blunder check's depth. `alpha`
+// and `beta` are the window for the side making it too
+fn search_move(board: &mut Board, evaluator: &mut dyn Evaluator, mv: BitMove, alpha: i32, beta: i32, settings: &SearchSettings) -> i32 {
+    let white = board.turn() == Player::White;
+    let (alpha, beta) = if white { (alpha, beta) } else { (-beta, -alpha) };
+
+    evaluator.make_move(board, mv);
+    board.apply_move(mv);
+    let score = minimax_helper(board, evaluator, BLUNDER_CHECK_DEPTH - 1, alpha, beta, settings);
+    board.undo_move();
+    evaluator.unmake_move();
+
+    if white { score } else { -score }
+}
+
+fn minimax_helper(board: &mut Board, evaluator: &mut dyn Evaluator, depth: u8, mut alpha: i32, mut beta: i32, settings: &SearchSettings) -> i32 {
+    // the score doesn't matter once the search has stopped, it is thrown away
+    if search_stopped(settings) {
         return 0;
     }
+
+    if is_draw(board) {
+        return settings.draw_score;
+    }
     else if board.checkmate() {
+        // mates found with more depth left are closer, so they score higher and are played first
         if board.turn() == Player::White {
-            return -1000000;
+            return -CHECKMATE_SCORE - depth as i32;
         }
         else {
-            return 1000000;
+            return CHECKMATE_SCORE + depth as i32;
+        }
+    }
+
+    // straight after a capture or pawn move the tablebases know the exact result
+    if board.rule_50() == 0 {
+        if let Some(wdl) = tablebases().and_then(|tablebases| tablebases.probe_wdl(board)) {
+            return tablebase_score(wdl, board.turn(), depth, settings.draw_score);
         }
     }
 
-    if depth <= 0 {
-        return evaluate(&board);
+    if depth == 0 {
+        if settings.quiescence {
+            return quiescence(board, evaluator, alpha, beta, 0, settings);
+        }
+        return evaluator.evaluate(board);
     }
 
     let curr_player = board.turn();
-    let possible_moves = board.generate_moves();
+    let mut possible_moves = board.generate_moves().to_vec();
+    order_moves(board, &mut possible_moves);
+
+    // one ply from the horizon, quiet moves can't make up for a position that is far behind
+    let margin = personality().futility_margin;
+    let futile = margin > 0 && depth == 1 && !board.in_check() && {
+        let static_score = evaluator.evaluate(board);
+        if curr_player == Player::White { static_score + margin <= alpha } else { static_score - margin >= beta }
+    };
 
     for curr_move in possible_moves {
+        if futile && !board.is_capture_or_promotion(curr_move) && !board.gives_check(curr_move) {
+            continue;
+        }
+
         //make move
+        evaluator.make_move(board, curr_move);
         board.apply_move(curr_move);
-        let score = minimax_helper(board.clone(), depth - 1, alpha, beta);
+        let score = minimax_helper(board, evaluator, depth - 1, alpha, beta, settings);
         //undo move
         board.undo_move();
+        evaluator.unmake_move();
 
         //alpha beta pruning
         if curr_player == Player::White {
@@ -90,75 +364,179 @@ fn minimax_helper(mut board: Board, depth: u8, mut alpha: i32, mut beta: i32) ->
     }
 
     if curr_player == Player::White {
-        return alpha;
+        alpha
     }
     else {
-        return beta;
+        beta
     }
 }
 
-//takes in a board and returns its score
-fn evaluate(board: &Board) -> i32 {
-    if (*board).stalemate() {
+// puts captures first, most valuable victims first, taken by the least valuable attackers, so cutoffs
+// come early. The order doesn't change the score a search finds, only how long it takes
+fn order_moves(board: &Board, moves: &mut [BitMove]) {
+    moves.sort_by_key(|&mv| {
+        let victim = if board.is_capture(mv) { board.piece_at_sq(mv.get_dest()).type_of() as i32 } else { 0 };
+        (-victim, if victim > 0 { board.piece_at_sq(mv.get_src()).type_of() as i32 } else { 0 })
+    });
+}
+
+// searches captures and promotions until the position is quiet, so the score at the horizon doesn't
+// miss a piece that is about to be taken. The side to move can also stop capturing and keep the
+// static evaluation, unless it is in check, when every move out of check is searched instead
+fn quiescence(board: &mut Board, evaluator: &mut dyn Evaluator, mut alpha: i32, mut beta: i32, ply: u8, settings: &SearchSettings) -> i32 {
+    if search_stopped(settings) {
         return 0;
     }
-    else if (*board).checkmate() {
-        if board.turn() == Player::White {
-            return -1000000;
+
+    if is_draw(board) {
+        return settings.draw_score;
+    }
+
+    let curr_player = board.turn();
+    if board.checkmate() {
+        // the evaluator doesn't have to know about mate, and the search has no depth left here
+        return if curr_player == Player::White { -CHECKMATE_SCORE } else { CHECKMATE_SCORE };
+    }
+    let stand_pat = evaluator.evaluate(board);
+
+    let evading = board.in_check() && ply < MAX_QUIESCENCE_PLIES;
+    if !evading {
+        if curr_player == Player::White {
+            if stand_pat >= beta {
+                return stand_pat;
+            }
+            alpha = max(alpha, stand_pat);
         }
         else {
-            return 1000000;
+            if stand_pat <= alpha {
+                return stand_pat;
+            }
+            beta = min(beta, stand_pat);
         }
     }
 
-    let mut score = 0i32;
-    let piece_locations = (*board).get_piece_locations();
-
-    for (sq, piece) in piece_locations {
-        let player = match piece.player() {
-            Some(x) => x,
-            None => Player::White
-        };
+    let mut captures: Vec<BitMove> = board.generate_moves().iter().copied().filter(|&mv| evading || board.is_capture_or_promotion(mv)).collect();
+    order_moves(board, &mut captures);
 
-        score += get_piece_score_at_square(piece.type_of(), sq, player);
-    }
+    for curr_move in captures {
+        evaluator.make_move(board, curr_move);
+        board.apply_move(curr_move);
+        let score = quiescence(board, evaluator, alpha, beta, ply + 1, settings);
+        board.undo_move();
+        evaluator.unmake_move();
 
-    if (*board).in_check() {
-        if board.turn() == Player::White {
-            score -= 400;
+        if curr_player == Player::White {
+            alpha = max(alpha, score);
+            if alpha >= beta {
+                return alpha;
+            }
         }
         else {
-            score += 400;
+            beta = min(beta, score);
+            if alpha >= beta {
+                return beta;
+            }
         }
     }
 
-    score
+    if curr_player == Player::White { alpha } else { beta }
+}
+
+// counts a searched position, and returns whether the search has been told to stop or has run out of
+// time
+fn search_stopped(settings: &SearchSettings) -> bool {
+    let nodes = NODES.fetch_add(1, Ordering::Relaxed);
+    if nodes.is_multiple_of(DEADLINE_CHECK_INTERVAL) && settings.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        settings.stop.store(true, Ordering::Relaxed);
+    }
+    settings.stop.load(Ordering::Relaxed)
 }
 
-//gets score of a piece at a specific square
-fn get_piece_score_at_square(piece: PieceType, square: SQ, player: Player) -> i32 {
-    let score_grid = match piece {
-        PieceType::R => ROOK_SCORE_GRID,
-        PieceType::N => KNIGHT_SCORE_GRID,
-        PieceType::B => BISHOP_SCORE_GRID,
-        PieceType::Q => QUEEN_SCORE_GRID,
-        PieceType::K => KING_SCORE_GRID,
-        PieceType::P => PAWN_SCORE_GRID,
-        _ => EMPTY_SCORE_GRID,
+// converts a tablebase result for the side to move into a score. Wins that are reached with more depth
+// left are closer, so score higher. Cursed wins and blessed losses are draws under the fifty move rule
+fn tablebase_score(wdl: Wdl, player: Player, depth: u8, draw_score: i32) -> i32 {
+    let score = match wdl {
+        Wdl::Win => TABLEBASE_WIN_SCORE + depth as i32,
+        Wdl::Loss => -TABLEBASE_WIN_SCORE - depth as i32,
+        _ => return draw_score,
     };
 
-    let piece_value = get_piece_value(piece);
-    let mut index = square_to_int(square);
+    if player == Player::White { score } else { -score }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::personality::{with_personality, Personality};
 
-    let score_multiplier = match player {
-        Player::White => 1,
-        Player::Black => -1,
-    };
+    #[test]
+    fn search_that_runs_out_of_time_does_not_stop_the_next() {
+        let board = Board::start_pos();
+        let limits = SearchLimits { hard_time: Some(Duration::from_millis(1)), ..Default::default() };
+        assert!(iterative_deepening(board.clone(), limits, |_| {}).is_some());
+        // this used to find the stop left set by the search before and panic
+        minimax(board, 2);
+    }
+
+    fn parse(board: &Board, uci_move: &str) -> BitMove {
+        board.generate_moves().iter().copied().find(|mv| mv.to_string() == uci_move).unwrap()
+    }
+
+    #[test]
+    fn blunder_check_swaps_a_move_that_hangs_a_piece() {
+        let board = Board::from_fen("6k1/5pp1/4p2p/8/8/8/5PPP/3R2K1 w - - 0 1").unwrap();
+        // the pawn on e6 takes the rook
+        assert_ne!(blunder_check(board.clone(), parse(&board, "d1d5")).to_string(), "d1d5");
+        assert_eq!(blunder_check(board.clone(), parse(&board, "d1d2")).to_string(), "d1d2");
+    }
+
+    #[test]
+    fn quiescence_searches_moves_out_of_check() {
+        // every way out of the rook's check lets it take the queen, which standing pat would miss
+        let mut board = Board::from_fen("k7/8/8/8/8/8/8/1r1K3Q w - - 0 1").unwrap();
+        let mut evaluator = MaterialEvaluator::new(&board);
+        let settings = SearchSettings::new(&board, true);
+        assert!(quiescence(&mut board, &mut evaluator, -9999999, 9999999, 0, &settings) < 0);
+    }
+
+    // scores every position as level, so only the search can see a mate
+    struct LevelEvaluator;
 
-    if player == Player::White {
-        index = 63 - index;
+    impl Evaluator for LevelEvaluator {
+        fn make_move(&mut self, _board: &Board, _mv: BitMove) {}
+        fn unmake_move(&mut self) {}
+        fn evaluate(&mut self, _board: &Board) -> i32 {
+            0
+        }
+    }
+
+    #[test]
+    fn quiescence_scores_a_capture_that_mates() {
+        // the rook takes on e8 and mates on the back rank
+        let mut board = Board::from_fen("4r2k/6pp/8/8/8/8/8/4R1K1 w - - 0 1").unwrap();
+        let settings = SearchSettings::new(&board, true);
+        assert_eq!(quiescence(&mut board, &mut LevelEvaluator, -9999999, 9999999, 0, &settings), CHECKMATE_SCORE);
+
+        let mut board = Board::from_fen("4r1k1/8/8/8/8/8/6PP/4R2K b - - 0 1").unwrap();
+        assert_eq!(quiescence(&mut board, &mut LevelEvaluator, -9999999, 9999999, 0, &settings), -CHECKMATE_SCORE);
     }
 
-    //calculate score by adding the piece value to the score grid value
-    (score_grid[index / 8][index % 8] + piece_value) * score_multiplier
+    #[test]
+    fn contempt_sets_the_draw_score_for_the_side_to_move() {
+        let contempt = Personality { contempt: 30, ..Default::default() };
+        let white_to_move = Board::start_pos();
+        let black_to_move = Board::from_fen("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1").unwrap();
+
+        // a draw is worse than equal for whoever the bot is playing, and scores are from white's side
+        with_personality(contempt.clone(), || assert_eq!(SearchSettings::new(&white_to_move, true).draw_score, -30));
+        with_personality(contempt, || assert_eq!(SearchSettings::new(&black_to_move, true).draw_score, 30));
+        assert_eq!(SearchSettings::new(&white_to_move, true).draw_score, 0);
+    }
+
+    #[test]
+    fn stopped_search_still_returns_a_move() {
+        let stop = Arc::new(AtomicBool::new(true));
+        let limits = SearchLimits { stop: Some(stop), ..Default::default() };
+        assert!(iterative_deepening(Board::start_pos(), limits, |_| {}).is_some());
+    }
 }
@@ -0,0 +1,210 @@
+// A stand-in for Lichess that runs in the tests, so the bot's requests can be tested without an
+// account or a network. It answers each route with the responses it has been given and keeps every
+// request it gets, so tests can check what the bot sent. Every response closes its connection, which
+// also ends streams once their events have been sent, unless the stream is made to stall.
+use crate::lichess_api::LichessClient;
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub const TOKEN: &str = "test-token";
+
+// challenges received by the bot: a correspondence game, a blitz game and a game without a clock
+pub const CHALLENGES: &str = r#"{
+    "in": [
+        {"id": "corresp1", "url": "https://lichess.org/corresp1", "status": "created",
+         "challenger": {"id": "alice", "name": "Alice", "rating": 1650, "title": null, "online": true},
+         "destUser": {"id": "rustbot", "name": "RustBot", "rating": 1800, "title": "BOT"},
+         "variant": {"key": "standard", "name": "Standard", "short": "Std"}, "rated": true, "speed": "correspondence",
+         "timeControl": {"type": "correspondence", "daysPerTurn": 2}, "color": "random", "perf": {"icon": "", "name": "Correspondence"}},
+        {"id": "blitz001", "url": "https://lichess.org/blitz001", "status": "created",
+         "challenger": {"id": "bob", "name": "Bob", "rating": 2010, "title": null},
+         "variant": {"key": "standard", "name": "Standard", "short": "Std"}, "rated": false, "speed": "blitz",
+         "timeControl": {"type": "clock", "limit": 180, "increment": 2, "show": "3+2"}, "color": "white"},
+        {"id": "unlim001", "url": "https://lichess.org/unlim001", "status": "created",
+         "challenger": {"id": "carol", "name": "Carol", "rating": 1500, "title": null, "provisional": true},
+         "variant": {"key": "standard", "name": "Standard", "short": "Std"}, "rated": false, "speed": "correspondence",
+         "timeControl": {"type": "unlimited"}, "color": "black"}
+    ],
+    "out": []
+}"#;
+
+pub const NO_CHALLENGES: &str = r#"{"in": [], "out": []}"#;
+
+// ongoing games: one where white, the bot, can mate in one with Ra8, and one waiting for the opponent
+pub const ONGOING_GAMES: &str = r#"{
+    "nowPlaying": [
+        {"gameId": "mate0001", "fullId": "mate0001abcd", "color": "white", "fen": "7k/6pp/8/8/8/8/8/R5K1 w - - 0 30",
+         "hasMoved": true, "isMyTurn": true, "lastMove": "g8h8", "opponent": {"id": "alice", "username": "Alice", "rating": 1650},
+         "perf": "correspondence", "rated": true, "secondsLeft": 172800, "source": "friend", "speed": "correspondence",
+         "variant": {"key": "standard", "name": "Standard"}},
+        {"gameId": "wait0001", "fullId": "wait0001abcd", "color": "black", "fen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+         "hasMoved": false, "isMyTurn": false, "lastMove": "", "opponent": {"id": "bob", "username": "Bob", "rating": 2010},
+         "perf": "correspondence", "rated": false, "secondsLeft": 86400, "source": "friend", "speed": "correspondence",
+         "variant": {"key": "standard", "name": "Standard"}}
+    ]
+}"#;
+
+pub const NO_GAMES: &str = r#"{"nowPlaying": []}"#;
+
+pub const OK: &str = r#"{"ok": true}"#;
+
+pub const RATE_LIMITED: &str = r#"{"error": "Too many requests. Try again later."}"#;
+
+/// A request the stand-in got
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub method: String,
+    // with the query string, if there is one
+    pub path: String,
+    // header names are lowercase
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+#[derive(Clone)]
+struct MockResponse {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+    // keeps the connection open without sending anything after the body
+    stall: bool,
+}
+
+#[derive(Default)]
+struct MockState {
+    // responses by method and path, without the query string. Each request takes the first, apart
+    // from the last, which answers every request after it
+    routes: HashMap<(String, String), Vec<MockResponse>>,
+    requests: Vec<RecordedRequest>,
+}
+
+/// Lichess running on a local port in a thread of the test
+pub struct MockLichess {
+    url: String,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockLichess {
+    pub fn start() -> MockLichess {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(MockState::default()));
+
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                let state = server_state.clone();
+                thread::spawn(move || handle_connection(stream, &state));
+            }
+        });
+
+        MockLichess { url, state }
+    }
+
+    /// a client for the stand-in
+    pub fn client(&self) -> LichessClient {
+        LichessClient::new(TOKEN, &self.url)
+    }
+
+    /// answers requests to a route with a JSON body. Calling it again for the same route queues
+    /// another response, e.g. a rate limit and then a success
+    pub fn respond(&self, method: &str, path: &str, status: u16, body: &str) -> &MockLichess {
+        self.add_response(method, path, MockResponse { status, content_type: "application/json", body: body.to_string(), stall: false })
+    }
+
+    /// answers requests to a route with a stream of events, one JSON object per line
+    pub fn stream(&self, path: &str, events: &[&str]) -> &MockLichess {
+        let body = events.iter().map(|event| format!("{}\n", event)).collect();
+        self.add_response("GET", path, MockResponse { status: 200, content_type: "application/x-ndjson", body, stall: false })
+    }
+
+    /// answers requests to a route with a stream of events that then stops sending, even keep-alive
+    /// lines, without closing the connection
+    pub fn stalled_stream(&self, path: &str, events: &[&str]) -> &MockLichess {
+        let body = events.iter().map(|event| format!("{}\n", event)).collect();
+        self.add_response("GET", path, MockResponse { status: 200, content_type: "application/x-ndjson", body, stall: true })
+    }
+
+    fn add_response(&self, method: &str, path: &str, response: MockResponse) -> &MockLichess {
+        self.state.lock().unwrap().routes.entry((method.to_string(), path.to_string())).or_default().push(response);
+        self
+    }
+
+    /// the requests the stand-in has got so far, in order
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// the method and path of each request so far, like `POST /api/challenge/abc/accept`
+    pub fn request_lines(&self) -> Vec<String> {
+        self.requests().iter().map(|request| format!("{} {}", request.method, request.path)).collect()
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, state: &Mutex<MockState>) {
+    let Some(request) = read_request(&mut stream) else { return };
+    let route = (request.method.clone(), request.path.split('?').next().unwrap_or_default().to_string());
+
+    let response = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(request);
+        match state.routes.get_mut(&route) {
+            Some(responses) if responses.len() > 1 => responses.remove(0),
+            Some(responses) if !responses.is_empty() => responses[0].clone(),
+            _ => MockResponse { status: 404, content_type: "application/json", body: r#"{"error": "Not found"}"#.to_string(), stall: false },
+        }
+    };
+
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        _ => "Unknown",
+    };
+    if response.stall {
+        // without a length the body goes on until the connection closes, which it doesn't until the test is over
+        write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nConnection: close\r\n\r\n{}",
+            response.status, reason, response.content_type, response.body).ok();
+        stream.flush().ok();
+        thread::sleep(Duration::from_secs(60));
+        return;
+    }
+    write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status, reason, response.content_type, response.body.len(), response.body).ok();
+}
+
+// reads the request line, the headers and a body with a Content-Length
+fn read_request(stream: &mut TcpStream) -> Option<RecordedRequest> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = (parts.next()?.to_string(), parts.next()?.to_string());
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let length = headers.get("content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+
+    Some(RecordedRequest { method, path, headers, body: String::from_utf8_lossy(&body).to_string() })
+}
@@ -0,0 +1,386 @@
+// A small efficiently updatable neural network (NNUE) evaluator, used instead of the handcrafted
+// evaluation when RUST_BOT_EVALUATOR=nnue.
+//
+// The network uses HalfKP inputs: for each side, every non-king piece is a feature indexed by the
+// square of that side's king, the piece and its square. Both sides share one feature transformer,
+// whose outputs (the accumulators) are updated incrementally as moves are made and taken back. The
+// side to move's accumulator and the other side's are concatenated, passed through a clipped ReLU
+// and a single output neuron.
+//
+// Weights file layout, all values little endian:
+//   magic "CBNN", format version (u32, = 1), accumulator size N (u32)
+//   feature weights: NUM_FEATURES * N i16, feature by feature
+//   feature biases: N i16
+//   output weights: 2 * N i16, the side to move's half first
+//   output bias: i32
+use crate::evaluation;
+
+use pleco::{Board,Player,PieceType,SQ,BitMove,Piece};
+use std::env;
+use std::fs;
+use std::sync::{Arc, OnceLock};
+use evaluation::{Evaluator, PieceChange, piece_changes, CHECKMATE_SCORE};
+
+const MAGIC: &[u8; 4] = b"CBNN";
+const VERSION: u32 = 1;
+
+// 64 king squares * 10 piece kinds (5 piece types for each side) * 64 squares
+pub const NUM_FEATURES: usize = 64 * 10 * 64;
+
+// quantisation: accumulators are clipped to [0, QA], output weights are scaled by QB, and the
+// output is scaled by OUTPUT_SCALE to get centipawns
+const QA: i32 = 255;
+const QB: i32 = 64;
+const OUTPUT_SCALE: i32 = 400;
+
+static NETWORK: OnceLock<Option<Arc<Network>>> = OnceLock::new();
+
+/// The weights of a network loaded from a file
+pub struct Network {
+    hidden_size: usize,
+    feature_weights: Vec<i16>,
+    feature_biases: Vec<i16>,
+    output_weights: Vec<i16>,
+    output_bias: i32,
+}
+
+// reads little endian values from the weights file, failing if it ends early
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        let end = self.position + len;
+        let slice = self.bytes.get(self.position..end).ok_or("NNUE file is too short")?;
+        self.position = end;
+        Ok(slice)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i16s(&mut self, count: usize) -> Result<Vec<i16>, String> {
+        Ok(self.take(count * 2)?.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect())
+    }
+}
+
+impl Network {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Network, String> {
+        let mut reader = Reader { bytes, position: 0 };
+
+        if reader.take(4)? != MAGIC {
+            return Err("Not an NNUE file, it doesn't start with \"CBNN\"".to_string());
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(format!("Unsupported NNUE file version {}, expected {}", version, VERSION));
+        }
+        let hidden_size = reader.u32()? as usize;
+        if hidden_size == 0 || hidden_size > 4096 {
+            return Err(format!("Invalid NNUE accumulator size {}", hidden_size));
+        }
+
+        let network = Network {
+            hidden_size,
+            feature_weights: reader.i16s(NUM_FEATURES * hidden_size)?,
+            feature_biases: reader.i16s(hidden_size)?,
+            output_weights: reader.i16s(2 * hidden_size)?,
+            output_bias: reader.i32()?,
+        };
+
+        if reader.position != bytes.len() {
+            return Err(format!("NNUE file has {} unexpected bytes at the end", bytes.len() - reader.position));
+        }
+
+        Ok(network)
+    }
+
+    pub fn load(path: &str) -> Result<Network, String> {
+        let bytes = fs::read(path).map_err(|e| format!("Could not read NNUE file {}: {}", path, e))?;
+        Network::from_bytes(&bytes)
+    }
+
+    fn feature_weights(&self, feature: usize) -> &[i16] {
+        &self.feature_weights[feature * self.hidden_size..(feature + 1) * self.hidden_size]
+    }
+
+    // builds the accumulator of one side from scratch
+    fn refresh(&self, accumulator: &mut [i16], perspective: Player, king_sq: SQ, pieces: &[(SQ, Piece)]) {
+        accumulator.copy_from_slice(&self.feature_biases);
+
+        for &(sq, piece) in pieces {
+            if let Some(feature) = feature_index(perspective, king_sq, piece, sq) {
+                for (a, w) in accumulator.iter_mut().zip(self.feature_weights(feature)) {
+                    *a = a.wrapping_add(*w);
+                }
+            }
+        }
+    }
+
+    // returns the score of a position from the side to move's point of view
+    fn output(&self, us: &[i16], them: &[i16]) -> i32 {
+        let (us_weights, them_weights) = self.output_weights.split_at(self.hidden_size);
+        // a large accumulator can add up to more than an i32 holds before it is scaled down
+        let mut sum = 0i64;
+
+        for (a, w) in us.iter().zip(us_weights).chain(them.iter().zip(them_weights)) {
+            sum += (*a as i64).clamp(0, QA as i64) * *w as i64;
+        }
+
+        let score = (sum + self.output_bias as i64) * OUTPUT_SCALE as i64 / (QA * QB) as i64;
+        // kept below the mate scores, so the search never mistakes it for a mate
+        score.clamp(-CHECKMATE_SCORE as i64 + 1, CHECKMATE_SCORE as i64 - 1) as i32
+    }
+}
+
+// returns the input feature of a piece as seen by `perspective`, or None for kings. Squares are
+// flipped vertically for black so both sides see the board from their own end
+fn feature_index(perspective: Player, king_sq: SQ, piece: Piece, sq: SQ) -> Option<usize> {
+    let (player, piece_type) = piece.player_piece()?;
+    let type_index = match piece_type {
+        PieceType::P => 0,
+        PieceType::N => 1,
+        PieceType::B => 2,
+        PieceType::R => 3,
+        PieceType::Q => 4,
+        _ => return None,
+    };
+
+    let orient = |sq: SQ| if perspective == Player::White { sq.0 as usize } else { sq.0 as usize ^ 56 };
+    let piece_index = type_index * 2 + if player == perspective { 0 } else { 1 };
+
+    Some((orient(king_sq) * 10 + piece_index) * 64 + orient(sq))
+}
+
+/// Evaluates positions with a network, keeping a stack of accumulators for the moves the search
+/// has made so each move only has to update the features it changes
+pub struct NnueEvaluator {
+    network: Arc<Network>,
+    // accumulators for [white, black], one entry per ply. Entries past `ply` are reused buffers
+    stack: Vec<[Vec<i16>; 2]>,
+    ply: usize,
+}
+
+impl NnueEvaluator {
+    pub fn new(network: Arc<Network>, board: &Board) -> NnueEvaluator {
+        let pieces: Vec<(SQ, Piece)> = board.get_piece_locations().into_iter().collect();
+        let mut accumulators = [vec![0; network.hidden_size], vec![0; network.hidden_size]];
+
+        for player in [Player::White, Player::Black] {
+            network.refresh(&mut accumulators[player as usize], player, board.king_sq(player), &pieces);
+        }
+
+        NnueEvaluator { network, stack: vec![accumulators], ply: 0 }
+    }
+}
+
+impl Evaluator for NnueEvaluator {
+    fn make_move(&mut self, board: &Board, mv: BitMove) {
+        let changes = piece_changes(board, mv);
+
+        if self.stack.len() == self.ply + 1 {
+            let blank = vec![0; self.network.hidden_size];
+            self.stack.push([blank.clone(), blank]);
+        }
+        let (done, rest) = self.stack.split_at_mut(self.ply + 1);
+        let (parent, child) = (&done[self.ply], &mut rest[0]);
+        self.ply += 1;
+
+        for player in [Player::White, Player::Black] {
+            let accumulator = &mut child[player as usize];
+            let king = Piece::make_lossy(player, PieceType::K);
+
+            // every feature depends on the king square, so a king move means starting again
+            if let Some(king_move) = changes.iter().find(|c| c.piece == king && c.added) {
+                let pieces = pieces_after(board, &changes);
+                self.network.refresh(accumulator, player, king_move.square, &pieces);
+                continue;
+            }
+
+            accumulator.copy_from_slice(&parent[player as usize]);
+            let king_sq = board.king_sq(player);
+            for change in &changes {
+                if let Some(feature) = feature_index(player, king_sq, change.piece, change.square) {
+                    for (a, w) in accumulator.iter_mut().zip(self.network.feature_weights(feature)) {
+                        *a = if change.added { a.wrapping_add(*w) } else { a.wrapping_sub(*w) };
+                    }
+                }
+            }
+        }
+    }
+
+    fn unmake_move(&mut self) {
+        self.ply -= 1;
+    }
+
+    fn evaluate(&mut self, board: &Board) -> i32 {
+        let accumulators = &self.stack[self.ply];
+        let us = board.turn();
+        let score = self.network.output(&accumulators[us as usize], &accumulators[!us as usize]);
+
+        if us == Player::White { score } else { -score }
+    }
+}
+
+// returns the pieces on the board once the given changes have been made to it
+fn pieces_after(board: &Board, changes: &[PieceChange]) -> Vec<(SQ, Piece)> {
+    let mut pieces: Vec<(SQ, Piece)> = board.get_piece_locations().into_iter()
+        .filter(|&(sq, piece)| !changes.iter().any(|c| !c.added && c.square == sq && c.piece == piece))
+        .collect();
+    pieces.extend(changes.iter().filter(|c| c.added).map(|c| (c.square, c.piece)));
+    pieces
+}
+
+/// loads the network when RUST_BOT_EVALUATOR=nnue, from the file named by RUST_BOT_NNUE_FILE.
+/// Should be called once at startup
+pub fn init_network_from_env() -> Result<(), String> {
+    let network = match env::var("RUST_BOT_EVALUATOR").as_deref() {
+        Ok("nnue") => {
+            let path = env::var("RUST_BOT_NNUE_FILE").map_err(|_| "RUST_BOT_NNUE_FILE must be set when RUST_BOT_EVALUATOR=nnue")?;
+            Some(Arc::new(Network::load(&path)?))
+        }
+        Ok("handcrafted") | Ok("") | Err(_) => None,
+        Ok(other) => return Err(format!("Unknown evaluator \"{}\", expected \"handcrafted\" or \"nnue\"", other)),
+    };
+
+    NETWORK.set(network).map_err(|_| "The NNUE network has already been initialised".to_string())
+}
+
+/// returns the network to evaluate with, or None if the handcrafted evaluation is selected
+pub fn network() -> Option<Arc<Network>> {
+    NETWORK.get().cloned().flatten()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HIDDEN_SIZE: usize = 8;
+
+    // xorshift64
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    fn random_network(seed: u64) -> Network {
+        let mut state = seed;
+        let mut weights = |count: usize| -> Vec<i16> { (0..count).map(|_| (next_random(&mut state) % 129) as i16 - 64).collect() };
+        Network {
+            hidden_size: HIDDEN_SIZE,
+            feature_weights: weights(NUM_FEATURES * HIDDEN_SIZE),
+            feature_biases: weights(HIDDEN_SIZE),
+            output_weights: weights(2 * HIDDEN_SIZE),
+            output_bias: 25,
+        }
+    }
+
+    // writes a network in the weights file layout
+    fn to_bytes(network: &Network) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.extend((network.hidden_size as u32).to_le_bytes());
+        for weights in [&network.feature_weights, &network.feature_biases, &network.output_weights] {
+            bytes.extend(weights.iter().flat_map(|w| w.to_le_bytes()));
+        }
+        bytes.extend(network.output_bias.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn network_reads_back_what_was_written() {
+        let network = random_network(0x5eed);
+        let read = Network::from_bytes(&to_bytes(&network)).unwrap();
+        assert_eq!(read.hidden_size, network.hidden_size);
+        assert_eq!(read.feature_weights, network.feature_weights);
+        assert_eq!(read.feature_biases, network.feature_biases);
+        assert_eq!(read.output_weights, network.output_weights);
+        assert_eq!(read.output_bias, network.output_bias);
+    }
+
+    #[test]
+    fn network_file_must_be_complete_and_have_the_header() {
+        let bytes = to_bytes(&random_network(0x5eed));
+        assert!(Network::from_bytes(&bytes[..bytes.len() - 1]).err().unwrap().contains("too short"));
+        assert!(Network::from_bytes(&bytes[..10]).err().unwrap().contains("too short"));
+
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert!(Network::from_bytes(&extra).err().unwrap().contains("unexpected bytes"));
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert!(Network::from_bytes(&bad_magic).err().unwrap().contains("Not an NNUE file"));
+
+        let mut bad_version = bytes.clone();
+        bad_version[4] = 2;
+        assert!(Network::from_bytes(&bad_version).err().unwrap().contains("version"));
+
+        let mut bad_size = bytes;
+        bad_size[8..12].copy_from_slice(&0u32.to_le_bytes());
+        assert!(Network::from_bytes(&bad_size).err().unwrap().contains("accumulator size"));
+    }
+
+    #[test]
+    fn output_of_a_wide_network_does_not_overflow() {
+        // 2 * 256 inputs at QA with the largest weights add up to more than an i32 holds
+        let hidden_size = 256;
+        let network = Network {
+            hidden_size,
+            feature_weights: Vec::new(),
+            feature_biases: Vec::new(),
+            output_weights: vec![i16::MAX; 2 * hidden_size],
+            output_bias: 0,
+        };
+        let accumulator = vec![QA as i16; hidden_size];
+        assert_eq!(network.output(&accumulator, &accumulator), CHECKMATE_SCORE - 1);
+
+        let negative = Network { output_weights: vec![i16::MIN; 2 * hidden_size], ..network };
+        assert_eq!(negative.output(&accumulator, &accumulator), -CHECKMATE_SCORE + 1);
+    }
+
+    #[test]
+    fn incremental_updates_match_a_full_refresh() {
+        let network = Arc::new(random_network(0x5eed));
+        let mut state = 0x9e3779b97f4a7c15;
+
+        // the second position has castling, en passant and promotions available from the start
+        for fen in ["rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", "r3k2r/1P4P1/8/3pP3/8/8/1p4p1/R3K2R w KQkq d6 0 1"] {
+            let mut board = Board::from_fen(fen).unwrap();
+            let mut evaluator = NnueEvaluator::new(network.clone(), &board);
+
+            for ply in 0..200 {
+                let moves = board.generate_moves();
+                if moves.is_empty() {
+                    break;
+                }
+
+                // now and then take two moves back, so the stack is reused as it is in a search
+                if ply % 7 == 6 && board.ply() >= 2 {
+                    for _ in 0..2 {
+                        board.undo_move();
+                        evaluator.unmake_move();
+                    }
+                } else {
+                    let mv = moves[(next_random(&mut state) % moves.len() as u64) as usize];
+                    evaluator.make_move(&board, mv);
+                    board.apply_move(mv);
+                }
+
+                let mut refreshed = NnueEvaluator::new(network.clone(), &board);
+                assert_eq!(evaluator.stack[evaluator.ply], refreshed.stack[0], "{}", board.fen());
+                assert_eq!(evaluator.evaluate(&board), refreshed.evaluate(&board), "{}", board.fen());
+            }
+        }
+    }
+}
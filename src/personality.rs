@@ -0,0 +1,201 @@
+// Playing styles, so bots running the same engine can play recognisably differently. A personality
+// scales some of the evaluation weights and changes how the search treats draws and how much it
+// prunes. It is picked at startup with RUST_BOT_PERSONALITY, either as the name of a preset or as the
+// path to a JSON file in the format printed by the `personality` command.
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::sync::OnceLock;
+
+static PERSONALITY: OnceLock<Personality> = OnceLock::new();
+
+// lets a test play with another personality on its own thread, without changing it for the others
+#[cfg(test)]
+thread_local! {
+    static TEST_PERSONALITY: std::cell::Cell<Option<&'static Personality>> = const { std::cell::Cell::new(None) };
+}
+
+/// Adjustments to how the engine plays. Weights are percentages of the normal weight
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Personality {
+    pub name: String,
+    // attacks on the squares around a king
+    pub king_attack: i32,
+    // piece values
+    pub material: i32,
+    // doubled, isolated and passed pawns
+    pub pawn_structure: i32,
+    // how many centipawns the bot thinks a draw is worse than an equal position for itself, so a
+    // positive contempt avoids draws and a negative one looks for them
+    pub contempt: i32,
+    // futility margin in centipawns: one ply from the horizon, quiet moves are skipped if the position
+    // is worse than the best score found so far by more than this. 0 turns it off, and smaller margins
+    // prune more
+    pub futility_margin: i32,
+}
+
+impl Default for Personality {
+    fn default() -> Self {
+        Personality {
+            name: "default".to_string(),
+            king_attack: 100,
+            material: 100,
+            pawn_structure: 100,
+            contempt: 0,
+            futility_margin: 0,
+        }
+    }
+}
+
+impl Personality {
+    pub const PRESETS: [&'static str; 4] = ["default", "aggressive", "solid", "materialistic"];
+
+    /// returns the preset with the given name
+    pub fn preset(name: &str) -> Option<Personality> {
+        let base = Personality { name: name.to_string(), ..Default::default() };
+        match name {
+            "default" => Some(base),
+            // goes for the king, doesn't mind giving up material or weakening pawns, avoids draws and
+            // searches fewer quiet moves to look deeper into the lines that matter
+            "aggressive" => Some(Personality { king_attack: 150, material: 90, pawn_structure: 80, contempt: 30, futility_margin: 200, ..base }),
+            // cares about its pawns, is wary of king attacks and never prunes
+            "solid" => Some(Personality { king_attack: 80, pawn_structure: 150, contempt: -10, ..base }),
+            // grabs material and trades into endgames it is ahead in
+            "materialistic" => Some(Personality { king_attack: 80, material: 125, pawn_structure: 90, contempt: 10, futility_margin: 400, ..base }),
+            _ => None,
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Personality, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Could not read personality file {}: {}", path, e))?;
+        let personality: Personality = serde_json::from_str(&contents).map_err(|e| format!("Invalid personality file {}: {}", path, e))?;
+
+        if personality.material <= 0 {
+            return Err(format!("Material weight must be positive, got {}", personality.material));
+        }
+        if personality.futility_margin < 0 {
+            return Err(format!("Futility margin can't be negative, got {}", personality.futility_margin));
+        }
+        Ok(personality)
+    }
+
+    /// returns the value of a piece, scaled by the personality. Applied when evaluating rather than
+    /// to the loaded parameters, so `params` and `tune` see the weights as they are in the file
+    pub fn scale_material(&self, value: i32) -> i32 {
+        (value * self.material / 100).max(1)
+    }
+
+    /// returns the score of a king attack term, scaled by the personality
+    pub fn scale_king_attack(&self, score: i32) -> i32 {
+        score * self.king_attack / 100
+    }
+
+    /// returns the score of a pawn structure term, scaled by the personality
+    pub fn scale_pawn_structure(&self, score: i32) -> i32 {
+        score * self.pawn_structure / 100
+    }
+
+    pub fn to_pretty_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// sets the personality named by RUST_BOT_PERSONALITY, a preset name or a JSON file. Should be called
+/// once at startup, before anything is evaluated
+pub fn init_personality_from_env() -> Result<(), String> {
+    let personality = match env::var("RUST_BOT_PERSONALITY") {
+        Ok(name) if !name.is_empty() => match Personality::preset(&name) {
+            Some(personality) => personality,
+            None if name.ends_with(".json") => Personality::load(&name)?,
+            None => return Err(format!("Unknown personality \"{}\", expected a JSON file or one of {:?}", name, Personality::PRESETS)),
+        },
+        _ => Personality::default(),
+    };
+
+    PERSONALITY.set(personality).map_err(|_| "Personality has already been initialised".to_string())
+}
+
+/// returns the personality the engine is playing with
+pub fn personality() -> &'static Personality {
+    #[cfg(test)]
+    if let Some(personality) = TEST_PERSONALITY.with(|test_personality| test_personality.get()) {
+        return personality;
+    }
+    PERSONALITY.get_or_init(Personality::default)
+}
+
+/// runs `f` with the personality in place of the engine's, on this thread only
+#[cfg(test)]
+pub fn with_personality<T>(personality: Personality, f: impl FnOnce() -> T) -> T {
+    let previous = TEST_PERSONALITY.with(|test_personality| test_personality.replace(Some(Box::leak(Box::new(personality)))));
+    let result = f();
+    TEST_PERSONALITY.with(|test_personality| test_personality.set(previous));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluation::{evaluate, trace_evaluation, EvalTerm, HandcraftedEvaluator, Evaluator};
+    use pleco::Board;
+
+    fn load_json(name: &str, contents: &str) -> Result<Personality, String> {
+        let path = std::env::temp_dir().join(format!("personality-{}-{}.json", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let personality = Personality::load(path.to_str().unwrap());
+        fs::remove_file(&path).ok();
+        personality
+    }
+
+    #[test]
+    fn presets_are_found_by_name() {
+        for name in Personality::PRESETS {
+            assert_eq!(Personality::preset(name).unwrap().name, name);
+        }
+        assert_eq!(Personality::preset("default"), Some(Personality::default()));
+        assert_eq!(Personality::preset("aggressive").unwrap().contempt, 30);
+        assert_eq!(Personality::preset("reckless"), None);
+    }
+
+    #[test]
+    fn personality_files_are_checked() {
+        let loaded = load_json("partial", r#"{"name": "sharp", "king_attack": 120}"#).unwrap();
+        assert_eq!(loaded, Personality { name: "sharp".to_string(), king_attack: 120, ..Default::default() });
+
+        let aggressive = Personality::preset("aggressive").unwrap();
+        assert_eq!(load_json("written", &aggressive.to_pretty_json()).unwrap(), aggressive);
+
+        assert!(load_json("material", r#"{"material": 0}"#).unwrap_err().contains("Material weight"));
+        assert!(load_json("futility", r#"{"futility_margin": -50}"#).unwrap_err().contains("Futility margin"));
+        assert!(load_json("unknown", r#"{"bravery": 100}"#).unwrap_err().contains("unknown field"));
+        assert!(load_json("type", r#"{"contempt": "high"}"#).unwrap_err().contains("Invalid personality file"));
+    }
+
+    #[test]
+    fn material_weight_scales_the_piece_values() {
+        // white is a knight up
+        let board = Board::from_fen("r1bqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1").unwrap();
+        let knight = trace_evaluation(&board).term(EvalTerm::Material);
+        let double = Personality { material: 200, ..Default::default() };
+
+        with_personality(double.clone(), || assert_eq!(trace_evaluation(&board).term(EvalTerm::Material), 2 * knight));
+        let gained = with_personality(double.clone(), || evaluate(&board)) - evaluate(&board);
+        assert_eq!(gained, knight);
+        // the evaluator the search uses keeps its material up to date with the same scale
+        with_personality(double, || assert_eq!(HandcraftedEvaluator::new(&board).evaluate(&board), evaluate(&board)));
+    }
+
+    #[test]
+    fn king_attack_weight_scales_the_attacks_on_the_king() {
+        // the queen attacks f7, next to black's king, and nothing attacks white's
+        let board = Board::from_fen("6k1/5ppp/8/8/8/8/5Q2/6K1 w - - 0 1").unwrap();
+        let king_safety = |king_attack| {
+            with_personality(Personality { king_attack, ..Default::default() }, || trace_evaluation(&board).term(EvalTerm::KingSafety))
+        };
+
+        let attack = king_safety(100) - king_safety(0);
+        assert!(attack > 0);
+        assert_eq!(king_safety(200) - king_safety(100), attack);
+    }
+}
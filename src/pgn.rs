@@ -0,0 +1,350 @@
+// Reads games from PGN files: the tag pairs and the main line of moves. Comments, variations and
+// annotations are skipped, and moves in standard algebraic notation are matched against the legal
+// moves of the position, so a game with a move that isn't legal stops at that move.
+use pleco::{BitMove, Board, PieceType};
+use std::collections::HashMap;
+
+/// How a game ended, from its Result tag or the end of its moves
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GameResult {
+    WhiteWin,
+    BlackWin,
+    Draw,
+    Unfinished,
+}
+
+impl GameResult {
+    pub fn parse(result: &str) -> GameResult {
+        match result {
+            "1-0" => GameResult::WhiteWin,
+            "0-1" => GameResult::BlackWin,
+            "1/2-1/2" => GameResult::Draw,
+            _ => GameResult::Unfinished,
+        }
+    }
+}
+
+/// A game as it is written in a PGN file, before its moves are played out
+pub struct PgnGame {
+    pub tags: HashMap<String, String>,
+    // the moves of the main line in standard algebraic notation
+    pub moves: Vec<String>,
+}
+
+impl PgnGame {
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.get(name).map(String::as_str)
+    }
+
+    pub fn result(&self) -> GameResult {
+        GameResult::parse(self.tag("Result").unwrap_or("*"))
+    }
+
+    // returns the player's rating, if the game has one
+    pub fn rating(&self, tag: &str) -> Option<u32> {
+        self.tag(tag)?.parse().ok()
+    }
+
+    /// returns the position the game starts from, which is the start position unless it has a FEN tag
+    pub fn start_position(&self) -> Result<Board, String> {
+        match self.tag("FEN") {
+            Some(fen) => Board::from_fen(fen).map_err(|e| format!("Invalid FEN tag {}: {:?}", fen, e)),
+            None => Ok(Board::start_pos()),
+        }
+    }
+
+    /// plays the game's moves from its start position, and returns them. An illegal or unreadable
+    /// move is an error
+    pub fn play(&self) -> Result<Vec<BitMove>, String> {
+        let mut board = self.start_position()?;
+        let mut played = Vec::with_capacity(self.moves.len());
+        for san in self.moves.iter() {
+            let mv = parse_san(&board, san).ok_or_else(|| format!("Illegal move {} in {}", san, board.fen()))?;
+            board.apply_move(mv);
+            played.push(mv);
+        }
+        Ok(played)
+    }
+}
+
+/// splits the contents of a PGN file into games
+pub fn parse_games(contents: &str) -> Vec<PgnGame> {
+    let mut games = Vec::new();
+    let mut tags = HashMap::new();
+    let mut movetext = String::new();
+
+    for line in contents.lines() {
+        let line = line.trim();
+        // lines starting with % are escaped and ignored
+        if line.starts_with('%') {
+            continue;
+        }
+        if line.starts_with('[') {
+            // a tag after some moves starts the next game
+            if !movetext.trim().is_empty() {
+                games.push(PgnGame { tags: std::mem::take(&mut tags), moves: parse_movetext(&movetext) });
+                movetext.clear();
+            }
+            if let Some((name, value)) = parse_tag(line) {
+                tags.insert(name, value);
+            }
+        } else {
+            movetext.push_str(line);
+            movetext.push('\n');
+        }
+    }
+    if !tags.is_empty() || !movetext.trim().is_empty() {
+        games.push(PgnGame { tags, moves: parse_movetext(&movetext) });
+    }
+
+    games
+}
+
+// parses a tag pair like `[White "Carlsen, Magnus"]`
+fn parse_tag(line: &str) -> Option<(String, String)> {
+    let inner = line.strip_prefix('[')?.strip_suffix(']')?.trim();
+    let (name, value) = inner.split_once(char::is_whitespace)?;
+    let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+    Some((name.to_string(), value.replace("\\\"", "\"").replace("\\\\", "\\")))
+}
+
+// returns the moves of the main line, skipping move numbers, comments, variations, annotations and
+// the result
+fn parse_movetext(movetext: &str) -> Vec<String> {
+    let mut moves = Vec::new();
+    let mut chars = movetext.chars().peekable();
+    let mut variation_depth = 0;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        break;
+                    }
+                }
+            }
+            ';' => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '(' => variation_depth += 1,
+            ')' => variation_depth -= 1,
+            c if c.is_whitespace() => {}
+            _ => {
+                let mut token = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || "{}();".contains(next) {
+                        break;
+                    }
+                    token.push(next);
+                    chars.next();
+                }
+                if variation_depth > 0 {
+                    continue;
+                }
+
+                // move numbers can be written straight in front of the move, like `1.e4` or `3...Nf6`
+                let token = token.rsplit('.').next().unwrap_or_default();
+                let token = token.trim_end_matches(['!', '?', '+', '#']);
+                if token.is_empty() || token.starts_with('$') || ["1-0", "0-1", "1/2-1/2", "*"].contains(&token) {
+                    continue;
+                }
+                moves.push(token.to_string());
+            }
+        }
+    }
+
+    moves
+}
+
+/// returns the legal move a move in standard algebraic notation stands for, like `Nbd7`, `exd5`,
+/// `e8=Q` or `O-O`. Check and annotation marks are ignored
+pub fn parse_san(board: &Board, san: &str) -> Option<BitMove> {
+    let san = san.trim_end_matches(['!', '?', '+', '#']);
+    let legal_moves = board.generate_moves();
+
+    // castling is written with letter O, but some files use zeros
+    let castle = match san {
+        "O-O" | "0-0" => Some(true),
+        "O-O-O" | "0-0-0" => Some(false),
+        _ => None,
+    };
+    if let Some(king_side) = castle {
+        // pleco stores castling as the king taking its own rook
+        return legal_moves.iter().copied().find(|mv| mv.is_castle() && (mv.get_dest().file_idx_of_sq() > mv.get_src().file_idx_of_sq()) == king_side);
+    }
+
+    let (san, promotion) = match san.split_once('=') {
+        Some((san, piece)) => (san, Some(parse_piece(piece.chars().next()?)?)),
+        // some files leave out the =, like `e8Q`
+        None => match san.chars().last().and_then(parse_piece).filter(|_| san.len() > 2) {
+            Some(piece) => (&san[..san.len() - 1], Some(piece)),
+            None => (san, None),
+        },
+    };
+
+    let mut chars: Vec<char> = san.chars().filter(|&c| c != 'x' && c != '-').collect();
+    let piece = match chars.first().copied().and_then(parse_piece) {
+        Some(piece) => {
+            chars.remove(0);
+            piece
+        }
+        None => PieceType::P,
+    };
+    if chars.len() < 2 {
+        return None;
+    }
+
+    // the last two characters are the destination, anything before them says which piece moves
+    let destination: String = chars[chars.len() - 2..].iter().collect();
+    let hints = &chars[..chars.len() - 2];
+    let from_file = hints.iter().find(|c| ('a'..='h').contains(*c)).map(|&c| c as u8 - b'a');
+    let from_rank = hints.iter().find(|c| ('1'..='8').contains(*c)).map(|&c| c as u8 - b'1');
+
+    let mut matches = legal_moves.iter().copied().filter(|mv| {
+        !mv.is_castle()
+            && mv.get_dest().to_string() == destination
+            && board.piece_at_sq(mv.get_src()).type_of() == piece
+            && from_file.is_none_or(|file| mv.get_src().file_idx_of_sq() == file)
+            && from_rank.is_none_or(|rank| mv.get_src().rank_idx_of_sq() == rank)
+            && match promotion {
+                Some(promotion) => mv.is_promo() && mv.promo_piece() == promotion,
+                None => !mv.is_promo(),
+            }
+    });
+
+    let mv = matches.next()?;
+    // an ambiguous move isn't any of them
+    if matches.next().is_some() {
+        return None;
+    }
+    Some(mv)
+}
+
+fn parse_piece(c: char) -> Option<PieceType> {
+    match c {
+        'N' => Some(PieceType::N),
+        'B' => Some(PieceType::B),
+        'R' => Some(PieceType::R),
+        'Q' => Some(PieceType::Q),
+        'K' => Some(PieceType::K),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the move a SAN move stands for in the position, in UCI notation
+    fn san(fen: &str, san: &str) -> Option<String> {
+        parse_san(&Board::from_fen(fen).unwrap(), san).map(|mv| mv.to_string())
+    }
+
+    #[test]
+    fn reads_piece_moves_and_captures() {
+        let start = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+        assert_eq!(san(start, "e4").as_deref(), Some("e2e4"));
+        assert_eq!(san(start, "Nf3").as_deref(), Some("g1f3"));
+        assert_eq!(san(start, "e5"), None);
+        assert_eq!(san(start, "Nd2"), None);
+
+        let exchange = "rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq d6 0 2";
+        assert_eq!(san(exchange, "exd5").as_deref(), Some("e4d5"));
+        assert_eq!(san(exchange, "e4xd5").as_deref(), Some("e4d5"));
+    }
+
+    #[test]
+    fn disambiguates_by_file_rank_or_both() {
+        // knights on b1 and f3 can both go to d2
+        let knights = "4k3/8/8/8/8/5N2/8/1N2K3 w - - 0 1";
+        assert_eq!(san(knights, "Nbd2").as_deref(), Some("b1d2"));
+        assert_eq!(san(knights, "Nfd2").as_deref(), Some("f3d2"));
+        assert_eq!(san(knights, "Nd2"), None);
+
+        // rooks on a1 and a5 can both go to a3
+        let rooks = "4k3/8/8/R7/8/8/8/R3K3 w - - 0 1";
+        assert_eq!(san(rooks, "R1a3").as_deref(), Some("a1a3"));
+        assert_eq!(san(rooks, "R5a3").as_deref(), Some("a5a3"));
+        assert_eq!(san(rooks, "Ra3"), None);
+
+        // queens on e4, h4 and h1 can all go to e1, so only the square says which
+        let queens = "k7/8/8/8/4Q2Q/8/8/K6Q w - - 0 1";
+        assert_eq!(san(queens, "Qh4e1").as_deref(), Some("h4e1"));
+        assert_eq!(san(queens, "Qh4xe1").as_deref(), Some("h4e1"));
+        assert_eq!(san(queens, "Qhe1"), None);
+    }
+
+    #[test]
+    fn reads_castling() {
+        let both_ways = "r3k2r/pppppppp/8/8/8/8/PPPPPPPP/R3K2R w KQkq - 0 1";
+        let king_side = parse_san(&Board::from_fen(both_ways).unwrap(), "O-O").unwrap();
+        let queen_side = parse_san(&Board::from_fen(both_ways).unwrap(), "O-O-O").unwrap();
+        assert!(king_side.is_castle() && queen_side.is_castle());
+        assert_ne!(king_side, queen_side);
+        assert_eq!(parse_san(&Board::from_fen(both_ways).unwrap(), "0-0"), Some(king_side));
+        assert_eq!(parse_san(&Board::from_fen(both_ways).unwrap(), "0-0-0"), Some(queen_side));
+
+        // black castles the same way, and without the right to castle it can't
+        let black = "r3k2r/pppppppp/8/8/8/8/PPPPPPPP/R3K2R b Kk - 0 1";
+        assert!(parse_san(&Board::from_fen(black).unwrap(), "O-O").unwrap().is_castle());
+        assert_eq!(san(black, "O-O-O"), None);
+    }
+
+    #[test]
+    fn reads_promotions() {
+        let promotion = "1n2k3/P7/8/8/8/8/8/4K3 w - - 0 1";
+        assert_eq!(san(promotion, "a8=Q").as_deref(), Some("a7a8q"));
+        assert_eq!(san(promotion, "a8Q").as_deref(), Some("a7a8q"));
+        assert_eq!(san(promotion, "a8=N").as_deref(), Some("a7a8n"));
+        assert_eq!(san(promotion, "axb8=R").as_deref(), Some("a7b8r"));
+        // a pawn on the last rank has to say what it becomes
+        assert_eq!(san(promotion, "a8"), None);
+    }
+
+    #[test]
+    fn ignores_check_marks_and_annotations() {
+        let fen = "6k1/5ppp/8/8/8/8/5PPP/R5K1 w - - 0 1";
+        for written in ["Ra8", "Ra8#", "Ra8+", "Ra8!", "Ra8#!!", "Ra8+?!"] {
+            assert_eq!(san(fen, written).as_deref(), Some("a1a8"), "{}", written);
+        }
+    }
+
+    #[test]
+    fn reads_the_main_line_of_a_game() {
+        let games = parse_games(r#"
+[Event "Test"]
+[White "Someone \"Quoted\""]
+[Result "1-0"]
+
+1. e4 {best by test} e5 (1... c5 2. Nf3 (2. c3) d6) 2.Nf3 $1 Nc6 3. Bb5!? ; a comment to the end of the line
+3...a6 4. Ba4 1-0
+
+[Event "Second"]
+[FEN "4k3/8/8/8/8/8/8/4K2R w K - 0 1"]
+
+1. O-O+ *
+"#);
+
+        assert_eq!(games.len(), 2);
+        assert_eq!(games[0].tag("White"), Some("Someone \"Quoted\""));
+        assert_eq!(games[0].result(), GameResult::WhiteWin);
+        assert_eq!(games[0].moves, ["e4", "e5", "Nf3", "Nc6", "Bb5", "a6", "Ba4"]);
+        let played: Vec<String> = games[0].play().unwrap().iter().map(|mv| mv.to_string()).collect();
+        assert_eq!(played, ["e2e4", "e7e5", "g1f3", "b8c6", "f1b5", "a7a6", "b5a4"]);
+
+        assert_eq!(games[1].result(), GameResult::Unfinished);
+        assert_eq!(games[1].moves, ["O-O"]);
+        assert!(games[1].play().unwrap()[0].is_castle());
+    }
+
+    #[test]
+    fn illegal_move_is_an_error() {
+        let games = parse_games("1. e4 e5 2. Ke3 *");
+        assert!(games[0].play().unwrap_err().contains("Ke3"));
+    }
+}
@@ -0,0 +1,478 @@
+// Endgame tables made by this crate, for a lone king against a few pieces. `generate_tables` solves
+// every position of each endgame by retrograde analysis: starting from the checkmates it walks
+// backwards through the moves that lead to them one ply at a time, so each position gets the number
+// of plies to mate with best play. The tables are written one per file and loaded at startup from
+// the directory named by RUST_BOT_ENDGAME_TABLES, then probed by the endgame evaluation.
+//
+// File layout: magic "CBTB", format version (u32, little endian, = 1), then one signed byte per
+// position. A positive value is the number of plies until the side to move mates, a negative value
+// -(n + 1) means the side to move is mated in n plies, and 0 is a draw (or an impossible position).
+// Positions are indexed by side to move (white first), then the squares of the strong king, the lone
+// king and the other pieces in the order of the table's name, 64 squares each. The strong side is
+// always white and its king is only stored on the squares in `king_squares`, every other position is
+// mirrored onto one of those.
+use pleco::{Board,Player,PieceType,SQ,BitBoard};
+use pleco::helper::prelude::{king_moves, knight_moves, bishop_moves, rook_moves, queen_moves, pawn_attacks_from, init_statics};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+const MAGIC: &[u8; 4] = b"CBTB";
+const VERSION: u32 = 1;
+const SUFFIX: &str = ".cbtb";
+
+// the endgames that can be generated, with the strong side's pieces besides its king. Promotions in
+// KPK look up KQK and KRK, so they come first
+const ENDGAMES: [(&str, &[PieceType]); 4] = [
+    ("KQK", &[PieceType::Q]),
+    ("KRK", &[PieceType::R]),
+    ("KPK", &[PieceType::P]),
+    ("KBNK", &[PieceType::B, PieceType::N]),
+];
+
+const WHITE_TO_MOVE: usize = 0;
+const BLACK_TO_MOVE: usize = 1;
+
+static TABLES: OnceLock<Vec<EndgameTable>> = OnceLock::new();
+
+/// The result of a table position for the side to move, with the number of plies until mate
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TableResult {
+    Win(u8),
+    Loss(u8),
+    Draw,
+}
+
+/// A solved endgame
+pub struct EndgameTable {
+    name: String,
+    pieces: Vec<PieceType>,
+    values: Vec<i8>,
+}
+
+// the squares the strong king is stored on: the a1-d1-d4 triangle without pawns, as the board can be
+// mirrored and turned, or the a-d files with pawns, as it can only be mirrored left to right
+fn king_squares(has_pawns: bool) -> Vec<usize> {
+    (0..64).filter(|&sq| {
+        let (file, rank) = (sq % 8, sq / 8);
+        if has_pawns { file <= 3 } else { file <= 3 && rank <= file }
+    }).collect()
+}
+
+impl EndgameTable {
+    fn has_pawns(&self) -> bool {
+        self.pieces.contains(&PieceType::P)
+    }
+
+    // number of positions for each side to move and strong king square
+    fn block_size(&self) -> usize {
+        64usize.pow(self.pieces.len() as u32 + 1)
+    }
+
+    fn expected_len(&self) -> usize {
+        2 * king_squares(self.has_pawns()).len() * self.block_size()
+    }
+
+    // returns the stored value of a position given as squares in table order, mirroring it so the
+    // strong king is on one of the stored squares
+    fn value(&self, stm: usize, squares: &[usize]) -> i8 {
+        let mut squares = squares.to_vec();
+        let king = squares[0];
+        if king % 8 > 3 {
+            squares.iter_mut().for_each(|sq| *sq ^= 7);
+        }
+        if !self.has_pawns() {
+            if squares[0] / 8 > 3 {
+                squares.iter_mut().for_each(|sq| *sq ^= 56);
+            }
+            if squares[0] / 8 > squares[0] % 8 {
+                squares.iter_mut().for_each(|sq| *sq = ((*sq >> 3) | (*sq << 3)) & 63);
+            }
+        }
+
+        let king_squares = king_squares(self.has_pawns());
+        let king_index = king_squares.iter().position(|&sq| sq == squares[0]).unwrap();
+        let rest = squares[1..].iter().fold(0, |index, &sq| index * 64 + sq);
+
+        self.values[(stm * king_squares.len() + king_index) * self.block_size() + rest]
+    }
+
+    fn from_bytes(name: &str, bytes: &[u8]) -> Result<EndgameTable, String> {
+        let Some(&(_, pieces)) = ENDGAMES.iter().find(|(endgame, _)| *endgame == name) else {
+            return Err(format!("Unknown endgame table {}", name));
+        };
+        if bytes.len() < 8 || &bytes[..4] != MAGIC {
+            return Err(format!("{} is not an endgame table file, it doesn't start with \"CBTB\"", name));
+        }
+        let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        if version != VERSION {
+            return Err(format!("Unsupported endgame table version {} in {}, expected {}", version, name, VERSION));
+        }
+
+        let table = EndgameTable {
+            name: name.to_string(),
+            pieces: pieces.to_vec(),
+            values: bytes[8..].iter().map(|&b| b as i8).collect(),
+        };
+        if table.values.len() != table.expected_len() {
+            return Err(format!("{} has {} positions, expected {}", name, table.values.len(), table.expected_len()));
+        }
+        Ok(table)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.extend(self.values.iter().map(|&v| v as u8));
+        bytes
+    }
+}
+
+// the pieces of a position, as squares in table order
+struct Position<'a> {
+    pieces: &'a [PieceType],
+    squares: Vec<usize>,
+}
+
+impl Position<'_> {
+    fn occupied(&self) -> BitBoard {
+        self.squares.iter().fold(BitBoard(0), |bb, &sq| bb | SQ(sq as u8).to_bb())
+    }
+
+    // returns true if the strong side attacks `target`, ignoring the piece on `skip`
+    fn white_attacks(&self, target: usize, occupied: BitBoard, skip: usize) -> bool {
+        let target = SQ(target as u8).to_bb();
+        if (king_moves(SQ(self.squares[0] as u8)) & target).is_not_empty() {
+            return true;
+        }
+
+        self.pieces.iter().zip(&self.squares[2..]).filter(|&(_, &sq)| sq != skip).any(|(&piece, &sq)| {
+            let sq = SQ(sq as u8);
+            let attacks = match piece {
+                PieceType::P => pawn_attacks_from(sq, Player::White),
+                PieceType::N => knight_moves(sq),
+                PieceType::B => bishop_moves(occupied, sq),
+                PieceType::R => rook_moves(occupied, sq),
+                _ => queen_moves(occupied, sq),
+            };
+            (attacks & target).is_not_empty()
+        })
+    }
+
+    fn is_legal(&self, stm: usize) -> bool {
+        let occupied = self.occupied();
+        if occupied.count_bits() as usize != self.squares.len() {
+            return false;
+        }
+        let pawn_on_back_rank = self.pieces.iter().zip(&self.squares[2..]).any(|(&piece, &sq)| piece == PieceType::P && !(8..56).contains(&sq));
+        let kings_touch = (king_moves(SQ(self.squares[0] as u8)) & SQ(self.squares[1] as u8).to_bb()).is_not_empty();
+
+        // the side that isn't to move can't be in check
+        !pawn_on_back_rank && !kings_touch && (stm == BLACK_TO_MOVE || !self.white_attacks(self.squares[1], occupied, 64))
+    }
+
+    // returns the number of legal moves of the lone king and whether it is in check
+    fn black_moves(&self) -> (u8, bool) {
+        let occupied = self.occupied();
+        let king = self.squares[1];
+        let without_king = occupied & !SQ(king as u8).to_bb();
+
+        let moves = king_moves(SQ(king as u8)).into_iter()
+            .filter(|&to| !self.white_attacks(to.0 as usize, without_king, to.0 as usize))
+            .count();
+
+        (moves as u8, self.white_attacks(king, occupied, 64))
+    }
+
+    // returns the squares the strong side's piece `i` could have moved from to reach this position
+    fn white_origins(&self, i: usize) -> Vec<usize> {
+        let occupied = self.occupied();
+        let sq = SQ(self.squares[i] as u8);
+
+        let origins = if i == 0 {
+            king_moves(sq)
+        }
+        else {
+            match self.pieces[i - 2] {
+                PieceType::P => {
+                    let mut origins = BitBoard(0);
+                    let behind = sq.0 as usize - 8;
+                    if sq.0 >= 16 && (occupied & SQ(behind as u8).to_bb()).is_empty() {
+                        origins |= SQ(behind as u8).to_bb();
+                        // double pushes end on the fourth rank
+                        if sq.0 / 8 == 3 && (occupied & SQ(behind as u8 - 8).to_bb()).is_empty() {
+                            origins |= SQ(behind as u8 - 8).to_bb();
+                        }
+                    }
+                    origins
+                }
+                PieceType::N => knight_moves(sq),
+                PieceType::B => bishop_moves(occupied, sq),
+                PieceType::R => rook_moves(occupied, sq),
+                _ => queen_moves(occupied, sq),
+            }
+        };
+
+        (origins & !occupied).into_iter().map(|sq| sq.0 as usize).collect()
+    }
+}
+
+// turns an index of the full table used during generation into the squares of the position
+fn decode(index: usize, num_squares: usize) -> Vec<usize> {
+    (0..num_squares).rev().map(|i| (index >> (6 * i)) & 63).collect()
+}
+
+fn encode(squares: &[usize]) -> usize {
+    squares.iter().fold(0, |index, &sq| (index << 6) | sq)
+}
+
+// solves an endgame, given the tables already generated for the endgames a promotion can lead to
+fn generate(name: &str, pieces: &[PieceType], solved: &HashMap<&str, EndgameTable>) -> EndgameTable {
+    let num_squares = pieces.len() + 2;
+    let size = 1 << (6 * num_squares);
+    // values for white to move then black to move, in the same encoding as table files
+    let mut values = vec![0i8; 2 * size];
+    let mut moves_left = vec![0u8; size];
+    let mut frontier = Vec::new();
+
+    // count the lone king's moves, and start from the positions where it is checkmated
+    for index in 0..size {
+        let position = Position { pieces, squares: decode(index, num_squares) };
+        if !position.is_legal(BLACK_TO_MOVE) {
+            continue;
+        }
+        let (moves, in_check) = position.black_moves();
+        moves_left[index] = moves;
+        if moves == 0 && in_check {
+            values[size + index] = -1;
+            frontier.push(index);
+        }
+    }
+
+    // pawns can promote into a smaller table, which gives a win in one more ply than it has
+    let mut promotion_wins: Vec<(u8, usize)> = Vec::new();
+    if let Some(pawn) = pieces.iter().position(|&piece| piece == PieceType::P) {
+        for index in 0..size {
+            let squares = decode(index, num_squares);
+            let position = Position { pieces, squares: squares.clone() };
+            if squares[pawn + 2] / 8 != 6 || !position.is_legal(WHITE_TO_MOVE) || (position.occupied() & SQ(squares[pawn + 2] as u8 + 8).to_bb()).is_not_empty() {
+                continue;
+            }
+
+            let mut promoted = squares.clone();
+            promoted[pawn + 2] += 8;
+            let fastest = ["KQK", "KRK"].iter()
+                .filter_map(|table| match solved[table].value(BLACK_TO_MOVE, &promoted) {
+                    value if value < 0 => Some((-value - 1) as u8 + 1),
+                    _ => None,
+                })
+                .min();
+            if let Some(plies) = fastest {
+                promotion_wins.push((plies, index));
+            }
+        }
+    }
+
+    let mut ply: u8 = 0;
+    while !frontier.is_empty() || promotion_wins.iter().any(|&(plies, _)| plies > ply) {
+        ply += 1;
+        let mut next_frontier = Vec::new();
+
+        if ply % 2 == 1 {
+            // white wins in `ply` if it has a move to a position black loses in `ply - 1`
+            for &index in &frontier {
+                let position = Position { pieces, squares: decode(index, num_squares) };
+                for i in (0..num_squares).filter(|&i| i != 1) {
+                    for origin in position.white_origins(i) {
+                        let mut squares = position.squares.clone();
+                        squares[i] = origin;
+                        let previous = encode(&squares);
+                        if values[previous] == 0 && (Position { pieces, squares }).is_legal(WHITE_TO_MOVE) {
+                            values[previous] = ply as i8;
+                            next_frontier.push(previous);
+                        }
+                    }
+                }
+            }
+            for &(plies, index) in &promotion_wins {
+                if plies == ply && values[index] == 0 {
+                    values[index] = ply as i8;
+                    next_frontier.push(index);
+                }
+            }
+        }
+        else {
+            // black loses in `ply` once every one of its moves leads to a position white wins
+            for &index in &frontier {
+                let position = Position { pieces, squares: decode(index, num_squares) };
+                for origin in king_moves(SQ(position.squares[1] as u8)).into_iter().map(|sq| sq.0 as usize) {
+                    let mut squares = position.squares.clone();
+                    squares[1] = origin;
+                    let previous = encode(&squares);
+                    if values[size + previous] != 0 || moves_left[previous] == 0 || !(Position { pieces, squares }).is_legal(BLACK_TO_MOVE) {
+                        continue;
+                    }
+                    moves_left[previous] -= 1;
+                    if moves_left[previous] == 0 {
+                        values[size + previous] = -(ply as i8) - 1;
+                        next_frontier.push(previous);
+                    }
+                }
+            }
+        }
+
+        frontier = next_frontier;
+    }
+
+    // keep only the positions with the strong king on the stored squares
+    let has_pawns = pieces.contains(&PieceType::P);
+    let block_size = size / 64;
+    let mut stored = Vec::new();
+    for stm in [WHITE_TO_MOVE, BLACK_TO_MOVE] {
+        for king in king_squares(has_pawns) {
+            let start = stm * size + king * block_size;
+            stored.extend_from_slice(&values[start..start + block_size]);
+        }
+    }
+
+    EndgameTable { name: name.to_string(), pieces: pieces.to_vec(), values: stored }
+}
+
+/// generates every table and writes them to `output_dir`
+pub fn generate_tables(output_dir: &str) -> Result<(), String> {
+    // the attack tables are normally set up when the first board is made
+    init_statics();
+    fs::create_dir_all(output_dir).map_err(|e| format!("Could not create {}: {}", output_dir, e))?;
+    let mut solved = HashMap::new();
+
+    for (name, pieces) in ENDGAMES {
+        let start_time = Instant::now();
+        let table = generate(name, pieces, &solved);
+
+        let path = Path::new(output_dir).join(format!("{}{}", name, SUFFIX));
+        fs::write(&path, table.to_bytes()).map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+
+        let wins = table.values.iter().filter(|&&v| v > 0).count();
+        let longest_mate = table.values.iter().copied().max().unwrap_or(0);
+        println!("{}: {} positions, {} won for the side to move, longest mate {} plies, {} ms", name, table.values.len(), wins, longest_mate, start_time.elapsed().as_millis());
+
+        solved.insert(name, table);
+    }
+
+    Ok(())
+}
+
+/// loads the tables in the directory named by RUST_BOT_ENDGAME_TABLES, if it is set. Should be
+/// called once at startup
+pub fn init_tables_from_env() -> Result<(), String> {
+    let mut tables = Vec::new();
+
+    if let Ok(dir) = env::var("RUST_BOT_ENDGAME_TABLES").map(|dir| dir.trim().to_string()) {
+        if !dir.is_empty() {
+            for (name, _) in ENDGAMES {
+                let path = Path::new(&dir).join(format!("{}{}", name, SUFFIX));
+                if let Ok(bytes) = fs::read(&path) {
+                    tables.push(EndgameTable::from_bytes(name, &bytes)?);
+                }
+            }
+        }
+    }
+
+    TABLES.set(tables).map_err(|_| "Endgame tables have already been initialised".to_string())
+}
+
+/// returns the result of the position if it is in one of the loaded tables
+pub fn probe(board: &Board) -> Option<TableResult> {
+    let tables = TABLES.get()?;
+    if tables.is_empty() || board.castling_bits() != 0 {
+        return None;
+    }
+
+    // the strong side is the one with pieces besides its king, it is white in the tables
+    let strong = if board.count_pieces_player(Player::Black) == 1 { Player::White } else { Player::Black };
+    if board.count_pieces_player(!strong) != 1 {
+        return None;
+    }
+
+    let table = tables.iter().find(|table| {
+        table.pieces.len() + 1 == board.count_pieces_player(strong) as usize
+            && table.pieces.iter().all(|&piece| board.count_piece(strong, piece) == 1)
+    })?;
+
+    let flip = if strong == Player::White { 0 } else { 56 };
+    let mut squares = vec![board.king_sq(strong).0 as usize ^ flip, board.king_sq(!strong).0 as usize ^ flip];
+    squares.extend(table.pieces.iter().map(|&piece| board.piece_bb(strong, piece).to_sq().0 as usize ^ flip));
+    let stm = if board.turn() == strong { WHITE_TO_MOVE } else { BLACK_TO_MOVE };
+
+    Some(match table.value(stm, &squares) {
+        value if value > 0 => TableResult::Win(value as u8),
+        value if value < 0 => TableResult::Loss((-value - 1) as u8),
+        _ => TableResult::Draw,
+    })
+}
+
+/// returns the names of the loaded tables
+pub fn loaded_tables() -> Vec<&'static str> {
+    TABLES.get().map(|tables| tables.iter().map(|table| table.name.as_str()).collect()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solve(name: &str, solved: &HashMap<&str, EndgameTable>) -> EndgameTable {
+        init_statics();
+        let (_, pieces) = ENDGAMES.iter().find(|(endgame, _)| *endgame == name).unwrap();
+        generate(name, pieces, solved)
+    }
+
+    // KQK is used by several tests, and takes a few seconds to solve in a debug build
+    fn kqk() -> &'static EndgameTable {
+        static KQK: OnceLock<EndgameTable> = OnceLock::new();
+        KQK.get_or_init(|| solve("KQK", &HashMap::new()))
+    }
+
+    fn longest_mate(table: &EndgameTable) -> i8 {
+        table.values.iter().copied().max().unwrap()
+    }
+
+    #[test]
+    fn longest_mates_are_the_known_lengths() {
+        // mate in 10 moves with a queen and in 16 with a rook, from the worst positions with the
+        // strong side to move
+        assert_eq!(longest_mate(kqk()), 19);
+        let krk = solve("KRK", &HashMap::new());
+        assert_eq!(longest_mate(&krk), 31);
+    }
+
+    #[test]
+    fn tables_survive_being_written_and_read_back() {
+        let kqk = kqk();
+        let read = EndgameTable::from_bytes("KQK", &kqk.to_bytes()).unwrap();
+        assert_eq!(read.values, kqk.values);
+
+        let mut truncated = kqk.to_bytes();
+        truncated.pop();
+        assert!(EndgameTable::from_bytes("KQK", &truncated).is_err());
+        assert!(EndgameTable::from_bytes("KXK", &kqk.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn values_are_looked_up_from_any_king_square() {
+        let kqk = kqk();
+        // white: king f6, queen g1, black: king h8, white to move mates with Qg7
+        let squares = [SQ::F6.0 as usize, SQ::H8.0 as usize, SQ::G1.0 as usize];
+        assert_eq!(kqk.value(WHITE_TO_MOVE, &squares), 1);
+        // mirrored onto the a-file and turned, it is the same mate
+        let mirrored: Vec<usize> = squares.iter().map(|sq| sq ^ 7).collect();
+        assert_eq!(kqk.value(WHITE_TO_MOVE, &mirrored), 1);
+        let flipped: Vec<usize> = squares.iter().map(|sq| sq ^ 56).collect();
+        assert_eq!(kqk.value(WHITE_TO_MOVE, &flipped), 1);
+        // after Qg7 black is mated, but with the white king far away the queen is just taken
+        assert_eq!(kqk.value(BLACK_TO_MOVE, &[SQ::F6.0 as usize, SQ::H8.0 as usize, SQ::G7.0 as usize]), -1);
+        assert_eq!(kqk.value(BLACK_TO_MOVE, &[SQ::A1.0 as usize, SQ::H8.0 as usize, SQ::G7.0 as usize]), 0);
+    }
+}
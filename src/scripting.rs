@@ -0,0 +1,285 @@
+// Experimental evaluation terms written in Rhai, so ideas can be tried without rebuilding the bot.
+// RUST_BOT_EVAL_SCRIPT names a script that defines `fn evaluate(board)`, which gets a read-only view
+// of the position and returns a score in centipawns (positive is good for white) that is added to the
+// evaluation. Scripts are slow, so this is only for experiments, and it needs the bot to be built with
+// the `scripting` feature.
+use pleco::Board;
+use std::env;
+
+#[cfg(feature = "scripting")]
+use crate::evaluation::game_phase;
+#[cfg(feature = "scripting")]
+use pleco::{Player, SQ};
+#[cfg(feature = "scripting")]
+use rhai::{Array, Engine, Scope, AST};
+#[cfg(feature = "scripting")]
+use std::fs;
+#[cfg(feature = "scripting")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "scripting")]
+use std::sync::OnceLock;
+
+// stops scripts that loop forever, in operations per evaluation
+#[cfg(feature = "scripting")]
+const MAX_OPERATIONS: u64 = 1_000_000;
+// scripts can't return scores big enough to look like a mate
+#[cfg(feature = "scripting")]
+const MAX_SCRIPT_SCORE: i64 = 10000;
+
+#[cfg(feature = "scripting")]
+static SCRIPT: OnceLock<Option<Script>> = OnceLock::new();
+#[cfg(feature = "scripting")]
+static REPORTED_ERROR: AtomicBool = AtomicBool::new(false);
+
+// lets a test evaluate with a script on its own thread, without changing it for the others
+#[cfg(all(test, feature = "scripting"))]
+thread_local! {
+    static TEST_SCRIPT: std::cell::Cell<Option<&'static Script>> = const { std::cell::Cell::new(None) };
+}
+
+/// A compiled evaluation script
+#[cfg(feature = "scripting")]
+struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+#[cfg(feature = "scripting")]
+impl Script {
+    // compiles a script, which must define `fn evaluate(board)`. `name` is what errors call it
+    fn compile(name: &str, contents: &str) -> Result<Script, String> {
+        let engine = create_engine();
+        let ast = engine.compile(contents).map_err(|e| format!("Invalid evaluation script {}: {}", name, e))?;
+        if !ast.iter_functions().any(|f| f.name == "evaluate" && f.params.len() == 1) {
+            return Err(format!("Evaluation script {} must define `fn evaluate(board)`", name));
+        }
+        Ok(Script { engine, ast })
+    }
+
+    // runs the script's `evaluate` on the position
+    fn score(&self, board: &Board) -> Result<i32, String> {
+        let score = self.engine.call_fn::<i64>(&mut Scope::new(), &self.ast, "evaluate", (ScriptBoard::new(board),)).map_err(|e| e.to_string())?;
+        Ok(score.clamp(-MAX_SCRIPT_SCORE, MAX_SCRIPT_SCORE) as i32)
+    }
+}
+
+/// What a script can see of the position. It is a copy, so scripts can't change the real board
+#[cfg(feature = "scripting")]
+#[derive(Clone)]
+struct ScriptBoard {
+    fen: String,
+    white_to_move: bool,
+    in_check: bool,
+    legal_moves: i64,
+    rule_50: i64,
+    phase: i64,
+    // FEN letter of the piece on each square, a1 first, or an empty string
+    pieces: Vec<String>,
+    // number of white and black pieces attacking each square
+    attackers: Vec<[i64; 2]>,
+}
+
+#[cfg(feature = "scripting")]
+impl ScriptBoard {
+    fn new(board: &Board) -> ScriptBoard {
+        let occupied = board.occupied();
+        let pieces = (0..64u8).map(|sq| board.piece_at_sq(SQ(sq)).character().map(String::from).unwrap_or_default()).collect();
+        let attackers = (0..64u8).map(|sq| {
+            let attackers = board.attackers_to(SQ(sq), occupied);
+            [Player::White, Player::Black].map(|player| (attackers & board.get_occupied_player(player)).count_bits() as i64)
+        }).collect();
+
+        ScriptBoard {
+            fen: board.fen(),
+            white_to_move: board.turn() == Player::White,
+            in_check: board.in_check(),
+            legal_moves: board.generate_moves().len() as i64,
+            rule_50: board.rule_50() as i64,
+            phase: game_phase(board) as i64,
+            pieces,
+            attackers,
+        }
+    }
+
+    fn piece_at(&mut self, square: &str) -> String {
+        parse_square(square).map(|sq| self.pieces[sq].clone()).unwrap_or_default()
+    }
+
+    fn count(&mut self, piece: &str) -> i64 {
+        self.pieces.iter().filter(|p| *p == piece).count() as i64
+    }
+
+    fn squares(&mut self, piece: &str) -> Array {
+        self.pieces.iter().enumerate()
+            .filter(|(_, p)| *p == piece)
+            .map(|(sq, _)| SQ(sq as u8).to_string().into())
+            .collect()
+    }
+
+    fn attackers(&mut self, square: &str, colour: &str) -> i64 {
+        let side = if colour == "white" { 0 } else { 1 };
+        parse_square(square).map(|sq| self.attackers[sq][side]).unwrap_or(0)
+    }
+}
+
+// turns a square name like "e4" into its index, a1 being 0
+#[cfg(feature = "scripting")]
+fn parse_square(square: &str) -> Option<usize> {
+    let mut chars = square.chars();
+    let file = chars.next()?;
+    let rank = chars.next()?;
+    if chars.next().is_some() || !('a'..='h').contains(&file) || !('1'..='8').contains(&rank) {
+        return None;
+    }
+    Some((rank as usize - '1' as usize) * 8 + (file as usize - 'a' as usize))
+}
+
+#[cfg(feature = "scripting")]
+fn create_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+
+    engine.register_type_with_name::<ScriptBoard>("Board")
+        .register_get("fen", |board: &mut ScriptBoard| board.fen.clone())
+        .register_get("white_to_move", |board: &mut ScriptBoard| board.white_to_move)
+        .register_get("in_check", |board: &mut ScriptBoard| board.in_check)
+        .register_get("legal_moves", |board: &mut ScriptBoard| board.legal_moves)
+        .register_get("rule_50", |board: &mut ScriptBoard| board.rule_50)
+        .register_get("phase", |board: &mut ScriptBoard| board.phase)
+        .register_fn("piece_at", ScriptBoard::piece_at)
+        .register_fn("count", ScriptBoard::count)
+        .register_fn("squares", ScriptBoard::squares)
+        .register_fn("attackers", ScriptBoard::attackers);
+
+    engine
+}
+
+/// compiles the script named by RUST_BOT_EVAL_SCRIPT, if it is set. Should be called once at startup
+#[cfg(feature = "scripting")]
+pub fn init_script_from_env() -> Result<(), String> {
+    let script = match env::var("RUST_BOT_EVAL_SCRIPT") {
+        Ok(path) if !path.is_empty() => {
+            let contents = fs::read_to_string(&path).map_err(|e| format!("Could not read evaluation script {}: {}", path, e))?;
+            Some(Script::compile(&path, &contents)?)
+        }
+        _ => None,
+    };
+
+    SCRIPT.set(script).map_err(|_| "Evaluation script has already been initialised".to_string())
+}
+
+#[cfg(not(feature = "scripting"))]
+pub fn init_script_from_env() -> Result<(), String> {
+    match env::var("RUST_BOT_EVAL_SCRIPT") {
+        Ok(path) if !path.is_empty() => Err("RUST_BOT_EVAL_SCRIPT is set, but the bot was built without the scripting feature".to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// returns the score the evaluation script gives the position, if there is a script. Errors are
+/// reported the first time they happen and then ignored, so a broken script doesn't stop the game
+#[cfg(feature = "scripting")]
+pub fn script_score(board: &Board) -> Option<i32> {
+    match script()?.score(board) {
+        Ok(score) => Some(score),
+        Err(e) => {
+            if !REPORTED_ERROR.swap(true, Ordering::Relaxed) {
+                eprintln!("evaluation script failed on {}: {}", board.fen(), e);
+            }
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+pub fn script_score(_board: &Board) -> Option<i32> {
+    None
+}
+
+#[cfg(feature = "scripting")]
+fn script() -> Option<&'static Script> {
+    #[cfg(test)]
+    if let Some(script) = TEST_SCRIPT.with(|test_script| test_script.get()) {
+        return Some(script);
+    }
+    SCRIPT.get()?.as_ref()
+}
+
+#[cfg(all(test, feature = "scripting"))]
+mod tests {
+    use super::*;
+    use crate::evaluation::{evaluate, trace_evaluation, EvalTerm};
+
+    fn compile(contents: &str) -> Script {
+        Script::compile("test.rhai", contents).unwrap()
+    }
+
+    // runs `f` with the script in place of RUST_BOT_EVAL_SCRIPT's, on this thread only
+    fn with_script<T>(contents: &str, f: impl FnOnce() -> T) -> T {
+        let script: &'static Script = Box::leak(Box::new(compile(contents)));
+        TEST_SCRIPT.with(|test_script| test_script.set(Some(script)));
+        let result = f();
+        TEST_SCRIPT.with(|test_script| test_script.set(None));
+        result
+    }
+
+    #[test]
+    fn script_score_is_added_to_the_evaluation() {
+        let board = Board::start_pos();
+        let without = evaluate(&board);
+
+        with_script("fn evaluate(board) { 50 }", || {
+            assert_eq!(evaluate(&board), without + 50);
+            assert_eq!(trace_evaluation(&board).term(EvalTerm::Script), 50);
+        });
+    }
+
+    #[test]
+    fn script_sees_the_position() {
+        let board = Board::start_pos();
+        let score = |contents: &str| compile(contents).score(&board).unwrap();
+
+        assert_eq!(score(r#"fn evaluate(board) { board.count("P") * 10 }"#), 80);
+        assert_eq!(score(r#"fn evaluate(board) { if board.piece_at("e2") == "P" && board.piece_at("e4") == "" { 1 } else { 0 } }"#), 1);
+        // the pawns on e2 and g2 and the knight on g1
+        assert_eq!(score(r#"fn evaluate(board) { board.attackers("f3", "white") * 10 + board.attackers("f3", "black") }"#), 30);
+        assert_eq!(score(r#"fn evaluate(board) { if board.squares("K") == ["e1"] && board.white_to_move && board.legal_moves == 20 { 1 } else { 0 } }"#), 1);
+    }
+
+    #[test]
+    fn script_score_is_clamped() {
+        let board = Board::start_pos();
+        assert_eq!(compile("fn evaluate(board) { 1000000000 }").score(&board), Ok(MAX_SCRIPT_SCORE as i32));
+        assert_eq!(compile("fn evaluate(board) { -1000000000 }").score(&board), Ok(-MAX_SCRIPT_SCORE as i32));
+    }
+
+    #[test]
+    fn squares_are_parsed_by_name() {
+        assert_eq!(parse_square("a1"), Some(0));
+        assert_eq!(parse_square("h1"), Some(7));
+        assert_eq!(parse_square("e4"), Some(28));
+        assert_eq!(parse_square("h8"), Some(63));
+        for square in ["", "e", "i1", "a0", "a9", "E4", "e44", "4e"] {
+            assert_eq!(parse_square(square), None, "{}", square);
+        }
+        // scripts asking about a square that doesn't exist get an empty square
+        assert_eq!(compile(r#"fn evaluate(board) { board.attackers("z9", "white") }"#).score(&Board::start_pos()), Ok(0));
+    }
+
+    #[test]
+    fn script_must_define_evaluate() {
+        assert!(Script::compile("test.rhai", "fn score(board) { 0 }").err().unwrap().contains("must define `fn evaluate(board)`"));
+        assert!(Script::compile("test.rhai", "fn evaluate() { 0 }").err().unwrap().contains("must define `fn evaluate(board)`"));
+        assert!(Script::compile("test.rhai", "fn evaluate(board) { 0 ").err().unwrap().contains("Invalid evaluation script test.rhai"));
+    }
+
+    #[test]
+    fn script_that_loops_forever_is_stopped() {
+        let board = Board::start_pos();
+        assert!(compile("fn evaluate(board) { loop { } }").score(&board).is_err());
+
+        // the evaluation goes on without it
+        let without = evaluate(&board);
+        with_script("fn evaluate(board) { loop { } }", || assert_eq!(evaluate(&board), without));
+    }
+}
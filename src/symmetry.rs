@@ -0,0 +1,162 @@
+// Checks that the evaluation treats both sides the same: swapping the colours of every piece and
+// mirroring the board top to bottom should exactly negate the score. Any difference means a term or
+// score grid favours one colour, so offending positions are reported with the terms that differ.
+use crate::evaluation::{trace_evaluation, EvalTerm, EvalTrace};
+
+use pleco::Board;
+use std::fmt;
+
+// plies played in each random game used to collect positions
+const GAME_LENGTH: usize = 80;
+
+/// A position whose score isn't negated by swapping colours
+pub struct SymmetryFailure {
+    pub fen: String,
+    pub trace: EvalTrace,
+    pub flipped_trace: EvalTrace,
+}
+
+impl fmt::Display for SymmetryFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.fen)?;
+        writeln!(f, "{:<16}{:>10}{:>10}{:>10}", "term", "score", "flipped", "diff")?;
+        for term in EvalTerm::ALL {
+            let (score, flipped) = (self.trace.term(term), self.flipped_trace.term(term));
+            if score != -flipped {
+                writeln!(f, "{:<16}{:>10}{:>10}{:>10}", term.name(), score, flipped, score + flipped)?;
+            }
+        }
+        let (score, flipped) = (self.trace.endgame_adjustment(), self.flipped_trace.endgame_adjustment());
+        if score != -flipped {
+            writeln!(f, "{:<16}{:>10}{:>10}{:>10}", "endgame", score, flipped, score + flipped)?;
+        }
+        write!(f, "{:<16}{:>10}{:>10}{:>10}", "total", self.trace.total(), self.flipped_trace.total(), self.trace.total() + self.flipped_trace.total())
+    }
+}
+
+// returns the FEN of the position with the colours swapped and the board mirrored top to bottom, so
+// white's pieces become black's pieces on the same squares from black's side of the board
+pub fn colour_flipped_fen(fen: &str) -> String {
+    let fields: Vec<&str> = fen.split_whitespace().collect();
+    let swap_case = |c: char| if c.is_ascii_uppercase() { c.to_ascii_lowercase() } else { c.to_ascii_uppercase() };
+
+    let placement = fields[0].split('/').rev().map(|rank| rank.chars().map(swap_case).collect::<String>()).collect::<Vec<_>>().join("/");
+    let turn = if fields.get(1) == Some(&"b") { "w" } else { "b" };
+
+    let castling = match fields.get(2) {
+        Some(&"-") | None => "-".to_string(),
+        Some(castling) => {
+            // white's rights come first in a FEN
+            let swapped: String = castling.chars().map(swap_case).collect();
+            swapped.chars().filter(char::is_ascii_uppercase).chain(swapped.chars().filter(char::is_ascii_lowercase)).collect()
+        }
+    };
+
+    let en_passant = match fields.get(3) {
+        Some(&"-") | None => "-".to_string(),
+        Some(square) => square.replace('3', "x").replace('6', "3").replace('x', "6"),
+    };
+
+    let mut flipped = vec![placement, turn.to_string(), castling, en_passant];
+    flipped.extend(fields.iter().skip(4).map(|field| field.to_string()));
+    flipped.join(" ")
+}
+
+pub fn colour_flipped(board: &Board) -> Board {
+    Board::from_fen(&colour_flipped_fen(&board.fen())).unwrap_or_else(|e| panic!("colour flipped {} is invalid: {:?}", board.fen(), e))
+}
+
+// returns the failure if the position's score isn't exactly negated by swapping colours
+pub fn check_symmetry(board: &Board) -> Option<SymmetryFailure> {
+    let trace = trace_evaluation(board);
+    let flipped_trace = trace_evaluation(&colour_flipped(board));
+
+    if trace.total() == -flipped_trace.total() && EvalTerm::ALL.iter().all(|&term| trace.term(term) == -flipped_trace.term(term)) {
+        return None;
+    }
+    Some(SymmetryFailure { fen: board.fen(), trace, flipped_trace })
+}
+
+// returns the positions of `games` games of random moves from the start position. The moves are
+// picked by a seeded generator, so the same positions are checked every time
+pub fn random_positions(games: usize, seed: u64) -> Vec<Board> {
+    let mut state = seed.max(1);
+    let mut positions = Vec::new();
+
+    for _ in 0..games {
+        let mut board = Board::start_pos();
+        for _ in 0..GAME_LENGTH {
+            let moves = board.generate_moves();
+            if moves.is_empty() {
+                break;
+            }
+
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            board.apply_move(moves[(state % moves.len() as u64) as usize]);
+            positions.push(board.shallow_clone());
+        }
+    }
+
+    positions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluation::evaluate;
+
+    #[test]
+    fn colour_flip_twice_is_the_same_position() {
+        for fen in [
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1",
+            "r3k2r/8/8/8/8/8/8/R3K2R w Kq - 3 20",
+            "8/8/8/3pP3/8/8/8/k6K w - d6 0 2",
+        ] {
+            assert_eq!(colour_flipped_fen(&colour_flipped_fen(fen)), fen);
+        }
+    }
+
+    #[test]
+    fn colour_flip_swaps_sides() {
+        assert_eq!(
+            colour_flipped_fen("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1"),
+            "rnbqkbnr/pppp1ppp/8/4p3/8/8/PPPPPPPP/RNBQKBNR w KQkq e6 0 1"
+        );
+        assert_eq!(colour_flipped_fen("r3k2r/8/8/8/8/8/8/R3K2R w Kq - 3 20"), "r3k2r/8/8/8/8/8/8/R3K2R b Qk - 3 20");
+    }
+
+    #[test]
+    fn start_position_scores_zero() {
+        assert_eq!(evaluate(&Board::start_pos()), 0);
+    }
+
+    #[test]
+    fn evaluation_is_colour_symmetric() {
+        let failures: Vec<String> = random_positions(200, 0x5eed)
+            .iter()
+            .filter_map(check_symmetry)
+            .map(|failure| failure.to_string())
+            .collect();
+
+        assert!(failures.is_empty(), "{} positions aren't symmetric, e.g.\n{}", failures.len(), failures.iter().take(3).cloned().collect::<Vec<_>>().join("\n\n"));
+    }
+
+    #[test]
+    fn endgames_are_colour_symmetric() {
+        for fen in [
+            "8/8/8/4k3/8/8/8/R3K3 w - - 0 1",
+            "8/8/8/8/4k3/8/8/BN2K3 b - - 0 1",
+            "4k3/8/8/8/8/8/4P3/4K3 w - - 0 1",
+            "8/8/4k3/8/2B5/P7/8/4K3 w - - 0 1",
+            "8/5b2/4k3/4p3/4P3/3K4/4B3/8 w - - 0 1",
+        ] {
+            let board = Board::from_fen(fen).unwrap();
+            if let Some(failure) = check_symmetry(&board) {
+                panic!("{}", failure);
+            }
+        }
+    }
+}
@@ -0,0 +1,1223 @@
+// Syzygy endgame tablebase probing. Table files are looked for in the directories listed in
+// RUST_BOT_SYZYGY_PATH, separated by ':'. At the root the tables pick the move that wins fastest or
+// loses slowest, and inside the search they replace the evaluation of positions with few pieces by
+// their exact result. When no tables are found nothing is probed and the engine plays as normal.
+//
+// The file format is Ronald de Man's, decoded the same way as in Stockfish's probing code. A table
+// is split into parts by side to move (WDL tables only) and by the file of the leading pawn (tables
+// with pawns only). Each part is a list of values, indexed by a canonical encoding of where the
+// pieces are, which is compressed with recursive pairing and Huffman coded in fixed size blocks.
+use crate::evaluation::CHECKMATE_SCORE;
+
+use memmap2::Mmap;
+use pleco::{Board,Player,PieceType,BitMove,BitBoard};
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+const WDL_SUFFIX: &str = ".rtbw";
+const DTZ_SUFFIX: &str = ".rtbz";
+const WDL_MAGIC: [u8; 4] = [0x71, 0xE8, 0x23, 0x5D];
+const DTZ_MAGIC: [u8; 4] = [0xD7, 0x66, 0x0C, 0xA5];
+
+const MAX_PIECES: usize = 7;
+
+/// score of a position the tables say is won, well above any evaluation but below a mate the
+/// search has actually found
+pub const TABLEBASE_WIN_SCORE: i32 = CHECKMATE_SCORE / 2;
+
+// win/draw/loss values as stored in WDL tables, from the side to move's point of view
+const LOSS: i32 = -2;
+const BLESSED_LOSS: i32 = -1;
+const DRAW: i32 = 0;
+const CURSED_WIN: i32 = 1;
+const WIN: i32 = 2;
+
+// flags of a table part
+const FLAG_STM: u8 = 1;
+const FLAG_MAPPED: u8 = 2;
+const FLAG_WIN_PLIES: u8 = 4;
+const FLAG_LOSS_PLIES: u8 = 8;
+const FLAG_WIDE: u8 = 16;
+const FLAG_SINGLE_VALUE: u8 = 128;
+
+static TABLEBASES: OnceLock<Option<Tablebases>> = OnceLock::new();
+static INDICES: OnceLock<Indices> = OnceLock::new();
+
+/// The result of a position with perfect play. Cursed wins and blessed losses are drawn because of
+/// the fifty move rule
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Wdl {
+    Loss,
+    BlessedLoss,
+    Draw,
+    CursedWin,
+    Win,
+}
+
+impl Wdl {
+    fn from_value(value: i32) -> Wdl {
+        match value {
+            LOSS => Wdl::Loss,
+            BLESSED_LOSS => Wdl::BlessedLoss,
+            DRAW => Wdl::Draw,
+            CURSED_WIN => Wdl::CursedWin,
+            _ => Wdl::Win,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Wdl::Loss => "loss",
+            Wdl::BlessedLoss => "blessed loss",
+            Wdl::Draw => "draw",
+            Wdl::CursedWin => "cursed win",
+            Wdl::Win => "win",
+        }
+    }
+}
+
+// lookup tables used to turn piece placements into table indices
+struct Indices {
+    // binomial[k][n] is the number of ways to choose k of n squares
+    binomial: [[u64; 64]; MAX_PIECES],
+    // numbers the squares a pawn can be on so that the leading pawn, the one nearest the edge and
+    // then on the lowest rank, has the highest number
+    map_pawns: [usize; 64],
+    lead_pawn_idx: [[u64; 64]; 6],
+    lead_pawns_size: [[u64; 4]; 6],
+    // numbers the squares below the a1-h8 diagonal
+    map_b1h1h7: [u64; 64],
+    // numbers the squares of the a1-d1-d4 triangle, those on the diagonal last
+    map_a1d1d4: [usize; 64],
+    // numbers the 462 ways to place two kings with the first one in the a1-d1-d4 triangle
+    map_kk: [[u64; 64]; 10],
+}
+
+// these tables are filled in square by square, so indexing reads better than iterators
+#[allow(clippy::needless_range_loop)]
+fn indices() -> &'static Indices {
+    INDICES.get_or_init(|| {
+        let mut binomial = [[0; 64]; MAX_PIECES];
+        binomial[0][0] = 1;
+        for n in 1..64 {
+            for k in 0..MAX_PIECES.min(n + 1) {
+                let with_square = if k > 0 { binomial[k - 1][n - 1] } else { 0 };
+                let without_square = if k < n { binomial[k][n - 1] } else { 0 };
+                binomial[k][n] = with_square + without_square;
+            }
+        }
+
+        let mut map_pawns = [0; 64];
+        let mut lead_pawn_idx = [[0; 64]; 6];
+        let mut lead_pawns_size = [[0; 4]; 6];
+        // the 48 squares pawns can be on are numbered from 47 down, so the last pair is 1 and 0
+        let mut available_squares = 48;
+        for lead_pawn_count in 1..=5 {
+            for file in 0..4 {
+                let mut idx = 0;
+                for rank in 1..7 {
+                    let sq = rank * 8 + file;
+                    if lead_pawn_count == 1 {
+                        map_pawns[sq] = available_squares - 1;
+                        map_pawns[sq ^ 7] = available_squares - 2;
+                        available_squares -= 2;
+                    }
+                    lead_pawn_idx[lead_pawn_count][sq] = idx;
+                    idx += binomial[lead_pawn_count - 1][map_pawns[sq]];
+                }
+                lead_pawns_size[lead_pawn_count][file] = idx;
+            }
+        }
+
+        let mut map_b1h1h7 = [0; 64];
+        let mut code = 0;
+        for sq in 0..64 {
+            if off_diagonal(sq) < 0 {
+                map_b1h1h7[sq] = code;
+                code += 1;
+            }
+        }
+
+        let mut map_a1d1d4 = [0; 64];
+        let mut diagonal = Vec::new();
+        let mut code = 0;
+        for sq in 0..28 {
+            if off_diagonal(sq) < 0 && sq % 8 <= 3 {
+                map_a1d1d4[sq] = code;
+                code += 1;
+            }
+            else if off_diagonal(sq) == 0 && sq % 8 <= 3 {
+                diagonal.push(sq);
+            }
+        }
+        for sq in diagonal {
+            map_a1d1d4[sq] = code;
+            code += 1;
+        }
+
+        let mut map_kk = [[0; 64]; 10];
+        let mut both_on_diagonal = Vec::new();
+        let mut code = 0;
+        for idx in 0..10 {
+            // b1 is the only square of the triangle numbered 0, the rest of the board is 0 too
+            for s1 in (0..28).filter(|&s1| map_a1d1d4[s1] == idx && (idx != 0 || s1 == 1)) {
+                for s2 in 0..64 {
+                    if square_distance(s1, s2) <= 1 || (off_diagonal(s1) == 0 && off_diagonal(s2) > 0) {
+                        continue;
+                    }
+                    if off_diagonal(s1) == 0 && off_diagonal(s2) == 0 {
+                        both_on_diagonal.push((s1, s2));
+                    }
+                    else {
+                        map_kk[idx][s2] = code;
+                        code += 1;
+                    }
+                }
+            }
+        }
+        for (s1, s2) in both_on_diagonal {
+            map_kk[map_a1d1d4[s1]][s2] = code;
+            code += 1;
+        }
+        debug_assert_eq!(code, 462);
+
+        Indices { binomial, map_pawns, lead_pawn_idx, lead_pawns_size, map_b1h1h7, map_a1d1d4, map_kk }
+    })
+}
+
+// squares are numbered 0 (a1) to 63 (h8). Returns how far above the a1-h8 diagonal a square is
+fn off_diagonal(sq: usize) -> i32 {
+    (sq / 8) as i32 - (sq % 8) as i32
+}
+
+fn flip_diagonal(sq: usize) -> usize {
+    ((sq >> 3) | (sq << 3)) & 63
+}
+
+fn square_distance(a: usize, b: usize) -> usize {
+    (a / 8).abs_diff(b / 8).max((a % 8).abs_diff(b % 8))
+}
+
+// the readers return None past the end of the file, so a truncated or corrupt table can't panic
+fn byte(data: &[u8], pos: usize) -> Option<u8> {
+    data.get(pos).copied()
+}
+
+fn u16_le(data: &[u8], pos: usize) -> Option<u16> {
+    data.get(pos..pos + 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_le(data: &[u8], pos: usize) -> Option<u32> {
+    data.get(pos..pos + 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+// the compressed data is read in big endian words, which may run past the end of the last block
+fn u32_be(data: &[u8], pos: usize) -> u32 {
+    data.get(pos..pos + 4).map_or(0, |bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn u64_be(data: &[u8], pos: usize) -> u64 {
+    ((u32_be(data, pos) as u64) << 32) | u32_be(data, pos + 4) as u64
+}
+
+// one part of a table, with offsets into the file for its compressed data
+#[derive(Clone, Default)]
+struct PairsData {
+    flags: u8,
+    // the value of every position if the part is a single value, otherwise the shortest symbol length
+    min_sym_len: u8,
+    block_size: usize,
+    span: u64,
+    num_blocks: usize,
+    block_length_size: usize,
+    sparse_index_size: usize,
+    lowest_sym: usize,
+    btree: usize,
+    sparse_index: usize,
+    block_length: usize,
+    data: usize,
+    // base64[l] is the lowest symbol of length l + min_sym_len, padded to 64 bits
+    base64: Vec<u64>,
+    // the number of values, less one, each symbol expands to
+    symlen: Vec<u32>,
+    // the pieces in the order they are encoded, in the table's colours
+    pieces: [u8; MAX_PIECES],
+    group_idx: [u64; MAX_PIECES + 1],
+    group_len: [usize; MAX_PIECES + 1],
+    // where the DTZ value maps for wins, losses, cursed wins and blessed losses start
+    map_idx: [u16; 4],
+}
+
+impl PairsData {
+    // symbols are pairs of two other symbols, or a value if the right hand one is 0xFFF
+    fn btree_left(&self, data: &[u8], sym: usize) -> Option<usize> {
+        let pos = self.btree + 3 * sym;
+        Some((((byte(data, pos + 1)? & 0xF) as usize) << 8) | byte(data, pos)? as usize)
+    }
+
+    fn btree_right(&self, data: &[u8], sym: usize) -> Option<usize> {
+        let pos = self.btree + 3 * sym;
+        Some(((byte(data, pos + 2)? as usize) << 4) | (byte(data, pos + 1)? >> 4) as usize)
+    }
+
+    fn set_symlen(&mut self, data: &[u8], sym: usize, visited: &mut [bool]) -> Option<u32> {
+        visited[sym] = true;
+        let right = self.btree_right(data, sym)?;
+        if right == 0xFFF {
+            return Some(0);
+        }
+
+        let left = self.btree_left(data, sym)?;
+        if left >= self.symlen.len() || right >= self.symlen.len() {
+            return None;
+        }
+        if !visited[left] {
+            self.symlen[left] = self.set_symlen(data, left, visited)?;
+        }
+        if !visited[right] {
+            self.symlen[right] = self.set_symlen(data, right, visited)?;
+        }
+
+        Some(self.symlen[left].saturating_add(self.symlen[right]).saturating_add(1))
+    }
+
+    // reads the sizes of the part's blocks and its Huffman code, returning the position after them
+    fn set_sizes(&mut self, data: &[u8], mut pos: usize) -> Option<usize> {
+        self.flags = byte(data, pos)?;
+        pos += 1;
+
+        if self.flags & FLAG_SINGLE_VALUE != 0 {
+            self.min_sym_len = byte(data, pos)?;
+            return Some(pos + 1);
+        }
+
+        let num_groups = self.group_len.iter().position(|&len| len == 0).unwrap_or(MAX_PIECES);
+        let table_size = self.group_idx[num_groups];
+
+        self.block_size = 1usize.checked_shl(byte(data, pos)? as u32)?;
+        self.span = 1u64.checked_shl(byte(data, pos + 1)? as u32)?;
+        self.sparse_index_size = table_size.div_ceil(self.span) as usize;
+        let padding = byte(data, pos + 2)? as usize;
+        self.num_blocks = u32_le(data, pos + 3)? as usize;
+        self.block_length_size = self.num_blocks + padding;
+        let max_sym_len = byte(data, pos + 7)?;
+        self.min_sym_len = byte(data, pos + 8)?;
+        pos += 9;
+
+        self.lowest_sym = pos;
+        let num_lengths = max_sym_len.checked_sub(self.min_sym_len)? as usize + 1;
+        self.base64 = vec![0; num_lengths];
+        for i in (0..num_lengths - 1).rev() {
+            let lowest = u16_le(data, self.lowest_sym + 2 * i)? as u64;
+            let next_lowest = u16_le(data, self.lowest_sym + 2 * (i + 1))? as u64;
+            self.base64[i] = self.base64[i + 1].wrapping_add(lowest).wrapping_sub(next_lowest) / 2;
+        }
+        for (i, base) in self.base64.iter_mut().enumerate() {
+            let shift = 64usize.checked_sub(i + self.min_sym_len as usize)?;
+            *base = base.checked_shl(shift as u32).unwrap_or(0);
+        }
+        pos += 2 * num_lengths;
+
+        let num_syms = u16_le(data, pos)? as usize;
+        pos += 2;
+        self.btree = pos;
+        self.symlen = vec![0; num_syms];
+        let mut visited = vec![false; num_syms];
+        for sym in 0..num_syms {
+            if !visited[sym] {
+                self.symlen[sym] = self.set_symlen(data, sym, &mut visited)?;
+            }
+        }
+
+        Some(pos + 3 * num_syms + (num_syms & 1))
+    }
+
+    // returns the value stored at an index, or None if the table is corrupt
+    fn decompress(&self, data: &[u8], idx: u64) -> Option<i32> {
+        if self.flags & FLAG_SINGLE_VALUE != 0 {
+            return Some(self.min_sym_len as i32);
+        }
+
+        // the sparse index gives the block and offset of every span-th value, starting half a span in
+        let entry = self.sparse_index + 6 * (idx / self.span) as usize;
+        let mut block = u32_le(data, entry)? as usize;
+        let mut offset = u16_le(data, entry + 4)? as i64 + (idx % self.span) as i64 - (self.span / 2) as i64;
+
+        let block_length = |block: usize| u16_le(data, self.block_length + 2 * block).map(|length| length as i64);
+        while offset < 0 {
+            block = block.checked_sub(1)?;
+            offset += block_length(block)? + 1;
+        }
+        while offset > block_length(block)? {
+            offset -= block_length(block)? + 1;
+            block += 1;
+        }
+        if block >= self.num_blocks {
+            return None;
+        }
+
+        // read symbols from the start of the block until reaching the one the value is in
+        let mut pos = self.data + block * self.block_size;
+        let mut buffer = u64_be(data, pos);
+        let mut buffer_size: usize = 64;
+        pos += 8;
+
+        let mut sym;
+        loop {
+            let mut len = 0;
+            while buffer < *self.base64.get(len)? {
+                len += 1;
+            }
+            let shift = 64usize.checked_sub(len + self.min_sym_len as usize)?;
+            sym = (buffer - self.base64[len]).checked_shr(shift as u32)? as u16;
+            sym = sym.wrapping_add(u16_le(data, self.lowest_sym + 2 * len)?);
+
+            let sym_len = *self.symlen.get(sym as usize)? as i64;
+            if offset < sym_len + 1 {
+                break;
+            }
+            offset -= sym_len + 1;
+
+            len += self.min_sym_len as usize;
+            buffer = buffer.checked_shl(len as u32)?;
+            buffer_size = buffer_size.checked_sub(len)?;
+            if buffer_size <= 32 {
+                buffer_size += 32;
+                buffer |= (u32_be(data, pos) as u64) << (64 - buffer_size);
+                pos += 4;
+            }
+        }
+
+        // expand the symbol into its pairs until reaching the value
+        let mut sym = sym as usize;
+        loop {
+            let sym_len = *self.symlen.get(sym)?;
+            if sym_len == 0 {
+                break;
+            }
+
+            let left = self.btree_left(data, sym)?;
+            let left_len = *self.symlen.get(left)? as i64;
+            let next = if offset < left_len + 1 {
+                left
+            }
+            else {
+                offset -= left_len + 1;
+                self.btree_right(data, sym)?
+            };
+
+            // each pair is longer than both of its symbols, which also stops a loop in a corrupt tree
+            if *self.symlen.get(next)? >= sym_len {
+                return None;
+            }
+            sym = next;
+        }
+
+        self.btree_left(data, sym).map(|value| value as i32)
+    }
+}
+
+/// A WDL or DTZ table file, mapped into memory
+struct Table {
+    data: Mmap,
+    is_dtz: bool,
+    // white's pieces in the table name, e.g. "KR" for KRvK
+    white: String,
+    // both sides have the same pieces, so only white to move is stored
+    symmetric: bool,
+    num_pieces: usize,
+    has_pawns: bool,
+    has_unique_pieces: bool,
+    // pawns of the leading side, which has the fewest pawns if both sides have some, and the other side
+    pawn_count: [usize; 2],
+    // parts by leading pawn file, then by side to move
+    parts: Vec<Vec<PairsData>>,
+    // start of the DTZ value maps
+    map: usize,
+}
+
+impl Table {
+    fn open(path: &Path, is_dtz: bool) -> Result<Table, String> {
+        let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+        // SAFETY: table files are only ever read, the mapping is invalid only if they change on disk
+        let data = unsafe { Mmap::map(&file) }.map_err(|e| format!("Could not map {}: {}", path.display(), e))?;
+
+        let magic = if is_dtz { DTZ_MAGIC } else { WDL_MAGIC };
+        if data.len() < 6 || data[..4] != magic {
+            return Err(format!("{} is not a Syzygy table", path.display()));
+        }
+
+        let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+        let (white, black) = name.split_once('v').ok_or_else(|| format!("Invalid table name {}", name))?;
+        let count = |side: &str, piece: char| side.chars().filter(|&c| c == piece).count();
+        let (white_pawns, black_pawns) = (count(white, 'P'), count(black, 'P'));
+        let lead_is_white = black_pawns == 0 || (white_pawns > 0 && black_pawns >= white_pawns);
+
+        let mut table = Table {
+            data,
+            is_dtz,
+            white: white.to_string(),
+            symmetric: white == black,
+            num_pieces: white.len() + black.len(),
+            has_pawns: white_pawns + black_pawns > 0,
+            has_unique_pieces: [white, black].iter().any(|side| "PNBRQ".chars().any(|piece| count(side, piece) == 1)),
+            pawn_count: if lead_is_white { [white_pawns, black_pawns] } else { [black_pawns, white_pawns] },
+            parts: Vec::new(),
+            map: 0,
+        };
+        table.init_parts().map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(table)
+    }
+
+    // reads the header of every part of the table
+    fn init_parts(&mut self) -> Result<(), String> {
+        let data = &self.data[..];
+        let truncated = || "table file is truncated".to_string();
+        let at = |pos: usize| byte(data, pos).ok_or_else(truncated);
+        if (at(4)? & 2 != 0) != self.has_pawns {
+            return Err("pawns in the header don't match the table name".to_string());
+        }
+
+        let sides = if self.is_dtz || self.symmetric { 1 } else { 2 };
+        let num_files = if self.has_pawns { 4 } else { 1 };
+        let both_have_pawns = self.has_pawns && self.pawn_count[1] > 0;
+        let mut parts = vec![vec![PairsData::default(); sides]; num_files];
+        let mut pos = 5;
+
+        for (file, file_parts) in parts.iter_mut().enumerate() {
+            let second = if both_have_pawns { at(pos + 1)? } else { 0xFF };
+            let orders = [[at(pos)? & 0xF, second & 0xF], [at(pos)? >> 4, second >> 4]];
+            pos += 1 + both_have_pawns as usize;
+
+            for k in 0..self.num_pieces {
+                for (side, part) in file_parts.iter_mut().enumerate() {
+                    part.pieces[k] = if side == 0 { at(pos)? & 0xF } else { at(pos)? >> 4 };
+                }
+                pos += 1;
+            }
+            for (side, part) in file_parts.iter_mut().enumerate() {
+                self.set_groups(part, orders[side], file).ok_or("the pieces in the header don't match the table name")?;
+            }
+        }
+        pos += pos & 1;
+
+        for part in parts.iter_mut().flatten() {
+            pos = part.set_sizes(data, pos).ok_or("table file is truncated or corrupt")?;
+        }
+
+        if self.is_dtz {
+            self.map = pos;
+            for file_parts in parts.iter_mut() {
+                let part = &mut file_parts[0];
+                if part.flags & FLAG_MAPPED == 0 {
+                    continue;
+                }
+                if part.flags & FLAG_WIDE != 0 {
+                    pos += pos & 1;
+                    for i in 0..4 {
+                        part.map_idx[i] = ((pos - self.map) / 2 + 1) as u16;
+                        pos += 2 * u16_le(data, pos).ok_or_else(truncated)? as usize + 2;
+                    }
+                }
+                else {
+                    for i in 0..4 {
+                        part.map_idx[i] = (pos - self.map + 1) as u16;
+                        pos += at(pos)? as usize + 1;
+                    }
+                }
+            }
+            pos += pos & 1;
+        }
+
+        for part in parts.iter_mut().flatten() {
+            part.sparse_index = pos;
+            pos += 6 * part.sparse_index_size;
+        }
+        for part in parts.iter_mut().flatten() {
+            part.block_length = pos;
+            pos += 2 * part.block_length_size;
+        }
+        for part in parts.iter_mut().flatten() {
+            pos = (pos + 0x3F) & !0x3F;
+            part.data = pos;
+            pos = part.num_blocks.checked_mul(part.block_size).and_then(|size| pos.checked_add(size)).ok_or_else(truncated)?;
+        }
+
+        if pos > data.len() {
+            return Err("table file is truncated".to_string());
+        }
+
+        self.parts = parts;
+        Ok(())
+    }
+
+    // works out which pieces are encoded together and the size of each group. Pieces of the same
+    // type and colour are a group, apart from the leading group, which is the leading pawns, three
+    // different pieces or the two kings. None if the groups are too big, which only a corrupt table has
+    fn set_groups(&self, part: &mut PairsData, order: [u8; 2], file: usize) -> Option<()> {
+        let ix = indices();
+        let mut n = 0;
+        let mut first_len: i32 = if self.has_pawns { 0 } else if self.has_unique_pieces { 3 } else { 2 };
+        part.group_len[0] = 1;
+
+        for i in 1..self.num_pieces {
+            first_len -= 1;
+            if first_len > 0 || part.pieces[i] == part.pieces[i - 1] {
+                part.group_len[n] += 1;
+            }
+            else {
+                n += 1;
+                part.group_len[n] = 1;
+            }
+        }
+        n += 1;
+        part.group_len[n] = 0;
+
+        // the groups are multiplied together in the order the table gives for the leading group and
+        // the other side's pawns, then the other groups as they come
+        let both_have_pawns = self.has_pawns && self.pawn_count[1] > 0;
+        let mut next = if both_have_pawns { 2 } else { 1 };
+        let mut free_squares = 64 - part.group_len[0] - if both_have_pawns { part.group_len[1] } else { 0 };
+        let mut size = 1;
+        let mut k = 0;
+
+        while next < n || k == order[0] as usize || k == order[1] as usize {
+            if k == order[0] as usize {
+                part.group_idx[0] = size;
+                size *= if self.has_pawns { ix.lead_pawns_size.get(part.group_len[0])?[file] } else if self.has_unique_pieces { 31332 } else { 462 };
+            }
+            else if k == order[1] as usize {
+                part.group_idx[1] = size;
+                size *= ix.binomial.get(part.group_len[1])?[48 - part.group_len[0]];
+            }
+            else {
+                part.group_idx[next] = size;
+                size *= ix.binomial.get(part.group_len[next])?[free_squares];
+                free_squares -= part.group_len[next];
+                next += 1;
+            }
+            k += 1;
+        }
+
+        part.group_idx[n] = size;
+        Some(())
+    }
+
+    // looks up the position, returning Some(None) if this is a DTZ table that only stores the other
+    // side to move, and None if the table is corrupt. `wdl` is the position's result, which DTZ
+    // values depend on
+    fn probe(&self, board: &Board, wdl: i32) -> Option<Option<i32>> {
+        let (file, stored, part, idx) = self.index(board)?;
+        if !stored {
+            return Some(None);
+        }
+        let value = part.decompress(&self.data, idx)?;
+        Some(Some(if self.is_dtz { self.map_dtz(file, value, wdl)? } else { value - 2 }))
+    }
+
+    // returns the leading pawn file, whether the side to move is stored, and the table part and the
+    // index within it of the position. None if the table's pieces don't match the position's
+    fn index(&self, board: &Board) -> Option<(usize, bool, &PairsData, u64)> {
+        let ix = indices();
+
+        // tables are stored with white as the side named first, and symmetric tables only have
+        // white to move, so the board may need mirroring and its colours swapping
+        let flip = self.white != side_name(board, Player::White) || (self.symmetric && board.turn() == Player::Black);
+        let flip_colour = if flip { 8 } else { 0 };
+        let flip_squares = if flip { 56 } else { 0 };
+        let stm = flip as usize ^ board.turn() as usize;
+
+        let mut squares = [0; MAX_PIECES];
+        let mut pieces = [0; MAX_PIECES];
+        let mut size = 0;
+        let mut lead_pawns = BitBoard(0);
+        let mut file = 0;
+
+        if self.has_pawns {
+            let lead_piece = self.parts[0][0].pieces[0] ^ flip_colour;
+            let lead_player = if lead_piece & 8 == 0 { Player::White } else { Player::Black };
+            lead_pawns = board.piece_bb(lead_player, PieceType::P);
+            for sq in lead_pawns {
+                squares[size] = sq.0 as usize ^ flip_squares;
+                size += 1;
+            }
+
+            if size == 0 {
+                return None;
+            }
+            let lead = (0..size).max_by_key(|&i| ix.map_pawns[squares[i]]).unwrap_or(0);
+            squares.swap(0, lead);
+            file = (squares[0] % 8).min(7 - squares[0] % 8);
+        }
+        let lead_pawn_count = size;
+
+        // symmetric tables without pawns are the same for both sides to move
+        let stores_stm = (self.parts[file][0].flags & FLAG_STM) as usize == stm || (self.symmetric && !self.has_pawns);
+        let stored = !self.is_dtz || stores_stm;
+
+        for sq in board.occupied() & !lead_pawns {
+            squares[size] = sq.0 as usize ^ flip_squares;
+            pieces[size] = board.piece_at_sq(sq) as u8 ^ flip_colour;
+            size += 1;
+        }
+
+        // put the pieces in the order the table encodes them
+        let part = &self.parts[file][stm % self.parts[file].len()];
+        for i in lead_pawn_count..size.saturating_sub(1) {
+            if let Some(j) = (i + 1..size).find(|&j| pieces[j] == part.pieces[i]) {
+                pieces.swap(i, j);
+                squares.swap(i, j);
+            }
+        }
+
+        // mirror so the leading piece is on the a-d files
+        if squares[0] % 8 > 3 {
+            for sq in &mut squares[..size] {
+                *sq ^= 7;
+            }
+        }
+
+        let mut idx;
+        if self.has_pawns {
+            idx = ix.lead_pawn_idx[lead_pawn_count][squares[0]];
+            squares[1..lead_pawn_count].sort_by_key(|&sq| ix.map_pawns[sq]);
+            for (i, &sq) in squares.iter().enumerate().take(lead_pawn_count).skip(1) {
+                idx += ix.binomial[i][ix.map_pawns[sq]];
+            }
+        }
+        else {
+            // without pawns the leading piece can also be mirrored onto ranks 1-4, and then below
+            // the a1-h8 diagonal
+            if squares[0] / 8 > 3 {
+                for sq in &mut squares[..size] {
+                    *sq ^= 56;
+                }
+            }
+            for i in 0..part.group_len[0] {
+                if off_diagonal(squares[i]) == 0 {
+                    continue;
+                }
+                if off_diagonal(squares[i]) > 0 {
+                    for sq in &mut squares[i..size] {
+                        *sq = flip_diagonal(*sq);
+                    }
+                }
+                break;
+            }
+
+            idx = if self.has_unique_pieces { unique_pieces_index(&squares) } else { ix.map_kk[ix.map_a1d1d4[squares[0]]][squares[1]] };
+        }
+
+        // the other groups are each encoded as a combination of the squares left for them
+        idx *= part.group_idx[0];
+        let mut group_start = part.group_len[0];
+        let mut remaining_pawns = self.has_pawns && self.pawn_count[1] > 0;
+        let mut next = 1;
+
+        while part.group_len[next] != 0 {
+            let group_end = group_start + part.group_len[next];
+            squares[group_start..group_end].sort();
+
+            let mut n = 0;
+            for i in 0..part.group_len[next] {
+                let sq = squares[group_start + i];
+                let adjust = squares[..group_start].iter().filter(|&&s| sq > s).count();
+                n += ix.binomial[i + 1][sq.checked_sub(adjust + if remaining_pawns { 8 } else { 0 })?];
+            }
+
+            remaining_pawns = false;
+            idx += n * part.group_idx[next];
+            group_start = group_end;
+            next += 1;
+        }
+
+        Some((file, stored, part, idx))
+    }
+
+    // DTZ values are stored as ranks by frequency, and in moves rather than plies when that is exact
+    fn map_dtz(&self, file: usize, value: i32, wdl: i32) -> Option<i32> {
+        let part = &self.parts[file][0];
+        let data = &self.data[..];
+        let mut value = value;
+
+        if part.flags & FLAG_MAPPED != 0 {
+            let map_for_wdl = [1, 3, 0, 2, 0][(wdl + 2) as usize];
+            let i = part.map_idx[map_for_wdl] as usize + value as usize;
+            value = if part.flags & FLAG_WIDE != 0 { u16_le(data, self.map + 2 * i)? as i32 } else { byte(data, self.map + i)? as i32 };
+        }
+
+        let in_moves = (wdl == WIN && part.flags & FLAG_WIN_PLIES == 0)
+            || (wdl == LOSS && part.flags & FLAG_LOSS_PLIES == 0)
+            || wdl == CURSED_WIN
+            || wdl == BLESSED_LOSS;
+        if in_moves {
+            value *= 2;
+        }
+
+        Some(value + 1)
+    }
+}
+
+// encodes the three leading pieces of a table with a piece that isn't a king or a pair
+fn unique_pieces_index(squares: &[usize]) -> u64 {
+    let ix = indices();
+    let rank = |sq: usize| (sq / 8) as u64;
+    let (s0, s1, s2) = (squares[0], squares[1], squares[2]);
+    let adjust1 = (s1 > s0) as u64;
+    let adjust2 = (s2 > s0) as u64 + (s2 > s1) as u64;
+
+    if off_diagonal(s0) != 0 {
+        (ix.map_a1d1d4[s0] as u64 * 63 + (s1 as u64 - adjust1)) * 62 + s2 as u64 - adjust2
+    }
+    else if off_diagonal(s1) != 0 {
+        (6 * 63 + rank(s0) * 28 + ix.map_b1h1h7[s1]) * 62 + s2 as u64 - adjust2
+    }
+    else if off_diagonal(s2) != 0 {
+        6 * 63 * 62 + 4 * 28 * 62 + rank(s0) * 7 * 28 + (rank(s1) - adjust1) * 28 + ix.map_b1h1h7[s2]
+    }
+    else {
+        6 * 63 * 62 + 4 * 28 * 62 + 4 * 7 * 28 + rank(s0) * 7 * 6 + (rank(s1) - adjust1) * 6 + (rank(s2) - adjust2)
+    }
+}
+
+// returns a side's pieces as they are written in table names, e.g. "KRP"
+fn side_name(board: &Board, player: Player) -> String {
+    [(PieceType::K, 'K'), (PieceType::Q, 'Q'), (PieceType::R, 'R'), (PieceType::B, 'B'), (PieceType::N, 'N'), (PieceType::P, 'P')].iter()
+        .flat_map(|&(piece, letter)| std::iter::repeat_n(letter, board.count_piece(player, piece) as usize))
+        .collect()
+}
+
+fn is_table_name(name: &str) -> bool {
+    match name.split_once('v') {
+        Some((white, black)) => {
+            let valid_side = |side: &str| side.starts_with('K') && side.chars().all(|c| "KQRBNP".contains(c));
+            valid_side(white) && valid_side(black) && white.len() + black.len() <= MAX_PIECES
+        }
+        None => false,
+    }
+}
+
+// the DTZ of the move before a capture or pawn move, which the tables don't store
+fn dtz_before_zeroing(wdl: i32) -> i32 {
+    match wdl {
+        WIN => 1,
+        CURSED_WIN => 101,
+        BLESSED_LOSS => -101,
+        LOSS => -1,
+        _ => 0,
+    }
+}
+
+// a table file, which is mapped the first time it is probed. The table is None if it couldn't be read
+struct TableFile {
+    path: PathBuf,
+    table: OnceLock<Option<Table>>,
+}
+
+impl TableFile {
+    fn new(path: PathBuf) -> TableFile {
+        TableFile { path, table: OnceLock::new() }
+    }
+}
+
+/// The table files found in the Syzygy directories
+pub struct Tablebases {
+    // the maps don't change after they are filled in, so probing doesn't take a lock
+    wdl_files: HashMap<String, TableFile>,
+    dtz_files: HashMap<String, TableFile>,
+    max_pieces: u8,
+}
+
+impl Tablebases {
+    /// finds the tables in a list of directories separated by ':'
+    pub fn open(paths: &str) -> Result<Tablebases, String> {
+        let mut wdl_files = HashMap::new();
+        let mut dtz_files = HashMap::new();
+
+        for dir in paths.split(':').filter(|dir| !dir.is_empty()) {
+            let entries = fs::read_dir(dir).map_err(|e| format!("Could not read Syzygy directory {}: {}", dir, e))?;
+            for path in entries.flatten().map(|entry| entry.path()) {
+                let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+                    continue;
+                };
+
+                if let Some(name) = file_name.strip_suffix(WDL_SUFFIX).filter(|name| is_table_name(name)) {
+                    wdl_files.insert(name.to_string(), TableFile::new(path.clone()));
+                }
+                else if let Some(name) = file_name.strip_suffix(DTZ_SUFFIX).filter(|name| is_table_name(name)) {
+                    dtz_files.insert(name.to_string(), TableFile::new(path.clone()));
+                }
+            }
+        }
+
+        let max_pieces = wdl_files.keys().map(|name| name.len() as u8 - 1).max().unwrap_or(0);
+        Ok(Tablebases { wdl_files, dtz_files, max_pieces })
+    }
+
+    pub fn num_tables(&self) -> usize {
+        self.wdl_files.len() + self.dtz_files.len()
+    }
+
+    pub fn max_pieces(&self) -> u8 {
+        self.max_pieces
+    }
+
+    /// returns the result of the position for the side to move, or None if it isn't in the tables
+    pub fn probe_wdl(&self, board: &mut Board) -> Option<Wdl> {
+        if !self.can_probe(board) {
+            return None;
+        }
+        self.search(board, false).map(|(wdl, _)| Wdl::from_value(wdl))
+    }
+
+    /// returns the distance to the next capture or pawn move with perfect play, in plies. Positive
+    /// if the side to move wins, negative if it loses and 0 for a draw
+    pub fn probe_dtz(&self, board: &mut Board) -> Option<i32> {
+        if !self.can_probe(board) {
+            return None;
+        }
+        self.dtz(board)
+    }
+
+    /// returns the move that wins quickest without running into the fifty move rule, or that loses
+    /// slowest, along with its DTZ. None if the position isn't in the tables
+    pub fn best_move(&self, board: &mut Board) -> Option<(BitMove, i32)> {
+        if !self.can_probe(board) {
+            return None;
+        }
+
+        let rule_50 = board.rule_50() as i32;
+        let mut best: Option<(BitMove, i32, i32)> = None;
+
+        for mv in board.generate_moves() {
+            board.apply_move(mv);
+            let dtz = self.move_dtz(board);
+            board.undo_move();
+            let dtz = dtz?;
+
+            // wins that come before the fifty move rule rank above cursed ones, and long losses rank
+            // above short ones
+            let rank = if dtz > 0 {
+                (1000 - (dtz + rule_50)).max(1)
+            }
+            else if dtz < 0 {
+                (-1000 - dtz + rule_50).min(-1)
+            }
+            else {
+                0
+            };
+
+            if best.is_none_or(|(_, _, best_rank)| rank > best_rank) {
+                best = Some((mv, dtz, rank));
+            }
+        }
+
+        best.map(|(mv, dtz, _)| (mv, dtz))
+    }
+
+    // the tables only have positions without castling rights and with few enough pieces
+    fn can_probe(&self, board: &Board) -> bool {
+        board.count_all_pieces() <= self.max_pieces && board.castling_bits() == 0
+    }
+
+    fn table(&self, board: &Board, dtz: bool) -> Option<&Table> {
+        let white = side_name(board, Player::White);
+        let black = side_name(board, Player::Black);
+        let files = if dtz { &self.dtz_files } else { &self.wdl_files };
+        let file = files.get(&format!("{}v{}", white, black)).or_else(|| files.get(&format!("{}v{}", black, white)))?;
+
+        file.table
+            .get_or_init(|| match Table::open(&file.path, dtz) {
+                Ok(table) => Some(table),
+                Err(e) => {
+                    eprintln!("Could not load tablebase: {}", e);
+                    None
+                }
+            })
+            .as_ref()
+    }
+
+    fn wdl_table_value(&self, board: &Board) -> Option<i32> {
+        if board.count_all_pieces() == 2 {
+            return Some(DRAW);
+        }
+        // WDL tables store both sides to move
+        self.table(board, false)?.probe(board, DRAW)?
+    }
+
+    // returns the WDL of a position and whether a capture (or pawn move, if `check_zeroing_moves`)
+    // is the best move. Tables don't store exact values where a capture is at least as good as the
+    // stored value, so captures have to be searched as well
+    fn search(&self, board: &mut Board, check_zeroing_moves: bool) -> Option<(i32, bool)> {
+        let moves = board.generate_moves();
+        if moves.is_empty() {
+            return Some((if board.in_check() { LOSS } else { DRAW }, false));
+        }
+
+        let mut zeroing_moves = 0;
+        let mut best = LOSS;
+        for &mv in moves.iter() {
+            let zeroing = board.is_capture(mv) || (check_zeroing_moves && board.moved_piece(mv).type_of() == PieceType::P);
+            if !zeroing {
+                continue;
+            }
+            zeroing_moves += 1;
+
+            board.apply_move(mv);
+            let result = self.search(board, false);
+            board.undo_move();
+
+            let value = -result?.0;
+            if value > best {
+                best = value;
+                if value >= WIN {
+                    return Some((value, true));
+                }
+            }
+        }
+
+        // if every move was searched the stored value might be wrong, e.g. when the only moves are
+        // en passant captures, which the tables don't know about
+        let all_moves_searched = zeroing_moves == moves.len();
+        let value = if all_moves_searched { best } else { self.wdl_table_value(board)? };
+
+        if best >= value {
+            return Some((best, best > DRAW || all_moves_searched));
+        }
+        Some((value, false))
+    }
+
+    fn dtz(&self, board: &mut Board) -> Option<i32> {
+        if board.generate_moves().is_empty() {
+            return Some(if board.in_check() { -1 } else { 0 });
+        }
+
+        let (wdl, zeroing_is_best) = self.search(board, true)?;
+        if wdl == DRAW {
+            return Some(0);
+        }
+        if zeroing_is_best {
+            return Some(dtz_before_zeroing(wdl));
+        }
+
+        if let Some(dtz) = self.table(board, true)?.probe(board, wdl)? {
+            let cursed = wdl == CURSED_WIN || wdl == BLESSED_LOSS;
+            return Some((dtz + if cursed { 100 } else { 0 }) * wdl.signum());
+        }
+
+        // the table only stores the other side to move, so take the best DTZ after each move
+        let mut min_dtz = i32::MAX;
+        for mv in board.generate_moves() {
+            let zeroing = board.is_capture(mv) || board.moved_piece(mv).type_of() == PieceType::P;
+
+            board.apply_move(mv);
+            let result = if zeroing { self.search(board, false).map(|(wdl, _)| -dtz_before_zeroing(wdl)) } else { self.dtz(board).map(|dtz| -dtz) };
+            let is_mate = board.in_check() && board.generate_moves().is_empty();
+            board.undo_move();
+
+            let mut dtz = result?;
+            if dtz == 1 && is_mate {
+                min_dtz = 1;
+            }
+            if !zeroing {
+                dtz += dtz.signum();
+            }
+            if dtz < min_dtz && dtz.signum() == wdl.signum() {
+                min_dtz = dtz;
+            }
+        }
+
+        Some(if min_dtz == i32::MAX { -1 } else { min_dtz })
+    }
+
+    // returns the DTZ of the move just made on the board, from the point of view of the side that made it
+    fn move_dtz(&self, board: &mut Board) -> Option<i32> {
+        let mut dtz = if board.rule_50() == 0 {
+            dtz_before_zeroing(-self.search(board, false)?.0)
+        }
+        else {
+            let dtz = -self.dtz(board)?;
+            dtz + dtz.signum()
+        };
+
+        // a mate has a DTZ of 1
+        if dtz == 2 && board.in_check() && board.generate_moves().is_empty() {
+            dtz = 1;
+        }
+        Some(dtz)
+    }
+}
+
+/// finds the tables in the directories named by RUST_BOT_SYZYGY_PATH, if it is set. Should be
+/// called once at startup
+pub fn init_tablebases_from_env() -> Result<(), String> {
+    let tablebases = match env::var("RUST_BOT_SYZYGY_PATH") {
+        Ok(paths) if !paths.is_empty() => Some(Tablebases::open(&paths)?).filter(|tablebases| tablebases.num_tables() > 0),
+        _ => None,
+    };
+
+    TABLEBASES.set(tablebases).map_err(|_| "Tablebases have already been initialised".to_string())
+}
+
+/// returns the tablebases to probe, or None if there aren't any
+pub fn tablebases() -> Option<&'static Tablebases> {
+    TABLEBASES.get().and_then(|tablebases| tablebases.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    // writes KQvK.rtbw to its own directory. The table has a single value for each side to move:
+    // white to move always wins, and black to move loses apart from stalemates and taking the
+    // queen, which the probe finds by itself, so it gives the true result of every position
+    fn write_kqvk(name: &str, tail: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("syzygy-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut data = WDL_MAGIC.to_vec();
+        // no pawns, the leading group first, then the pieces for each side to move: K, Q and k
+        data.extend([0, 0x00, 0x66, 0x55, 0xEE, 0]);
+        data.extend(tail);
+        data.resize(data.len().max(64), 0);
+        fs::write(dir.join("KQvK.rtbw"), data).unwrap();
+        dir
+    }
+
+    fn single_value_kqvk(name: &str) -> PathBuf {
+        // single value parts, with values stored 2 higher than the results
+        write_kqvk(name, &[FLAG_SINGLE_VALUE, (WIN + 2) as u8, FLAG_SINGLE_VALUE, (LOSS + 2) as u8])
+    }
+
+    fn open(dir: &Path) -> Tablebases {
+        Tablebases::open(dir.to_str().unwrap()).unwrap()
+    }
+
+    fn wdl(tablebases: &Tablebases, fen: &str) -> Option<Wdl> {
+        tablebases.probe_wdl(&mut Board::from_fen(fen).unwrap())
+    }
+
+    fn dtz(tablebases: &Tablebases, fen: &str) -> Option<i32> {
+        tablebases.probe_dtz(&mut Board::from_fen(fen).unwrap())
+    }
+
+    #[test]
+    fn index_tables_have_the_sizes_of_the_format() {
+        let ix = indices();
+
+        let kk_codes: std::collections::HashSet<u64> = (0..10)
+            .flat_map(|idx| (0..64).map(move |sq| ix.map_kk[idx][sq]))
+            .collect();
+        assert_eq!(kk_codes.len(), 462);
+        assert_eq!(kk_codes.iter().max(), Some(&461));
+
+        assert_eq!(ix.binomial[2][6], 15);
+        assert_eq!(ix.binomial[3][48], 17296);
+        assert_eq!(ix.map_b1h1h7.iter().max(), Some(&27));
+        // a1, b2, c3 and d4 are numbered after the 6 squares below the diagonal
+        assert_eq!([0, 9, 18, 27].map(|sq| ix.map_a1d1d4[sq]), [6, 7, 8, 9]);
+        // a single pawn on the a-file can be on 6 squares
+        assert_eq!(ix.lead_pawns_size[1], [6, 6, 6, 6]);
+    }
+
+    #[test]
+    fn probes_wdl_from_a_table() {
+        let tablebases = open(&single_value_kqvk("wdl"));
+        assert_eq!(tablebases.max_pieces(), 3);
+
+        assert_eq!(wdl(&tablebases, "8/8/8/4k3/8/8/8/KQ6 w - - 0 1"), Some(Wdl::Win));
+        assert_eq!(wdl(&tablebases, "8/8/8/4k3/8/8/8/KQ6 b - - 0 1"), Some(Wdl::Loss));
+        // the colours are swapped to look up KvKQ
+        assert_eq!(wdl(&tablebases, "8/8/8/4K3/8/8/8/kq6 b - - 0 1"), Some(Wdl::Win));
+        // black takes the queen
+        assert_eq!(wdl(&tablebases, "8/8/8/4k3/4Q3/8/8/K7 b - - 0 1"), Some(Wdl::Draw));
+        // stalemate
+        assert_eq!(wdl(&tablebases, "k7/8/1Q6/8/8/8/8/2K5 b - - 0 1"), Some(Wdl::Draw));
+        // there is no KRvK table
+        assert_eq!(wdl(&tablebases, "8/8/8/4k3/8/8/8/KR6 w - - 0 1"), None);
+    }
+
+    #[test]
+    fn probes_dtz_without_a_dtz_table_where_the_result_is_known() {
+        let tablebases = open(&single_value_kqvk("dtz"));
+
+        assert_eq!(dtz(&tablebases, "k7/1Q6/1K6/8/8/8/8/8 b - - 0 1"), Some(-1));
+        assert_eq!(dtz(&tablebases, "k7/8/1Q6/8/8/8/8/2K5 b - - 0 1"), Some(0));
+        assert_eq!(dtz(&tablebases, "8/8/8/4k3/4Q3/8/8/K7 b - - 0 1"), Some(0));
+        // anything else needs KQvK.rtbz
+        assert_eq!(dtz(&tablebases, "8/8/8/4k3/8/8/8/KQ6 w - - 0 1"), None);
+    }
+
+    // real tables from https://tablebase.lichess.ovh/tables/standard/3-4-5/, checked in under
+    // testdata/syzygy. The expected values agree with the tables `retrograde` generates, where the
+    // distance to zeroing in KRvK is the distance to mate, as nothing can be captured or pushed
+    #[test]
+    #[ignore = "needs KRvK and KPvK .rtbw and .rtbz in testdata/syzygy, see its readme"]
+    fn real_tables_give_known_results() {
+        let tablebases = open(&Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/syzygy"));
+
+        // Ra8 mates
+        assert_eq!(wdl(&tablebases, "7k/8/6K1/8/8/8/8/R7 w - - 0 1"), Some(Wdl::Win));
+        assert_eq!(dtz(&tablebases, "7k/8/6K1/8/8/8/8/R7 w - - 0 1"), Some(1));
+        // Kg8 then Ra8 mates
+        assert_eq!(wdl(&tablebases, "7k/8/6K1/8/8/8/8/R7 b - - 0 1"), Some(Wdl::Loss));
+        assert_eq!(dtz(&tablebases, "7k/8/6K1/8/8/8/8/R7 b - - 0 1"), Some(-2));
+        assert_eq!(dtz(&tablebases, "8/8/4k3/8/3K4/8/8/R7 w - - 0 1"), Some(21));
+        assert_eq!(dtz(&tablebases, "8/8/4k3/8/3K4/8/8/R7 b - - 0 1"), Some(-22));
+        // the longest KRvK mates, with each side to move
+        assert_eq!(dtz(&tablebases, "8/8/8/8/8/2k5/1R6/K7 w - - 0 1"), Some(31));
+        assert_eq!(dtz(&tablebases, "8/8/8/8/8/8/8/KRk5 b - - 0 1"), Some(-30));
+        // the colours are swapped to look up KvKR
+        assert_eq!(dtz(&tablebases, "r7/8/8/8/8/6k1/8/7K b - - 0 1"), Some(1));
+        // black takes the rook
+        assert_eq!(wdl(&tablebases, "8/8/8/8/8/8/6kR/K7 b - - 0 1"), Some(Wdl::Draw));
+        assert_eq!(dtz(&tablebases, "8/8/8/8/8/8/6kR/K7 b - - 0 1"), Some(0));
+
+        // the king on the sixth rank in front of its pawn wins whoever is to move
+        assert_eq!(wdl(&tablebases, "4k3/8/4K3/4P3/8/8/8/8 w - - 0 1"), Some(Wdl::Win));
+        assert_eq!(dtz(&tablebases, "4k3/8/4K3/4P3/8/8/8/8 w - - 0 1"), Some(3));
+        assert_eq!(wdl(&tablebases, "4k3/8/4K3/4P3/8/8/8/8 b - - 0 1"), Some(Wdl::Loss));
+        assert_eq!(dtz(&tablebases, "4k3/8/4K3/4P3/8/8/8/8 b - - 0 1"), Some(-4));
+        // and the same with the colours swapped
+        assert_eq!(dtz(&tablebases, "8/8/8/8/4p3/4k3/8/4K3 w - - 0 1"), Some(-4));
+        // the pawn promotes
+        assert_eq!(dtz(&tablebases, "8/4P3/8/8/8/k7/8/4K3 w - - 0 1"), Some(1));
+        // the king in front of the pawn holds the draw, as it does in the corner against a rook pawn
+        assert_eq!(wdl(&tablebases, "8/8/8/8/8/4k3/4P3/4K3 w - - 0 1"), Some(Wdl::Draw));
+        assert_eq!(dtz(&tablebases, "8/8/8/8/8/4k3/4P3/4K3 w - - 0 1"), Some(0));
+        assert_eq!(wdl(&tablebases, "k7/8/K7/P7/8/8/8/8 w - - 0 1"), Some(Wdl::Draw));
+        assert_eq!(wdl(&tablebases, "k7/8/K7/P7/8/8/8/8 b - - 0 1"), Some(Wdl::Draw));
+    }
+
+    #[test]
+    fn truncated_table_is_not_probed() {
+        let dir = single_value_kqvk("truncated");
+        let path = dir.join("KQvK.rtbw");
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..12]).unwrap();
+
+        assert_eq!(wdl(&open(&dir), "8/8/8/4k3/8/8/8/KQ6 w - - 0 1"), None);
+    }
+
+    #[test]
+    fn corrupt_tables_do_not_panic() {
+        let positions = ["8/8/8/4k3/8/8/8/KQ6 w - - 0 1", "8/8/8/4k3/8/8/8/KQ6 b - - 0 1", "3k4/8/8/8/8/8/2Q5/6K1 b - - 0 1"];
+        let mut seed: u64 = 0x9E37_79B9_7F4A_7C15;
+        for i in 0..200 {
+            let tail: Vec<u8> = (0..200).map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (seed >> 56) as u8
+            }).collect();
+            let tablebases = open(&write_kqvk(&format!("corrupt{}", i), &tail));
+
+            // any result will do, as long as it doesn't panic
+            for fen in positions {
+                wdl(&tablebases, fen);
+            }
+        }
+    }
+}
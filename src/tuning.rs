@@ -0,0 +1,350 @@
+// Texel-style tuning of the piece values and score grids. This is an offline tool, it is not used in
+// the lambda function.
+use crate::eval_params;
+use crate::evaluation;
+use crate::endgame::recognise;
+use crate::personality::personality;
+
+use pleco::{Board,Player};
+use std::fs;
+use eval_params::{EvalParams, PARAM_PIECES};
+use evaluation::{evaluate_with, is_draw, score_grid_cell};
+
+// piece values come first in the parameter vector, followed by the 64 cells of each score grid
+const NUM_PARAMS: usize = 6 + 6 * 64;
+const KING_VALUE_INDEX: usize = 5;
+
+// Adam optimiser settings. The learning rate is in centipawns per step
+const LEARNING_RATE: f64 = 1.0;
+const BETA1: f64 = 0.9;
+const BETA2: f64 = 0.999;
+const EPSILON: f64 = 1e-8;
+
+/// A labelled position, stored as the parts of its evaluation that depend on the tuned parameters
+struct TuningPosition {
+    // game result from white's point of view: 1 for a win, 0.5 for a draw and 0 for a loss
+    result: f64,
+    // the part of the evaluation that doesn't depend on the tuned parameters
+    fixed_score: f64,
+    // (parameter index, coefficient) pairs. The evaluation is `fixed_score + sum(coefficient * parameter)`
+    features: Vec<(usize, f64)>,
+}
+
+impl TuningPosition {
+    fn evaluate(&self, weights: &[f64]) -> f64 {
+        self.fixed_score + self.features.iter().map(|&(i, coefficient)| coefficient * weights[i]).sum::<f64>()
+    }
+}
+
+// tunes the parameters in use against the labelled positions in `positions_path`, and writes the
+// result to `output_path` in the format RUST_BOT_EVAL_PARAMS files are read in
+pub fn tune(positions_path: &str, output_path: &str, epochs: usize) -> Result<(), String> {
+    let base_params = eval_params::params().clone();
+    let positions = load_positions(positions_path, &base_params)?;
+    if positions.is_empty() {
+        return Err(format!("No labelled positions found in {}", positions_path));
+    }
+    println!("loaded {} positions", positions.len());
+
+    let mut weights = params_to_weights(&base_params);
+    let k = find_best_k(&positions, &weights);
+    println!("using K = {:.3}, initial loss: {:.6}", k, loss(&positions, &weights, k));
+
+    let mut m = vec![0.0; NUM_PARAMS];
+    let mut v = vec![0.0; NUM_PARAMS];
+
+    for epoch in 1..=epochs {
+        let gradient = gradient(&positions, &weights, k);
+        adam_step(&mut weights, &gradient, &mut m, &mut v, epoch);
+
+        if epoch % 10 == 0 || epoch == epochs {
+            println!("epoch {}: loss {:.6}", epoch, loss(&positions, &weights, k));
+        }
+    }
+
+    let tuned_params = weights_to_params(&weights);
+    fs::write(output_path, tuned_params.to_pretty_json()).map_err(|e| format!("Could not write {}: {}", output_path, e))?;
+    println!("wrote tuned parameters to {}", output_path);
+
+    Ok(())
+}
+
+// moves the weights one step against the gradient. `m` and `v` are the running averages of the
+// gradient and its square, and `epoch` counts from 1
+fn adam_step(weights: &mut [f64], gradient: &[f64], m: &mut [f64], v: &mut [f64], epoch: usize) {
+    for i in 0..NUM_PARAMS {
+        // both sides always have exactly one king, so its value can't be learned
+        if i == KING_VALUE_INDEX {
+            continue;
+        }
+
+        m[i] = BETA1 * m[i] + (1.0 - BETA1) * gradient[i];
+        v[i] = BETA2 * v[i] + (1.0 - BETA2) * gradient[i] * gradient[i];
+        let m_hat = m[i] / (1.0 - BETA1.powi(epoch as i32));
+        let v_hat = v[i] / (1.0 - BETA2.powi(epoch as i32));
+        weights[i] -= LEARNING_RATE * m_hat / (v_hat.sqrt() + EPSILON);
+    }
+}
+
+// reads one labelled position per line. Each line is a FEN followed by the game result, e.g.
+// `<fen>;1-0`, `<fen> [0.5]` or an EPD line with `c9 "1/2-1/2";`
+fn load_positions(path: &str, base_params: &EvalParams) -> Result<Vec<TuningPosition>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    let mut positions = Vec::new();
+
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (fen, result) = parse_labelled_line(line).ok_or_else(|| format!("Line {}: expected a FEN followed by a result", line_number + 1))?;
+        let board = Board::from_fen(&fen).map_err(|e| format!("Line {}: invalid FEN: {:?}", line_number + 1, e))?;
+
+        // the result of a finished game says nothing about the weights, and recognised endgames are
+        // scored by their own rules rather than the sum of the weights the tuner fits
+        if board.checkmate() || is_draw(&board) || recognise(&board, base_params).is_some() {
+            continue;
+        }
+
+        positions.push(to_tuning_position(&board, result, base_params));
+    }
+
+    Ok(positions)
+}
+
+// reads a FEN, or the four position fields of an EPD line, followed by the result. The result is
+// either the `c9` opcode of an EPD line or a label after the position, e.g. `;1-0` or `[0.5]`
+fn parse_labelled_line(line: &str) -> Option<(String, f64)> {
+    let fen_end = line.find([';', '|', '[', '"']).unwrap_or(line.len());
+    let fields: Vec<&str> = line[..fen_end].split_whitespace().collect();
+    if fields.len() < 4 {
+        return None;
+    }
+
+    // EPD lines leave out the move counters and put opcodes after the position
+    let has_counters = fields.len() >= 6 && fields[4..6].iter().all(|field| field.parse::<u32>().is_ok());
+    let (fen, rest) = if has_counters {
+        (fields[..6].join(" "), &fields[6..])
+    } else {
+        (format!("{} 0 1", fields[..4].join(" ")), &fields[4..])
+    };
+    let label = format!("{} {}", rest.join(" "), &line[fen_end..]);
+
+    let label = match epd_operand(&label, "c9") {
+        Some(result) => result,
+        None => label.as_str(),
+    };
+    Some((fen, parse_result(label)?))
+}
+
+// returns the operand of an EPD opcode, without its quotes
+fn epd_operand<'a>(opcodes: &'a str, opcode: &str) -> Option<&'a str> {
+    opcodes.split(';')
+        .filter_map(|operation| operation.trim().strip_prefix(opcode))
+        .find(|operand| operand.starts_with(char::is_whitespace))
+        .map(|operand| operand.trim().trim_matches('"'))
+}
+
+fn parse_result(label: &str) -> Option<f64> {
+    if label.contains("1/2-1/2") || label.contains("0.5") {
+        Some(0.5)
+    }
+    else if label.contains("1-0") || label.contains("1.0") {
+        Some(1.0)
+    }
+    else if label.contains("0-1") || label.contains("0.0") {
+        Some(0.0)
+    }
+    else {
+        None
+    }
+}
+
+fn to_tuning_position(board: &Board, result: f64, base_params: &EvalParams) -> TuningPosition {
+    let mut features = Vec::new();
+
+    for (sq, piece) in board.get_piece_locations() {
+        let player = match piece.player() {
+            Some(x) => x,
+            None => Player::White
+        };
+        let Some(piece_index) = PARAM_PIECES.iter().position(|&p| p == piece.type_of()) else {
+            continue;
+        };
+
+        let coefficient = if player == Player::White { 1.0 } else { -1.0 };
+        let (row, col) = score_grid_cell(sq, player);
+        // the evaluation scales piece values by the personality, so the tuned values are unscaled
+        features.push((piece_index, coefficient * personality().material as f64 / 100.0));
+        features.push((6 + piece_index * 64 + row * 8 + col, coefficient));
+    }
+
+    let base_weights = params_to_weights(base_params);
+    let mut position = TuningPosition { result, fixed_score: 0.0, features };
+    position.fixed_score = evaluate_with(board, base_params) as f64 - position.evaluate(&base_weights);
+    position
+}
+
+fn params_to_weights(params: &EvalParams) -> Vec<f64> {
+    let mut weights: Vec<f64> = params.piece_values.iter().map(|&v| v as f64).collect();
+    for grid in &params.score_grids {
+        weights.extend(grid.iter().flatten().map(|&v| v as f64));
+    }
+    weights
+}
+
+fn weights_to_params(weights: &[f64]) -> EvalParams {
+    let mut params = EvalParams::default();
+
+    for (i, value) in params.piece_values.iter_mut().enumerate() {
+        *value = (weights[i].round() as i32).max(1);
+    }
+    for (piece_index, grid) in params.score_grids.iter_mut().enumerate() {
+        for (cell, value) in grid.iter_mut().flatten().enumerate() {
+            *value = weights[6 + piece_index * 64 + cell].round() as i32;
+        }
+    }
+
+    params
+}
+
+// maps a score in centipawns to an expected game result between 0 and 1
+fn sigmoid(score: f64, k: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf(-k * score / 400.0))
+}
+
+// mean squared error between the game results and the results predicted by the evaluation
+fn loss(positions: &[TuningPosition], weights: &[f64], k: f64) -> f64 {
+    let total: f64 = positions.iter().map(|p| (p.result - sigmoid(p.evaluate(weights), k)).powi(2)).sum();
+    total / positions.len() as f64
+}
+
+fn gradient(positions: &[TuningPosition], weights: &[f64], k: f64) -> Vec<f64> {
+    let mut gradient = vec![0.0; NUM_PARAMS];
+    let scale = k * std::f64::consts::LN_10 / 400.0;
+
+    for position in positions {
+        let predicted = sigmoid(position.evaluate(weights), k);
+        let error_gradient = 2.0 * (predicted - position.result) * predicted * (1.0 - predicted) * scale;
+
+        for &(i, coefficient) in &position.features {
+            gradient[i] += error_gradient * coefficient;
+        }
+    }
+
+    for g in gradient.iter_mut() {
+        *g /= positions.len() as f64;
+    }
+    gradient
+}
+
+// finds the scaling constant that best fits the current evaluation to the results, so that tuning
+// only changes the relative size of the weights
+fn find_best_k(positions: &[TuningPosition], weights: &[f64]) -> f64 {
+    let mut best_k = 1.0;
+    let mut step = 0.5;
+
+    for _ in 0..10 {
+        let candidates = [best_k - step, best_k, best_k + step];
+        best_k = candidates.iter()
+            .copied()
+            .filter(|&k| k > 0.0)
+            .min_by(|&a, &b| loss(positions, weights, a).total_cmp(&loss(positions, weights, b)))
+            .unwrap_or(best_k);
+        step /= 2.0;
+    }
+
+    best_k
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq -";
+
+    fn parsed(line: &str) -> Option<(String, f64)> {
+        parse_labelled_line(&line.replace("<fen>", START))
+    }
+
+    #[test]
+    fn reads_fens_with_a_result_label() {
+        let fen = format!("{} 0 1", START);
+        assert_eq!(parsed("<fen> 0 1;1-0"), Some((fen.clone(), 1.0)));
+        assert_eq!(parsed("<fen> 0 1 [0.5]"), Some((fen.clone(), 0.5)));
+        assert_eq!(parsed("<fen> 0 1 | 0-1"), Some((fen.clone(), 0.0)));
+        assert_eq!(parsed("<fen> 0 1 \"1/2-1/2\""), Some((fen.clone(), 0.5)));
+        // the move counters are kept
+        assert_eq!(parsed("<fen> 12 40 [1.0]").unwrap().0, format!("{} 12 40", START));
+    }
+
+    #[test]
+    fn reads_epd_lines_with_other_opcodes() {
+        let fen = format!("{} 0 1", START);
+        assert_eq!(parsed("<fen> c9 \"0-1\";"), Some((fen.clone(), 0.0)));
+        assert_eq!(parsed("<fen> bm Nf3; c9 \"1-0\";"), Some((fen.clone(), 1.0)));
+        assert_eq!(parsed("<fen> bm Nf3; id \"pos 1.0\"; c9 \"1/2-1/2\";"), Some((fen.clone(), 0.5)));
+        assert_eq!(parsed("<fen>; c9 \"0-1\";"), Some((fen, 0.0)));
+    }
+
+    // middlegame positions with a material edge for one side or the other, and the results of the
+    // games they came from
+    const LABELLED: [(&str, f64); 6] = [
+        ("r1bqkb1r/pppp1ppp/2n2n2/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R w KQkq - 4 4", 0.5),
+        ("r1bqkb1r/pppp1ppp/5n2/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R w KQkq - 0 5", 1.0),
+        ("r1bqkb1r/pppp1ppp/2n2n2/4p3/4P3/5N2/PPPP1PPP/RNBQK2R w KQkq - 0 5", 0.0),
+        ("rnbqkb1r/ppp2ppp/4pn2/3p4/2PP4/2N5/PP2PPPP/R1BQKBNR w KQkq - 0 4", 0.5),
+        ("rnbqkb1r/ppp2ppp/4pn2/3p4/2PP4/8/PP2PPPP/R1BQKBNR w KQkq - 0 4", 0.0),
+        ("rnbqkb1r/ppp2ppp/4p3/3p4/2PP4/2N5/PP2PPPP/R1BQKBNR w KQkq - 0 4", 1.0),
+    ];
+
+    fn labelled_positions(params: &EvalParams) -> Vec<TuningPosition> {
+        LABELLED.iter().map(|&(fen, result)| to_tuning_position(&Board::from_fen(fen).unwrap(), result, params)).collect()
+    }
+
+    #[test]
+    fn linear_model_matches_the_evaluation() {
+        let base = EvalParams::default();
+        let mut changed = base.clone();
+        changed.piece_values[1] += 40;
+        changed.score_grids[0][4][3] += 15;
+        changed.score_grids[1][5][5] -= 10;
+
+        for (fen, _) in LABELLED {
+            let board = Board::from_fen(fen).unwrap();
+            let position = to_tuning_position(&board, 0.5, &base);
+            assert_eq!(position.evaluate(&params_to_weights(&base)).round() as i32, evaluate_with(&board, &base), "{}", fen);
+            // the weights the model is fitted with reach the evaluation the same way
+            assert_eq!(position.evaluate(&params_to_weights(&changed)).round() as i32, evaluate_with(&board, &changed), "{}", fen);
+        }
+    }
+
+    #[test]
+    fn adam_steps_reduce_the_loss() {
+        let base = EvalParams::default();
+        let positions = labelled_positions(&base);
+        let mut weights = params_to_weights(&base);
+        let k = find_best_k(&positions, &weights);
+        let initial_loss = loss(&positions, &weights, k);
+
+        let (mut m, mut v) = (vec![0.0; NUM_PARAMS], vec![0.0; NUM_PARAMS]);
+        for epoch in 1..=20 {
+            let gradient = gradient(&positions, &weights, k);
+            adam_step(&mut weights, &gradient, &mut m, &mut v, epoch);
+        }
+
+        assert!(loss(&positions, &weights, k) < initial_loss);
+        assert_eq!(weights[KING_VALUE_INDEX], base.piece_values[KING_VALUE_INDEX] as f64);
+        // the tuned weights read back as parameters
+        assert_ne!(weights_to_params(&weights), base);
+    }
+
+    #[test]
+    fn rejects_lines_without_a_position_or_result() {
+        assert_eq!(parsed("<fen> bm Nf3;"), None);
+        assert_eq!(parsed("<fen> 0 1"), None);
+        assert_eq!(parsed("8/8/8/8 w [1-0]"), None);
+        assert_eq!(parsed(""), None);
+    }
+}
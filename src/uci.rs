@@ -0,0 +1,290 @@
+// The UCI protocol, so the engine can be loaded in chess GUIs and tournament managers. The `uci`
+// command starts it: commands are read from stdin and answered on stdout until `quit`. Searches run
+// on their own thread, so `stop` and `isready` are answered while the engine is thinking.
+use crate::book::pick_book_move;
+use crate::minimax::{iterative_deepening, SearchInfo, SearchLimits};
+
+use pleco::{BitMove, Board, Player};
+use std::io::{self, BufRead};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const DEFAULT_MOVE_OVERHEAD: u64 = 50;
+
+// the options the engine has, set with `setoption`
+struct UciOptions {
+    // milliseconds kept back from each move for the time it takes the GUI to get the move
+    move_overhead: u64,
+    // play moves from the opening book set up with RUST_BOT_BOOK, if there is one
+    own_book: bool,
+    // resolve captures at the horizon. Without it a depth can end halfway through an exchange, so the
+    // score swings from one depth to the next
+    quiescence: bool,
+}
+
+impl Default for UciOptions {
+    fn default() -> Self {
+        UciOptions { move_overhead: DEFAULT_MOVE_OVERHEAD, own_book: true, quiescence: true }
+    }
+}
+
+// the arguments of a `go` command
+#[derive(Default)]
+struct GoCommand {
+    depth: Option<u8>,
+    movetime: Option<u64>,
+    wtime: Option<u64>,
+    btime: Option<u64>,
+    winc: u64,
+    binc: u64,
+    movestogo: Option<u64>,
+    infinite: bool,
+}
+
+struct UciEngine {
+    board: Board,
+    options: UciOptions,
+    search: Option<JoinHandle<()>>,
+    // set by `stop`, which stops the search and lets an infinite search send its move. Each search has
+    // its own
+    stop_requested: Arc<AtomicBool>,
+}
+
+/// reads UCI commands from stdin until `quit` or the end of the input
+pub fn run() {
+    let mut engine = UciEngine {
+        board: Board::start_pos(),
+        options: UciOptions::default(),
+        search: None,
+        stop_requested: Arc::new(AtomicBool::new(false)),
+    };
+
+    for line in io::stdin().lock().lines() {
+        let Ok(line) = line else { break };
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+
+        match tokens.first().copied() {
+            Some("uci") => {
+                println!("id name {} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+                println!("id author the {} authors", env!("CARGO_PKG_NAME"));
+                println!("option name Move Overhead type spin default {} min 0 max 5000", DEFAULT_MOVE_OVERHEAD);
+                println!("option name OwnBook type check default true");
+                println!("option name Quiescence type check default true");
+                println!("uciok");
+            }
+            Some("isready") => println!("readyok"),
+            Some("ucinewgame") => {
+                engine.stop();
+                engine.board = Board::start_pos();
+            }
+            Some("position") => {
+                engine.stop();
+                match parse_position(&tokens[1..]) {
+                    Ok(board) => engine.board = board,
+                    Err(e) => println!("info string {}", e),
+                }
+            }
+            Some("setoption") => {
+                if let Err(e) = engine.set_option(&tokens[1..]) {
+                    println!("info string {}", e);
+                }
+            }
+            Some("go") => {
+                engine.stop();
+                engine.go(parse_go(&tokens[1..]));
+            }
+            Some("stop") => engine.stop(),
+            Some("quit") => break,
+            // debug, register and ponderhit are part of the protocol but don't do anything here
+            _ => {}
+        }
+    }
+
+    engine.stop();
+}
+
+impl UciEngine {
+    // stops the search if there is one, and waits for it to send its move
+    fn stop(&mut self) {
+        if let Some(search) = self.search.take() {
+            self.stop_requested.store(true, Ordering::Relaxed);
+            search.join().ok();
+        }
+    }
+
+    // parses `name <name> [value <value>]`, where names can have spaces in them
+    fn set_option(&mut self, tokens: &[&str]) -> Result<(), String> {
+        let value_index = tokens.iter().position(|&token| token == "value");
+        let name = tokens.get(1..value_index.unwrap_or(tokens.len())).unwrap_or_default().join(" ");
+        let value = value_index.map(|index| tokens[index + 1..].join(" ")).unwrap_or_default();
+
+        match name.to_lowercase().as_str() {
+            "move overhead" => self.options.move_overhead = value.parse().map_err(|_| format!("Invalid Move Overhead {}", value))?,
+            "ownbook" => self.options.own_book = value == "true",
+            "quiescence" => self.options.quiescence = value == "true",
+            _ => return Err(format!("Unknown option {}", name)),
+        }
+        Ok(())
+    }
+
+    // starts searching the current position on another thread, which sends `bestmove` when it is done
+    fn go(&mut self, go: GoCommand) {
+        let board = self.board.shallow_clone();
+        let stop_requested = Arc::new(AtomicBool::new(false));
+        self.stop_requested = stop_requested.clone();
+        let limits = SearchLimits { stop: Some(stop_requested.clone()), ..search_limits(&go, board.turn(), &self.options) };
+        let own_book = self.options.own_book;
+        let infinite = go.infinite;
+
+        self.search = Some(thread::spawn(move || {
+            let turn = board.turn();
+            let book_move = if own_book && !infinite { pick_book_move(&board) } else { None };
+            let best_move = book_move.or_else(|| iterative_deepening(board, limits, |info| print_info(info, turn)));
+
+            // with `go infinite` the move is only sent after `stop`, even if the search ends sooner
+            while infinite && !stop_requested.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            println!("bestmove {}", best_move.map(|mv| mv.to_string()).unwrap_or_else(|| "0000".to_string()));
+        }));
+    }
+}
+
+// parses `startpos [moves ...]` or `fen <fen> [moves ...]`
+fn parse_position(tokens: &[&str]) -> Result<Board, String> {
+    let moves_index = tokens.iter().position(|&token| token == "moves").unwrap_or(tokens.len());
+    let mut board = match tokens.first() {
+        Some(&"startpos") => Board::start_pos(),
+        Some(&"fen") => {
+            let fen = tokens[1..moves_index].join(" ");
+            Board::from_fen(&fen).map_err(|e| format!("Invalid FEN {}: {:?}", fen, e))?
+        }
+        _ => return Err("Expected position startpos or position fen".to_string()),
+    };
+
+    for &uci_move in tokens.iter().skip(moves_index + 1) {
+        let mv = parse_move(&board, uci_move).ok_or_else(|| format!("Illegal move {} in {}", uci_move, board.fen()))?;
+        board.apply_move(mv);
+    }
+    Ok(board)
+}
+
+/// returns the legal move written in UCI format, like `e2e4`, `e7e8q` or `e1g1`
+pub fn parse_move(board: &Board, uci_move: &str) -> Option<BitMove> {
+    board.generate_moves().iter().copied().find(|mv| mv.to_string() == uci_move)
+}
+
+fn parse_go(tokens: &[&str]) -> GoCommand {
+    let mut go = GoCommand::default();
+    let mut tokens = tokens.iter();
+
+    while let Some(&token) = tokens.next() {
+        let mut number = || tokens.next().and_then(|value| value.parse::<i64>().ok()).map(|value| value.max(0) as u64);
+        match token {
+            "depth" => go.depth = number().map(|depth| depth.clamp(1, u8::MAX as u64) as u8),
+            "movetime" => go.movetime = number(),
+            "wtime" => go.wtime = number(),
+            "btime" => go.btime = number(),
+            "winc" => go.winc = number().unwrap_or(0),
+            "binc" => go.binc = number().unwrap_or(0),
+            "movestogo" => go.movestogo = number().filter(|&moves| moves > 0),
+            "infinite" => go.infinite = true,
+            _ => {}
+        }
+    }
+
+    go
+}
+
+// works out how long to search for, from the move time or the clock of the side to move
+fn search_limits(go: &GoCommand, turn: Player, options: &UciOptions) -> SearchLimits {
+    let limits = SearchLimits { depth: go.depth, quiescence: options.quiescence, ..Default::default() };
+    if go.infinite {
+        return limits;
+    }
+
+    let (time, increment) = if turn == Player::White { (go.wtime, go.winc) } else { (go.btime, go.binc) };
+    match (go.movetime, time) {
+        (Some(movetime), _) => limits.with_move_time(movetime.saturating_sub(options.move_overhead)),
+        (None, Some(time)) => limits.with_clock(time.saturating_sub(options.move_overhead), increment, go.movestogo),
+        (None, None) => limits,
+    }
+}
+
+// sends what the search found at a depth. Scores are from the point of view of the side to move, and
+// mates are given in moves
+fn print_info(info: &SearchInfo, turn: Player) {
+    let score = match info.mate_for(turn) {
+        Some(moves) => format!("mate {}", moves),
+        None => format!("cp {}", info.score_for(turn)),
+    };
+
+    let millis = info.elapsed.as_millis() as u64;
+    let nps = info.nodes * 1000 / millis.max(1);
+    println!("info depth {} score {} nodes {} nps {} time {} pv {}", info.depth, score, info.nodes, nps, millis, info.best_move);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(command: &str) -> Vec<&str> {
+        command.split_whitespace().collect()
+    }
+
+    #[test]
+    fn position_from_startpos_with_moves() {
+        let board = parse_position(&tokens("startpos moves e2e4 e7e5 g1f3")).unwrap();
+        assert_eq!(board.fen(), "rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2");
+    }
+
+    #[test]
+    fn position_from_fen_with_castling_and_promotion() {
+        let board = parse_position(&tokens("fen r3k3/1P6/8/8/8/8/8/R3K2R w KQq - 0 1 moves e1g1 e8d8 b7b8q")).unwrap();
+        assert_eq!(board.fen(), "rQ1k4/8/8/8/8/8/8/R4RK1 b - - 0 2");
+    }
+
+    #[test]
+    fn invalid_positions_are_errors() {
+        assert!(parse_position(&tokens("startpos moves e2e5")).is_err());
+        assert!(parse_position(&tokens("fen not a fen")).is_err());
+        assert!(parse_position(&tokens("moves e2e4")).is_err());
+    }
+
+    #[test]
+    fn go_arguments() {
+        let go = parse_go(&tokens("wtime 60000 btime 55000 winc 1000 binc 500 movestogo 20 depth 8"));
+        assert_eq!((go.wtime, go.btime, go.winc, go.binc, go.movestogo, go.depth), (Some(60000), Some(55000), 1000, 500, Some(20), Some(8)));
+        assert!(!go.infinite && go.movetime.is_none());
+
+        let go = parse_go(&tokens("infinite"));
+        assert!(go.infinite);
+        // negative clocks, which some GUIs send when a player is out of time, count as none left
+        assert_eq!(parse_go(&tokens("wtime -100 movestogo 0")).wtime, Some(0));
+        assert_eq!(parse_go(&tokens("movestogo 0")).movestogo, None);
+        assert_eq!(parse_go(&tokens("depth 0")).depth, Some(1));
+    }
+
+    #[test]
+    fn limits_from_move_time_and_clock() {
+        let options = UciOptions::default();
+        let limits = search_limits(&parse_go(&tokens("movetime 1000")), Player::White, &options);
+        assert_eq!(limits.soft_time, Some(Duration::from_millis(1000 - DEFAULT_MOVE_OVERHEAD)));
+        assert_eq!(limits.hard_time, limits.soft_time);
+        assert!(limits.quiescence);
+
+        // black's clock is used for black, shared out over the moves to go
+        let go = parse_go(&tokens("wtime 1000 btime 20050 binc 0 movestogo 10"));
+        let limits = search_limits(&go, Player::Black, &options);
+        assert_eq!(limits.soft_time, Some(Duration::from_millis(1000)));
+        assert_eq!(limits.hard_time, Some(Duration::from_millis(6000)));
+
+        let limits = search_limits(&parse_go(&tokens("infinite wtime 1000")), Player::White, &options);
+        assert!(limits.soft_time.is_none() && limits.hard_time.is_none());
+        let limits = search_limits(&parse_go(&tokens("depth 3")), Player::White, &options);
+        assert_eq!(limits.depth, Some(3));
+        assert!(limits.hard_time.is_none());
+    }
+}
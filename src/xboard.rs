@@ -0,0 +1,439 @@
+// The XBoard protocol (CECP), for GUIs and testing tools that don't speak UCI. The `xboard` command
+// starts it. Unlike UCI, the engine keeps track of the game itself: it is told the opponent's moves
+// and decides when to think and move. Commands are read on one thread and searches run on another,
+// and both send their events to the main loop, so `?`, `force` and the rest work while it thinks.
+use crate::book::pick_book_move;
+use crate::minimax::{iterative_deepening, SearchInfo, SearchLimits};
+use crate::uci::parse_move;
+
+use pleco::{BitBoard, BitMove, Board, PieceType, Player};
+use std::io::{self, BufRead};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+// milliseconds kept back from each move for the time it takes the GUI to get the move
+const MOVE_OVERHEAD: u64 = 50;
+// mates are reported as this plus the number of moves, which GUIs show as a mate
+const XBOARD_MATE_SCORE: i32 = 100000;
+
+enum Event {
+    Command(String),
+    // the search with this id has finished with this move
+    SearchDone(u64, Option<BitMove>),
+}
+
+struct XboardEngine {
+    board: Board,
+    // the side the engine plays, or None in force mode, where it only keeps track of the moves
+    engine_side: Option<Player>,
+    // moves per time control (0 for the whole game) and increment in milliseconds, from `level`. The
+    // base time isn't needed, because the GUI sends the clock with `time` before each move
+    moves_per_session: u64,
+    increment: u64,
+    // fixed time per move in milliseconds, from `st`
+    move_time: Option<u64>,
+    // maximum depth, from `sd`
+    max_depth: Option<u8>,
+    // the engine's clock in milliseconds, from `time`
+    clock: Option<u64>,
+    // print the search's progress, turned on by `post`
+    post: bool,
+    search: Option<JoinHandle<()>>,
+    // set to make the running search send the best move it has
+    stop: Arc<AtomicBool>,
+    // results of searches with older ids are thrown away, which is how a search is cancelled
+    search_id: u64,
+    events: Sender<Event>,
+}
+
+/// reads XBoard commands from stdin until `quit` or the end of the input
+pub fn run() {
+    let (events, receiver) = mpsc::channel();
+
+    let command_events = events.clone();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if command_events.send(Event::Command(line)).is_err() {
+                return;
+            }
+        }
+        command_events.send(Event::Command("quit".to_string())).ok();
+    });
+
+    let mut engine = XboardEngine::new(events);
+
+    for event in receiver {
+        match event {
+            Event::Command(line) => {
+                if !engine.handle_command(&line) {
+                    break;
+                }
+            }
+            Event::SearchDone(id, best_move) => {
+                if id == engine.search_id {
+                    engine.play_search_result(best_move);
+                }
+            }
+        }
+    }
+
+    engine.cancel_search();
+}
+
+impl XboardEngine {
+    fn new(events: Sender<Event>) -> XboardEngine {
+        XboardEngine {
+            board: Board::start_pos(),
+            engine_side: Some(Player::Black),
+            moves_per_session: 0,
+            increment: 0,
+            move_time: None,
+            max_depth: None,
+            clock: None,
+            post: false,
+            search: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            search_id: 0,
+            events,
+        }
+    }
+
+    // returns false once the engine should quit
+    fn handle_command(&mut self, line: &str) -> bool {
+        let (command, args) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+        let args = args.trim();
+
+        match command {
+            "protover" => {
+                println!("feature myname=\"{} {}\" ping=1 setboard=1 usermove=1 time=1 draw=0 sigint=0 sigterm=0 reuse=1 analyze=0 colors=0 done=1",
+                    env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            }
+            "new" => {
+                self.cancel_search();
+                self.board = Board::start_pos();
+                self.engine_side = Some(Player::Black);
+                // the GUI sends the time control again for each game, and the clock of the last game
+                // is no use in this one
+                self.max_depth = None;
+                self.move_time = None;
+                self.clock = None;
+            }
+            "force" | "result" => {
+                // after a result the game is over, so the engine waits for `new` or `go`
+                self.cancel_search();
+                self.engine_side = None;
+            }
+            "go" => {
+                self.cancel_search();
+                self.engine_side = Some(self.board.turn());
+                self.start_search();
+            }
+            "playother" => {
+                self.cancel_search();
+                self.engine_side = Some(!self.board.turn());
+            }
+            "usermove" => self.user_move(args),
+            "?" => {
+                // move now: the search sends the best move it has
+                if self.search.is_some() {
+                    self.stop.store(true, Ordering::Relaxed);
+                }
+            }
+            "level" => {
+                if let Err(e) = self.set_level(args) {
+                    println!("Error ({}): level {}", e, args);
+                }
+            }
+            "st" => match args.parse::<f64>() {
+                Ok(seconds) => self.move_time = Some((seconds * 1000.0) as u64),
+                Err(_) => println!("Error (invalid time): st {}", args),
+            },
+            "sd" => match args.parse::<u8>() {
+                Ok(depth) => self.max_depth = Some(depth.max(1)),
+                Err(_) => println!("Error (invalid depth): sd {}", args),
+            },
+            // times are in centiseconds. The opponent's clock doesn't change how long the engine thinks
+            "time" => self.clock = args.parse::<u64>().ok().map(|centiseconds| centiseconds * 10),
+            "otim" => {}
+            "post" => self.post = true,
+            "nopost" => self.post = false,
+            "undo" => self.undo(1),
+            "remove" => self.undo(2),
+            "setboard" => {
+                self.cancel_search();
+                match Board::from_fen(args) {
+                    Ok(board) => self.board = board,
+                    Err(_) => println!("tellusererror Illegal position"),
+                }
+            }
+            "ping" => println!("pong {}", args),
+            "quit" => return false,
+            // xboard, accepted, rejected, random, hard, easy, computer, name and the rest don't need
+            // anything from the engine
+            "" | "xboard" | "accepted" | "rejected" | "random" | "hard" | "easy" | "computer" | "name" | "rating" | "ics" | "white" | "black" => {}
+            // moves can come without `usermove` if the GUI didn't accept the feature
+            _ if parse_move(&self.board, command).is_some() => self.user_move(command),
+            _ => println!("Error (unknown command): {}", command),
+        }
+
+        true
+    }
+
+    // plays the opponent's move, and starts thinking if it is now the engine's turn
+    fn user_move(&mut self, uci_move: &str) {
+        self.cancel_search();
+        match parse_move(&self.board, uci_move) {
+            Some(mv) => {
+                self.board.apply_move(mv);
+                if self.engine_side == Some(self.board.turn()) {
+                    self.start_search();
+                }
+            }
+            None => println!("Illegal move: {}", uci_move),
+        }
+    }
+
+    // parses `level <moves per session> <base> <increment>`, where base is minutes or minutes:seconds
+    // and the increment is in seconds
+    fn set_level(&mut self, args: &str) -> Result<(), &'static str> {
+        let fields: Vec<&str> = args.split_whitespace().collect();
+        let [moves, _base, increment] = fields[..] else {
+            return Err("expected 3 values");
+        };
+        self.moves_per_session = moves.parse().map_err(|_| "invalid moves per session")?;
+        self.increment = (increment.parse::<f64>().map_err(|_| "invalid increment")? * 1000.0) as u64;
+        self.move_time = None;
+        Ok(())
+    }
+
+    fn undo(&mut self, moves: usize) {
+        self.cancel_search();
+        for _ in 0..moves {
+            // pleco only keeps the moves played on this board, so a position from setboard can't be
+            // taken back past
+            if self.board.ply() == 0 {
+                break;
+            }
+            self.board.undo_move();
+        }
+    }
+
+    fn search_limits(&self) -> SearchLimits {
+        let limits = SearchLimits { depth: self.max_depth, ..Default::default() };
+        if let Some(move_time) = self.move_time {
+            return limits.with_move_time(move_time.saturating_sub(MOVE_OVERHEAD));
+        }
+        let Some(clock) = self.clock else {
+            return limits;
+        };
+
+        // with a number of moves per session, the clock only has to last until the next session
+        let moves_to_go = if self.moves_per_session > 0 {
+            let moves_played = self.board.moves_played() as u64 / 2;
+            Some(self.moves_per_session - moves_played % self.moves_per_session)
+        } else {
+            None
+        };
+        limits.with_clock(clock.saturating_sub(MOVE_OVERHEAD), self.increment, moves_to_go)
+    }
+
+    // starts thinking about the current position on another thread, unless the game is over
+    fn start_search(&mut self) {
+        if self.board.generate_moves().is_empty() {
+            return;
+        }
+
+        let board = self.board.shallow_clone();
+        self.stop = Arc::new(AtomicBool::new(false));
+        let limits = SearchLimits { stop: Some(self.stop.clone()), ..self.search_limits() };
+        let post = self.post;
+        let events = self.events.clone();
+        self.search_id += 1;
+        let id = self.search_id;
+
+        self.search = Some(thread::spawn(move || {
+            let turn = board.turn();
+            let best_move = pick_book_move(&board).or_else(|| {
+                iterative_deepening(board, limits, |info| {
+                    if post {
+                        print_thinking(info, turn);
+                    }
+                })
+            });
+            events.send(Event::SearchDone(id, best_move)).ok();
+        }));
+    }
+
+    // stops the search if there is one and throws its move away
+    fn cancel_search(&mut self) {
+        if let Some(search) = self.search.take() {
+            self.search_id += 1;
+            self.stop.store(true, Ordering::Relaxed);
+            search.join().ok();
+        }
+    }
+
+    fn play_search_result(&mut self, best_move: Option<BitMove>) {
+        if let Some(search) = self.search.take() {
+            search.join().ok();
+        }
+        let Some(best_move) = best_move else { return };
+
+        self.board.apply_move(best_move);
+        println!("move {}", best_move);
+        if let Some(result) = game_result(&self.board) {
+            println!("{}", result);
+        }
+    }
+}
+
+// returns the result line for a game that has ended on the board
+fn game_result(board: &Board) -> Option<&'static str> {
+    if board.checkmate() {
+        return Some(if board.turn() == Player::White { "0-1 {Black mates}" } else { "1-0 {White mates}" });
+    }
+    if board.generate_moves().is_empty() {
+        return Some("1/2-1/2 {Stalemate}");
+    }
+    if board.rule_50() >= 100 {
+        return Some("1/2-1/2 {50 move rule}");
+    }
+    if is_threefold_repetition(board) {
+        return Some("1/2-1/2 {Draw by repetition}");
+    }
+    if is_insufficient_material(board) {
+        return Some("1/2-1/2 {Insufficient material}");
+    }
+    None
+}
+
+// returns true if the position has come up twice before. Only positions since the last capture or
+// pawn move can repeat, and pleco keeps the moves played on the board, so they can be taken back
+fn is_threefold_repetition(board: &Board) -> bool {
+    // unlike `clone`, this keeps the count of moves that can be taken back
+    let mut past = board.parallel_clone();
+    let mut repetitions = 0;
+    for _ in 0..board.rule_50().min(board.depth() as i16) {
+        past.undo_move();
+        if past.zobrist() == board.zobrist() {
+            repetitions += 1;
+        }
+    }
+    repetitions >= 2
+}
+
+// returns true if neither side can mate however badly the other plays: bare kings, a single minor
+// piece, or only bishops that are all on the same colour
+fn is_insufficient_material(board: &Board) -> bool {
+    if (board.piece_two_bb_both_players(PieceType::P, PieceType::R) | board.piece_bb_both_players(PieceType::Q)).is_not_empty() {
+        return false;
+    }
+    let knights = board.piece_bb_both_players(PieceType::N).count_bits();
+    let bishops = board.piece_bb_both_players(PieceType::B);
+    if knights + bishops.count_bits() <= 1 {
+        return true;
+    }
+    knights == 0 && ((bishops & BitBoard::DARK_SQUARES).is_empty() || (bishops & BitBoard::LIGHT_SQUARES).is_empty())
+}
+
+// sends thinking output: depth, score in centipawns for the engine, time in centiseconds, nodes and
+// the best move
+fn print_thinking(info: &SearchInfo, turn: Player) {
+    let score = match info.mate_for(turn) {
+        Some(moves) if moves > 0 => XBOARD_MATE_SCORE + moves,
+        Some(moves) => -XBOARD_MATE_SCORE + moves,
+        None => info.score_for(turn),
+    };
+    println!("{} {} {} {} {}", info.depth, score, info.elapsed.as_millis() / 10, info.nodes, info.best_move);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn engine() -> XboardEngine {
+        XboardEngine::new(mpsc::channel().0)
+    }
+
+    fn result_of(fen: &str) -> Option<&'static str> {
+        game_result(&Board::from_fen(fen).unwrap())
+    }
+
+    #[test]
+    fn level_sets_the_moves_per_session_and_increment() {
+        let mut engine = engine();
+        engine.handle_command("st 2");
+        engine.handle_command("level 40 5 0");
+        assert_eq!((engine.moves_per_session, engine.increment, engine.move_time), (40, 0, None));
+
+        engine.handle_command("level 0 2:30 1.5");
+        assert_eq!((engine.moves_per_session, engine.increment), (0, 1500));
+
+        assert!(engine.set_level("0 5").is_err());
+        assert!(engine.set_level("x 5 0").is_err());
+        assert_eq!((engine.moves_per_session, engine.increment), (0, 1500));
+    }
+
+    #[test]
+    fn search_limits_follow_the_time_control() {
+        let mut engine = engine();
+        let limits = engine.search_limits();
+        assert_eq!((limits.depth, limits.soft_time, limits.hard_time), (None, None, None));
+
+        engine.handle_command("sd 6");
+        engine.handle_command("st 1");
+        let limits = engine.search_limits();
+        let move_time = Some(Duration::from_millis(1000 - MOVE_OVERHEAD));
+        assert_eq!((limits.depth, limits.soft_time, limits.hard_time), (Some(6), move_time, move_time));
+
+        // with 40 moves a session and 10 played, the clock has to last 30 more moves
+        engine.handle_command("level 40 5 0");
+        engine.handle_command("time 30000");
+        for _ in 0..20 {
+            let mv = engine.board.generate_moves()[0];
+            engine.board.apply_move(mv);
+        }
+        let limits = engine.search_limits();
+        let expected = SearchLimits::default().with_clock(300000 - MOVE_OVERHEAD, 0, Some(30));
+        assert_eq!((limits.soft_time, limits.hard_time), (expected.soft_time, expected.hard_time));
+    }
+
+    #[test]
+    fn new_forgets_the_last_games_time_control() {
+        let mut engine = engine();
+        engine.handle_command("st 5");
+        engine.handle_command("sd 4");
+        engine.handle_command("time 1000");
+        engine.handle_command("new");
+        assert_eq!((engine.move_time, engine.max_depth, engine.clock), (None, None, None));
+    }
+
+    #[test]
+    fn game_result_finds_mates_and_draws() {
+        assert_eq!(result_of("rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3"), Some("0-1 {Black mates}"));
+        assert_eq!(result_of("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1"), Some("1/2-1/2 {Stalemate}"));
+        assert_eq!(result_of("4k3/8/8/8/8/8/4R3/4K3 b - - 100 80"), Some("1/2-1/2 {50 move rule}"));
+        assert_eq!(result_of("4k3/8/8/8/8/8/4N3/4K3 b - - 0 80"), Some("1/2-1/2 {Insufficient material}"));
+        // bishops on the same colour can't mate, but on opposite colours they can
+        assert_eq!(result_of("2b1k3/8/8/8/8/8/8/4KB2 w - - 0 80"), Some("1/2-1/2 {Insufficient material}"));
+        assert_eq!(result_of("3bk3/8/8/8/8/8/8/4KB2 w - - 0 80"), None);
+        assert_eq!(result_of("4k3/8/8/8/8/8/4R3/4K3 b - - 99 80"), None);
+        assert_eq!(result_of("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"), None);
+    }
+
+    #[test]
+    fn game_result_finds_threefold_repetition() {
+        let mut board = Board::start_pos();
+        let moves = ["g1f3", "g8f6", "f3g1", "f6g8", "g1f3", "g8f6", "f3g1", "f6g8"];
+        for (i, uci_move) in moves.iter().enumerate() {
+            // the start position comes up for the second time after four moves and the third after eight
+            assert_eq!(game_result(&board), None, "after {} moves", i);
+            board.apply_move(parse_move(&board, uci_move).unwrap());
+        }
+        assert_eq!(game_result(&board), Some("1/2-1/2 {Draw by repetition}"));
+    }
+}